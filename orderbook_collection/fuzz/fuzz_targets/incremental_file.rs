@@ -0,0 +1,50 @@
+//! Reads arbitrary bytes as an incremental file with both readers, the first two bytes are
+//! the buffer size. Neither may panic or loop forever and, unless the array based reader fails,
+//! both must read the same order book.
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use orderbook_collection_lib::{
+    array_orderbook, btree_orderbook, config::OrderBookConfig, orderbook::CrossPolicy,
+    output::OrderBookOutput, price::TickSize,
+};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let buffer_size = u16::from_le_bytes([data[0], data[1]]) as usize;
+    let file = std::env::temp_dir().join(format!("fuzz_incremental_{}.bin", std::process::id()));
+    std::fs::write(&file, &data[2..]).unwrap();
+
+    let config = OrderBookConfig {
+        id: 1,
+        min_price: 90.0,
+        max_price: 110.0,
+        tick_size: 0.01,
+        recenter: false,
+        cross_policy: CrossPolicy::Warn,
+    };
+    let mut array_books = array_orderbook::ser::init_order_books(&HashMap::from([(1, config)]));
+    let mut btree_books = HashMap::from([(
+        1,
+        btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01)),
+    )]);
+    let array_result = array_orderbook::ser::read_incremental_file(
+        file.clone(),
+        &mut array_books,
+        buffer_size,
+        None,
+    );
+    let btree_result =
+        btree_orderbook::ser::read_incremental_file(file, &mut btree_books, buffer_size, None);
+    if array_result.is_ok() {
+        assert!(btree_result.is_ok());
+        assert_eq!(
+            OrderBookOutput::from_order_book(&array_books[&1]),
+            OrderBookOutput::from_order_book(&btree_books[&1])
+        );
+    }
+});
@@ -0,0 +1,44 @@
+//! Applies arbitrary bytes as an incremental message with both readers. Neither may panic and,
+//! unless the array based reader rejects a price out of its bounds, both must return the same
+//! result and order book.
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use orderbook_collection_lib::{
+    array_orderbook, btree_orderbook, config::OrderBookConfig, orderbook::CrossPolicy,
+    output::OrderBookOutput, price::TickSize, ser::Error,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let config = OrderBookConfig {
+        id: 1,
+        min_price: 90.0,
+        max_price: 110.0,
+        tick_size: 0.01,
+        recenter: false,
+        cross_policy: CrossPolicy::Warn,
+    };
+    let mut array_books = array_orderbook::ser::init_order_books(&HashMap::from([(1, config)]));
+    let mut btree_books = HashMap::from([(
+        1,
+        btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01)),
+    )]);
+    // the same message applied twice, i.e. as a new and as an already applied update
+    for _ in 0..2 {
+        let array_result = array_orderbook::ser::incremental::read(data, &mut array_books);
+        let btree_result = btree_orderbook::ser::incremental::read(data, &mut btree_books);
+        if let Ok(size) | Err(Error::GapDetected(_, size)) = array_result {
+            assert!(size <= data.len());
+        }
+        if matches!(array_result, Err(Error::InvalidData(_))) {
+            return;
+        }
+        assert_eq!(format!("{:?}", array_result), format!("{:?}", btree_result));
+        assert_eq!(
+            OrderBookOutput::from_order_book(&array_books[&1]),
+            OrderBookOutput::from_order_book(&btree_books[&1])
+        );
+    }
+});
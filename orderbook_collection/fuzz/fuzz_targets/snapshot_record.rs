@@ -0,0 +1,46 @@
+//! Reads arbitrary bytes as a snapshot record of both formats with both readers. Neither may
+//! panic and, unless the array based reader rejects a price out of its bounds, both must read
+//! the same order book.
+#![no_main]
+
+use std::collections::HashMap;
+
+use libfuzzer_sys::fuzz_target;
+use orderbook_collection_lib::{
+    array_orderbook, btree_orderbook, config::OrderBookConfig, orderbook::CrossPolicy,
+    output::OrderBookOutput,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let configs = HashMap::from([(
+        1,
+        OrderBookConfig {
+            id: 1,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: true,
+            cross_policy: CrossPolicy::Warn,
+        },
+    )]);
+    let mut array_books = array_orderbook::ser::init_order_books(&configs);
+    let legacy = array_orderbook::ser::snapshot::read(data, &mut array_books);
+    let btree_book = btree_orderbook::ser::snapshot::read(data, &configs);
+    if legacy.is_ok() {
+        assert_eq!(
+            OrderBookOutput::from_order_book(&array_books[&1]),
+            OrderBookOutput::from_order_book(&btree_book.unwrap())
+        );
+    }
+
+    let mut array_books = array_orderbook::ser::init_order_books(&configs);
+    let depth = array_orderbook::ser::snapshot::read_depth(data, &mut array_books);
+    let btree_book = btree_orderbook::ser::snapshot::read_depth(data, &configs);
+    if let Ok(size) = depth {
+        assert!(size <= data.len());
+        assert_eq!(
+            OrderBookOutput::from_order_book(&array_books[&1]),
+            OrderBookOutput::from_order_book(&btree_book.unwrap())
+        );
+    }
+});
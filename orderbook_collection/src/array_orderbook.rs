@@ -1,2 +1,3 @@
+pub mod bitmap;
 pub mod orderbook;
 pub mod ser;
\ No newline at end of file
@@ -0,0 +1,198 @@
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Hierarchical occupancy bitmap over the price index.
+/// The lowest layer has one bit per index, every upper layer has one bit per word of the layer
+/// below, which is set if any bit of that word is set. With 64 bit words, 1m indexes take 4 layers,
+/// so finding the nearest occupied index above or below a given one takes at most 4 steps up
+/// and 4 steps down, regardless of the distance between the indexes.
+#[derive(Clone)]
+pub struct Bitmap {
+    layers: Vec<Vec<u64>>,
+}
+
+impl Bitmap {
+    pub fn new(capacity: usize) -> Self {
+        let mut layers = vec![];
+        let mut bits = capacity.max(1);
+        loop {
+            let words = bits.div_ceil(WORD_BITS);
+            layers.push(vec![0; words]);
+            if words == 1 {
+                break;
+            }
+            bits = words;
+        }
+        Self { layers }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, index: usize) -> bool {
+        self.layers[0][index / WORD_BITS] & (1 << (index % WORD_BITS)) != 0
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, index: usize) {
+        let mut index = index;
+        for layer in self.layers.iter_mut() {
+            let word = &mut layer[index / WORD_BITS];
+            let was_empty = *word == 0;
+            *word |= 1 << (index % WORD_BITS);
+            // upper layers already have the bit set
+            if !was_empty {
+                break;
+            }
+            index /= WORD_BITS;
+        }
+    }
+
+    #[inline(always)]
+    pub fn remove(&mut self, index: usize) {
+        let mut index = index;
+        for layer in self.layers.iter_mut() {
+            let word = &mut layer[index / WORD_BITS];
+            *word &= !(1 << (index % WORD_BITS));
+            // upper layers still need the bit set
+            if *word != 0 {
+                break;
+            }
+            index /= WORD_BITS;
+        }
+    }
+
+    /// Returns the lowest occupied index greater than or equal to the given one.
+    pub fn next(&self, index: usize) -> Option<usize> {
+        let mut layer = 0;
+        let mut index = index;
+        // go up until there's an occupied bit at or after the index in the current layer
+        loop {
+            let word_index = index / WORD_BITS;
+            let words = &self.layers[layer];
+            if word_index >= words.len() {
+                return None;
+            }
+            let word = words[word_index] & (u64::MAX << (index % WORD_BITS));
+            if word != 0 {
+                index = word_index * WORD_BITS + word.trailing_zeros() as usize;
+                break;
+            }
+            layer += 1;
+            if layer == self.layers.len() {
+                return None;
+            }
+            index = word_index + 1;
+        }
+        // go down taking the lowest occupied bit
+        while layer > 0 {
+            layer -= 1;
+            let word = self.layers[layer][index];
+            index = index * WORD_BITS + word.trailing_zeros() as usize;
+        }
+        Some(index)
+    }
+
+    /// Returns the highest occupied index less than or equal to the given one.
+    pub fn prev(&self, index: usize) -> Option<usize> {
+        let mut layer = 0;
+        let mut index = index;
+        // go up until there's an occupied bit at or before the index in the current layer
+        loop {
+            let words = &self.layers[layer];
+            let (word_index, mask) = if index / WORD_BITS >= words.len() {
+                (words.len() - 1, u64::MAX)
+            } else {
+                (
+                    index / WORD_BITS,
+                    u64::MAX >> (WORD_BITS - 1 - index % WORD_BITS),
+                )
+            };
+            let word = words[word_index] & mask;
+            if word != 0 {
+                index = word_index * WORD_BITS + (WORD_BITS - 1 - word.leading_zeros() as usize);
+                break;
+            }
+            layer += 1;
+            if word_index == 0 || layer == self.layers.len() {
+                return None;
+            }
+            index = word_index - 1;
+        }
+        // go down taking the highest occupied bit
+        while layer > 0 {
+            layer -= 1;
+            let word = self.layers[layer][index];
+            index = index * WORD_BITS + (WORD_BITS - 1 - word.leading_zeros() as usize);
+        }
+        Some(index)
+    }
+
+    pub fn clear(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.fill(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitmap_next_prev() {
+        let mut bitmap = Bitmap::new(1_000_000);
+        assert_eq!(bitmap.next(0), None);
+        assert_eq!(bitmap.prev(999_999), None);
+
+        for index in [0, 63, 64, 4_095, 4_096, 262_144, 999_999] {
+            bitmap.insert(index);
+            assert!(bitmap.contains(index));
+        }
+
+        assert_eq!(bitmap.next(0), Some(0));
+        assert_eq!(bitmap.next(1), Some(63));
+        assert_eq!(bitmap.next(65), Some(4_095));
+        assert_eq!(bitmap.next(4_097), Some(262_144));
+        assert_eq!(bitmap.next(262_145), Some(999_999));
+        assert_eq!(bitmap.prev(999_998), Some(262_144));
+        assert_eq!(bitmap.prev(262_143), Some(4_096));
+        assert_eq!(bitmap.prev(4_094), Some(64));
+        assert_eq!(bitmap.prev(62), Some(0));
+
+        bitmap.remove(0);
+        bitmap.remove(999_999);
+        assert!(!bitmap.contains(0));
+        assert_eq!(bitmap.prev(62), None);
+        assert_eq!(bitmap.next(262_145), None);
+        // the word of 63 and 64 is still occupied in the upper layer after removing one of them
+        bitmap.remove(63);
+        assert_eq!(bitmap.next(0), Some(64));
+
+        bitmap.clear();
+        assert_eq!(bitmap.next(0), None);
+        assert_eq!(bitmap.prev(999_999), None);
+    }
+
+    #[test]
+    fn test_bitmap_matches_linear_scan() {
+        let capacity = 10_000;
+        let mut bitmap = Bitmap::new(capacity);
+        let mut occupied = vec![false; capacity];
+        // pseudo random sequence of inserts and removals
+        let mut state: u64 = 42;
+        for _ in 0..5_000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let index = (state >> 33) as usize % capacity;
+            if occupied[index] {
+                bitmap.remove(index);
+            } else {
+                bitmap.insert(index);
+            }
+            occupied[index] = !occupied[index];
+
+            let probe = (state >> 13) as usize % capacity;
+            assert_eq!(bitmap.next(probe), (probe..capacity).find(|i| occupied[*i]));
+            assert_eq!(bitmap.prev(probe), (0..=probe).rev().find(|i| occupied[*i]));
+        }
+    }
+}
@@ -1,6 +1,12 @@
 use anyhow::bail;
+use tracing::info;
 
-use crate::config;
+use crate::{
+    array_orderbook::bitmap::Bitmap,
+    config,
+    orderbook::{BookHealth, CrossCounts, Side},
+    price::{Price, TickSize},
+};
 
 const MAX_LEVELS: usize = 1_000_000; // 1m levels, e.g. from 0 to 10_000 with 0.01 tick size
 const EMPTY: usize = usize::MAX;
@@ -11,18 +17,34 @@ const EMPTY: usize = usize::MAX;
 /// updates and benefits from CPU cache locality. The order book is divided into bids and asks, each represented by a separate
 /// `OrderBookSide`. The order book supports a configurable range of prices and tick size,
 /// allowing for flexible market configurations.
+/// Prices are in ticks, the index of a level is its distance in ticks from the min price.
+/// If `recenter` is enabled in the config, the price bounds are shifted or widened when a price
+/// outside of them is received, see `OrderBook::recenter`.
+#[derive(Clone)]
 pub struct OrderBook {
     pub seq_no: u64,
     pub timestamp: u64,
+    pub health: BookHealth,
+    pub cross_counts: CrossCounts,
+    pub unknown_deletes: u64,
     pub bids: OrderBookSide,
     pub asks: OrderBookSide,
     config: config::OrderBookConfig,
+    tick_size: TickSize,
+    min_price: Price,
+    max_price: Price,
+    recenter_count: u64,
+    /// Decoded updates of the incremental message being applied, kept to reuse the allocation,
+    /// see `ser::incremental::read_observed`.
+    pub(crate) updates: Vec<(Side, Price, u64)>,
 }
 
 impl OrderBook {
     pub fn new(config: config::OrderBookConfig) -> Self {
-        let levels =
-            ((config.max_price - config.min_price) / config.tick_size).round() as usize + 1;
+        let tick_size = TickSize::new(config.tick_size);
+        let min_price = tick_size.to_price(config.min_price);
+        let max_price = tick_size.to_price(config.max_price);
+        let levels = (max_price.ticks() - min_price.ticks()) as usize + 1;
         assert!(
             levels <= MAX_LEVELS,
             "Number of levels exceeds the max levels limit of {}",
@@ -33,14 +55,21 @@ impl OrderBook {
             bids: OrderBookSide::new(true),
             asks: OrderBookSide::new(false),
             config,
+            tick_size,
+            min_price,
+            max_price,
             seq_no: 0,
             timestamp: 0,
+            health: BookHealth::Live,
+            cross_counts: CrossCounts::default(),
+            unknown_deletes: 0,
+            recenter_count: 0,
+            updates: vec![],
         }
     }
 
     pub fn init(&mut self) {
-        let capacity =
-            ((self.config.max_price - self.config.min_price) / self.config.tick_size) as usize;
+        let capacity = self.levels();
         self.bids.init(capacity);
         self.asks.init(capacity);
     }
@@ -49,43 +78,155 @@ impl OrderBook {
         self.config.id
     }
 
-    fn price_to_index(&self, price: f64) -> usize {
-        if price < self.config.min_price || price > self.config.max_price {
+    pub fn config(&self) -> &config::OrderBookConfig {
+        &self.config
+    }
+
+    pub fn tick_size(&self) -> TickSize {
+        self.tick_size
+    }
+
+    /// Number of times the price bounds of the order book were re-centered.
+    pub fn recenter_count(&self) -> u64 {
+        self.recenter_count
+    }
+
+    /// Number of price levels between min and max price (inclusive).
+    fn levels(&self) -> usize {
+        (self.max_price.ticks() - self.min_price.ticks()) as usize + 1
+    }
+
+    #[inline(always)]
+    fn price_to_index(&self, price: Price) -> usize {
+        if price < self.min_price || price > self.max_price {
             return EMPTY;
         }
-        ((price - self.config.min_price) / self.config.tick_size).round() as usize
+        (price.ticks() - self.min_price.ticks()) as usize
     }
 
-    fn index_to_price(&self, index: usize) -> f64 {
-        self.config.min_price + self.config.tick_size * index as f64
+    #[inline(always)]
+    fn index_to_price(&self, index: usize) -> Price {
+        self.min_price + index as i64
     }
 
-    pub fn add_bid(&mut self, price: f64, qty: u64) -> anyhow::Result<()> {
-        let idx = self.price_to_index(price);
+    /// Returns whether the price is within the price bounds, otherwise adding a level at the price
+    /// fails or re-centers the order book, see `recenter`.
+    pub fn in_bounds(&self, price: Price) -> bool {
+        self.price_to_index(price) != EMPTY
+    }
+
+    pub fn add_bid(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        let mut idx = self.price_to_index(price);
         if idx == EMPTY {
-            bail!("price is out of bounds");
+            if !self.config.recenter {
+                bail!("price is out of bounds");
+            }
+            // removing a level outside of the bounds is a no-op, as such level can't exist
+            if qty == 0 {
+                return Ok(());
+            }
+            self.recenter(price)?;
+            idx = self.price_to_index(price);
         }
         self.bids.update(idx, qty);
         Ok(())
     }
 
-    pub fn add_ask(&mut self, price: f64, qty: u64) -> anyhow::Result<()> {
-        let idx = self.price_to_index(price);
+    pub fn add_ask(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        let mut idx = self.price_to_index(price);
         if idx == EMPTY {
-            bail!("price is out of bounds");
+            if !self.config.recenter {
+                bail!("price is out of bounds");
+            }
+            if qty == 0 {
+                return Ok(());
+            }
+            self.recenter(price)?;
+            idx = self.price_to_index(price);
         }
         self.asks.update(idx, qty);
         Ok(())
     }
 
-    pub fn get_bids(&self) -> Vec<(f64, u64)> {
+    /// Rebuilds the order book with new price bounds, so that both the given price and all
+    /// existing levels are within the bounds.
+    /// If the range of prices fits into the current number of levels, the bounds are shifted
+    /// so that the range is in the middle of the new bounds, otherwise the number of levels is
+    /// at least doubled (up to `MAX_LEVELS`).
+    fn recenter(&mut self, price: Price) -> anyhow::Result<()> {
+        let bids = self.get_bids();
+        let asks = self.get_asks();
+        let (low, high) = bids
+            .iter()
+            .chain(asks.iter())
+            .fold((price, price), |(low, high), (price, _)| {
+                (low.min(*price), high.max(*price))
+            });
+
+        // prices are decoded from the input, so the range can overflow
+        let span = high
+            .ticks()
+            .checked_sub(low.ticks())
+            .map_or(usize::MAX, |span| (span as u64).saturating_add(1) as usize);
+        if span > MAX_LEVELS {
+            bail!(
+                "price is out of bounds, price range {} - {} exceeds the max levels limit of {}",
+                self.tick_size.to_f64(low),
+                self.tick_size.to_f64(high),
+                MAX_LEVELS
+            );
+        }
+        let current_levels = self.levels();
+        let levels = if span <= current_levels {
+            current_levels
+        } else {
+            (current_levels * 2).max(span * 2).min(MAX_LEVELS)
+        };
+        let (Some(min_price), Some(max_price)) = (
+            low.ticks().checked_sub(((levels - span) / 2) as i64),
+            high.ticks().checked_add((levels - span).div_ceil(2) as i64),
+        ) else {
+            bail!(
+                "price is out of bounds, price {} is too far from zero",
+                self.tick_size.to_f64(price)
+            );
+        };
+        let (min_price, max_price) = (Price(min_price), Price(max_price));
+
+        info!(
+            "Re-centering order book ID {} on price {}: bounds {} - {} -> {} - {}",
+            self.config.id,
+            self.tick_size.to_f64(price),
+            self.config.min_price,
+            self.config.max_price,
+            self.tick_size.to_f64(min_price),
+            self.tick_size.to_f64(max_price)
+        );
+        self.min_price = min_price;
+        self.max_price = max_price;
+        self.config.min_price = self.tick_size.to_f64(min_price);
+        self.config.max_price = self.tick_size.to_f64(max_price);
+        self.init();
+        for (price, qty) in bids {
+            let idx = self.price_to_index(price);
+            self.bids.update(idx, qty);
+        }
+        for (price, qty) in asks {
+            let idx = self.price_to_index(price);
+            self.asks.update(idx, qty);
+        }
+        self.recenter_count += 1;
+        Ok(())
+    }
+
+    pub fn get_bids(&self) -> Vec<(Price, u64)> {
         self.bids
             .levels()
             .into_iter()
             .map(|(idx, qty)| (self.index_to_price(idx), qty))
             .collect()
     }
-    pub fn get_asks(&self) -> Vec<(f64, u64)> {
+    pub fn get_asks(&self) -> Vec<(Price, u64)> {
         self.asks
             .levels()
             .into_iter()
@@ -93,36 +234,187 @@ impl OrderBook {
             .collect()
     }
 
-    pub fn best_bid(&self) -> Option<(f64, u64)> {
+    pub fn best_bid(&self) -> Option<(Price, u64)> {
         self.bids
             .head()
             .map(|(idx, qty)| (self.index_to_price(idx), qty))
     }
 
-    pub fn best_ask(&self) -> Option<(f64, u64)> {
+    pub fn best_ask(&self) -> Option<(Price, u64)> {
         self.asks
             .head()
             .map(|(idx, qty)| (self.index_to_price(idx), qty))
     }
 
-    pub fn worst_bid(&self) -> Option<(f64, u64)> {
+    pub fn worst_bid(&self) -> Option<(Price, u64)> {
         self.bids
             .tail()
             .map(|(idx, qty)| (self.index_to_price(idx), qty))
     }
 
-    pub fn worst_ask(&self) -> Option<(f64, u64)> {
+    pub fn worst_ask(&self) -> Option<(Price, u64)> {
         self.asks
             .tail()
             .map(|(idx, qty)| (self.index_to_price(idx), qty))
     }
 
+    pub fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.bids
+            .iter()
+            .map(|(idx, qty)| (self.index_to_price(idx), qty))
+    }
+
+    pub fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.asks
+            .iter()
+            .map(|(idx, qty)| (self.index_to_price(idx), qty))
+    }
+
+    /// Total qty of the levels on the side at the price or better, walking the linked list
+    /// from the best level until the price is passed.
+    pub fn qty_up_to(&self, side: Side, price: Price) -> u64 {
+        match side {
+            Side::Bid => self
+                .iter_bids()
+                .take_while(|(level_price, _)| *level_price >= price)
+                .map(|(_, qty)| qty)
+                .sum(),
+            Side::Ask => self
+                .iter_asks()
+                .take_while(|(level_price, _)| *level_price <= price)
+                .map(|(_, qty)| qty)
+                .sum(),
+        }
+    }
+
+    /// Qty of the level on the side at the price, 0 if there's no such level or the price is
+    /// out of bounds.
+    pub fn qty_at(&self, side: Side, price: Price) -> u64 {
+        let idx = self.price_to_index(price);
+        if idx == EMPTY {
+            return 0;
+        }
+        match side {
+            Side::Bid => self.bids.volumes[idx],
+            Side::Ask => self.asks.volumes[idx],
+        }
+    }
+
     pub fn clear(&mut self) {
         self.bids.clear();
         self.asks.clear();
         self.seq_no = 0;
         self.timestamp = 0;
     }
+
+    fn to_f64(&self, levels: Vec<(Price, u64)>) -> Vec<(f64, u64)> {
+        levels
+            .into_iter()
+            .map(|(price, qty)| (self.tick_size.to_f64(price), qty))
+            .collect()
+    }
+}
+
+impl crate::orderbook::OrderBook for OrderBook {
+    #[inline(always)]
+    fn id(&self) -> u64 {
+        self.id()
+    }
+
+    #[inline(always)]
+    fn seq_no(&self) -> u64 {
+        self.seq_no
+    }
+
+    #[inline(always)]
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline(always)]
+    fn tick_size(&self) -> TickSize {
+        self.tick_size
+    }
+
+    #[inline(always)]
+    fn health(&self) -> BookHealth {
+        self.health
+    }
+
+    #[inline(always)]
+    fn cross_counts(&self) -> CrossCounts {
+        self.cross_counts
+    }
+
+    #[inline(always)]
+    fn unknown_deletes(&self) -> u64 {
+        self.unknown_deletes
+    }
+
+    #[inline(always)]
+    fn add_bid(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        self.add_bid(price, qty)
+    }
+
+    #[inline(always)]
+    fn add_ask(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        self.add_ask(price, qty)
+    }
+
+    #[inline(always)]
+    fn get_bids(&self) -> Vec<(Price, u64)> {
+        self.get_bids()
+    }
+
+    #[inline(always)]
+    fn get_asks(&self) -> Vec<(Price, u64)> {
+        self.get_asks()
+    }
+
+    #[inline(always)]
+    fn best_bid(&self) -> Option<(Price, u64)> {
+        self.best_bid()
+    }
+
+    #[inline(always)]
+    fn best_ask(&self) -> Option<(Price, u64)> {
+        self.best_ask()
+    }
+
+    #[inline(always)]
+    fn worst_bid(&self) -> Option<(Price, u64)> {
+        self.worst_bid()
+    }
+
+    #[inline(always)]
+    fn worst_ask(&self) -> Option<(Price, u64)> {
+        self.worst_ask()
+    }
+
+    #[inline(always)]
+    fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.iter_bids()
+    }
+
+    #[inline(always)]
+    fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.iter_asks()
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) {
+        self.clear()
+    }
+
+    #[inline(always)]
+    fn qty_up_to(&self, side: Side, price: Price) -> u64 {
+        self.qty_up_to(side, price)
+    }
+
+    #[inline(always)]
+    fn qty_at(&self, side: Side, price: Price) -> u64 {
+        self.qty_at(side, price)
+    }
 }
 
 impl std::fmt::Debug for OrderBook {
@@ -133,8 +425,8 @@ impl std::fmt::Debug for OrderBook {
             self.config.id,
             self.seq_no,
             self.timestamp,
-            self.get_bids(),
-            self.get_asks()
+            self.to_f64(self.get_bids()),
+            self.to_f64(self.get_asks())
         )
     }
 }
@@ -142,14 +434,19 @@ impl std::fmt::Debug for OrderBook {
 /// Represents a side of the order book (bids or asks).
 /// It uses a linked list structure to maintain the order of levels, allowing for efficient insertion and
 /// removal of levels. The linked list is based on array, which benefits from CPU cache locality in case of dense order book.
+/// The neighbours of a new level in the linked list are found using the hierarchical occupancy
+/// bitmap over the price index, so insertion takes constant number of steps regardless of the depth
+/// of the order book, and both head and tail of the list are kept, so best and worst levels are O(1).
 /// The `OrderBookSide` supports both ascending and descending order for bids and asks,
 /// respectively, and provides methods to update levels, retrieve the head and tail of the side, and clear the side.
-
+#[derive(Clone)]
 pub struct OrderBookSide {
     volumes: Vec<u64>,
     next: Vec<usize>,
     prev: Vec<usize>,
+    occupied: Bitmap,
     head: usize,
+    tail: usize,
     is_descending: bool,
 }
 
@@ -159,7 +456,9 @@ impl OrderBookSide {
             volumes: vec![],
             next: vec![],
             prev: vec![],
+            occupied: Bitmap::new(0),
             head: EMPTY,
+            tail: EMPTY,
             is_descending,
         }
     }
@@ -168,45 +467,60 @@ impl OrderBookSide {
         self.volumes = vec![0; capacity];
         self.next = vec![EMPTY; capacity];
         self.prev = vec![EMPTY; capacity];
+        self.occupied = Bitmap::new(capacity);
         self.head = EMPTY;
+        self.tail = EMPTY;
+    }
+
+    /// Returns the closest occupied index which is further from mid than the given index,
+    /// i.e. the level which follows the given one in the linked list.
+    #[inline(always)]
+    fn next_occupied(&self, index: usize) -> usize {
+        let next = if self.is_descending {
+            index
+                .checked_sub(1)
+                .and_then(|index| self.occupied.prev(index))
+        } else {
+            self.occupied.next(index + 1)
+        };
+        next.unwrap_or(EMPTY)
+    }
+
+    /// Returns the closest occupied index which is closer to mid than the given index,
+    /// i.e. the level which precedes the given one in the linked list.
+    #[inline(always)]
+    fn prev_occupied(&self, index: usize) -> usize {
+        let prev = if self.is_descending {
+            self.occupied.next(index + 1)
+        } else {
+            index
+                .checked_sub(1)
+                .and_then(|index| self.occupied.prev(index))
+        };
+        prev.unwrap_or(EMPTY)
     }
 
     fn insert(&mut self, index: usize) {
-        if self.head == EMPTY {
+        let prev = self.prev_occupied(index);
+        let next = self.next_occupied(index);
+        self.occupied.insert(index);
+
+        self.prev[index] = prev;
+        self.next[index] = next;
+        if prev != EMPTY {
+            self.next[prev] = index;
+        } else {
             self.head = index;
-            return;
         }
-
-        let mut current = self.head;
-        while current != EMPTY {
-            if (self.is_descending && index > current) || (!self.is_descending && index < current) {
-                // insert before i
-                self.next[index] = current;
-                if self.prev[current] != EMPTY {
-                    self.next[self.prev[current]] = index;
-                    self.prev[index] = self.prev[current];
-                } else {
-                    self.head = index;
-                }
-                self.prev[current] = index;
-                return;
-            }
-            current = self.next[current];
-        }
-
-        // insert at the end
-        current = self.head;
-        while current != EMPTY {
-            if self.next[current] == EMPTY {
-                self.next[current] = index;
-                self.prev[index] = current;
-                break;
-            }
-            current = self.next[current];
+        if next != EMPTY {
+            self.prev[next] = index;
+        } else {
+            self.tail = index;
         }
     }
 
     fn remove(&mut self, index: usize) {
+        self.occupied.remove(index);
         if self.prev[index] != EMPTY {
             self.next[self.prev[index]] = self.next[index];
         } else {
@@ -215,6 +529,8 @@ impl OrderBookSide {
 
         if self.next[index] != EMPTY {
             self.prev[self.next[index]] = self.prev[index];
+        } else {
+            self.tail = self.prev[index];
         }
 
         self.next[index] = EMPTY;
@@ -233,13 +549,20 @@ impl OrderBookSide {
     }
 
     pub fn levels(&self) -> Vec<(usize, u64)> {
-        let mut levels = Vec::new();
+        self.iter().collect()
+    }
+
+    /// Iterates over the levels in the order of the linked list, i.e. from the best one.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
         let mut current = self.head;
-        while current != EMPTY {
-            levels.push((current, self.volumes[current]));
+        std::iter::from_fn(move || {
+            if current == EMPTY {
+                return None;
+            }
+            let level = (current, self.volumes[current]);
             current = self.next[current];
-        }
-        levels
+            Some(level)
+        })
     }
 
     pub fn head(&self) -> Option<(usize, u64)> {
@@ -251,14 +574,10 @@ impl OrderBookSide {
     }
 
     pub fn tail(&self) -> Option<(usize, u64)> {
-        if self.head == EMPTY {
-            None
+        if self.tail != EMPTY {
+            Some((self.tail, self.volumes[self.tail]))
         } else {
-            let mut current = self.head;
-            while self.next[current] != EMPTY {
-                current = self.next[current];
-            }
-            Some((current, self.volumes[current]))
+            None
         }
     }
 
@@ -266,39 +585,62 @@ impl OrderBookSide {
         let mut current = self.head;
         while current != EMPTY {
             let next = self.next[current];
-            self.remove(current);
+            self.next[current] = EMPTY;
+            self.prev[current] = EMPTY;
             self.volumes[current] = 0;
+            // removing levels one by one is cheaper than clearing the whole bitmap
+            self.occupied.remove(current);
             current = next;
         }
+        self.head = EMPTY;
+        self.tail = EMPTY;
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::array_orderbook::orderbook::OrderBook;
+    use crate::{
+        array_orderbook::orderbook::OrderBook,
+        orderbook::{CrossPolicy, OrderBook as _, Side, Sweep},
+        price::{Price, TickSize},
+    };
+
+    fn add_bid(order_book: &mut OrderBook, price: Price, qty: u64) {
+        order_book.add_bid(price, qty).unwrap();
+    }
 
     struct TestSet {
         order_book: Box<OrderBook>,
-        initial_bids: Vec<(f64, u64)>,
-        initial_asks: Vec<(f64, u64)>,
+        initial_bids: Vec<(Price, u64)>,
+        initial_asks: Vec<(Price, u64)>,
+    }
+
+    fn price(price: f64) -> Price {
+        TickSize::new(0.01).to_price(price)
+    }
+
+    fn prices(levels: &[(f64, u64)]) -> Vec<(Price, u64)> {
+        levels.iter().map(|(p, qty)| (price(*p), *qty)).collect()
     }
 
     fn init_orderbook() -> TestSet {
-        let initial_bids = vec![
+        let initial_bids = prices(&[
             (100.1, 4),
             (100.05, 20),
             (100.0, 10),
-        ];
-        let initial_asks = vec![
+        ]);
+        let initial_asks = prices(&[
             (101.0, 5),
             (101.1, 2),
             (102.0, 1),
-        ];
+        ]);
         let config = crate::config::OrderBookConfig {
             id: 0,
             min_price: 90.0,
             max_price: 110.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         };
         let mut order_book = Box::new(OrderBook::new(config));
         order_book.init();
@@ -324,13 +666,16 @@ mod tests {
             min_price: 90.0,
             max_price: 110.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         };
         let order_book = OrderBook::new(config);
-        assert_eq!(order_book.price_to_index(90.0), 0);
-        assert_eq!(order_book.price_to_index(100.0), 1000);
-        assert_eq!(order_book.price_to_index(110.0), 2000);
-        assert_eq!(order_book.price_to_index(89.99), usize::MAX);
-        assert_eq!(order_book.price_to_index(110.01), usize::MAX);
+        assert_eq!(order_book.price_to_index(price(90.0)), 0);
+        assert_eq!(order_book.price_to_index(price(100.0)), 1000);
+        assert_eq!(order_book.price_to_index(price(110.0)), 2000);
+        assert_eq!(order_book.price_to_index(price(89.99)), usize::MAX);
+        assert_eq!(order_book.price_to_index(price(110.01)), usize::MAX);
+        assert_eq!(order_book.index_to_price(1000), price(100.0));
     }
 
     #[test]
@@ -340,26 +685,109 @@ mod tests {
             min_price: 90.0,
             max_price: 110.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         };
         let order_book = OrderBook::new(config);
         let x = 0.1 + 0.2;
-        let price = 90.0 + x;
-        assert_eq!(order_book.price_to_index(price), 30);
-        let price = 91.0 - x;
-        assert_eq!(order_book.price_to_index(price), 70);
+        let p = order_book.tick_size().to_price(90.0 + x);
+        assert_eq!(order_book.price_to_index(p), 30);
+        let p = order_book.tick_size().to_price(91.0 - x);
+        assert_eq!(order_book.price_to_index(p), 70);
     }
 
     fn assert_order_book_levels(
         order_book: &OrderBook,
-        expected_bids: &Vec<(f64, u64)>,
-        expected_asks: &Vec<(f64, u64)>,
+        expected_bids: &[(Price, u64)],
+        expected_asks: &[(Price, u64)],
     ) {
-        assert_eq!(order_book.get_bids(), expected_bids.clone());
-        assert_eq!(order_book.get_asks(), expected_asks.clone());
+        assert_eq!(order_book.get_bids(), expected_bids.to_vec());
+        assert_eq!(order_book.get_asks(), expected_asks.to_vec());
         assert_eq!(order_book.best_bid(), expected_bids.first().cloned());
         assert_eq!(order_book.best_ask(), expected_asks.first().cloned());
         assert_eq!(order_book.worst_bid(), expected_bids.last().cloned());
         assert_eq!(order_book.worst_ask(), expected_asks.last().cloned());
+        assert_eq!(
+            order_book.iter_bids().collect::<Vec<_>>(),
+            expected_bids.to_vec()
+        );
+        assert_eq!(
+            order_book.iter_asks().collect::<Vec<_>>(),
+            expected_asks.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_order_book_analytics() {
+        let mut test_set = init_orderbook();
+        let order_book = &mut test_set.order_book;
+        // best bid 100.1 x 4, best ask 101.0 x 5
+        assert_eq!(order_book.mid_price(), Some(100.55));
+        assert_eq!(order_book.spread_ticks(), Some(90));
+        assert!((order_book.spread_bps().unwrap() - 0.9 / 100.55 * 10_000.0).abs() < 1e-9);
+        assert!((order_book.microprice().unwrap() - 100.5).abs() < 1e-9);
+        assert_eq!(order_book.bid_volume(2), 24);
+        assert_eq!(order_book.ask_volume(2), 7);
+        assert_eq!(order_book.ask_volume(10), 8);
+        assert_eq!(order_book.imbalance(2), Some(17.0 / 31.0));
+
+        order_book.clear();
+        assert_eq!(order_book.mid_price(), None);
+        assert_eq!(order_book.spread_ticks(), None);
+        assert_eq!(order_book.spread_bps(), None);
+        assert_eq!(order_book.microprice(), None);
+        assert_eq!(order_book.imbalance(5), None);
+
+        // one sided book has no mid, but has imbalance
+        add_bid(order_book, TickSize::new(0.01).to_price(100.0), 3);
+        assert_eq!(order_book.mid_price(), None);
+        assert_eq!(order_book.imbalance(5), Some(1.0));
+    }
+
+    #[test]
+    fn test_order_book_depth() {
+        let mut test_set = init_orderbook();
+        let order_book = &mut test_set.order_book;
+        let price = |price| TickSize::new(0.01).to_price(price);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.05)), 24);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.06)), 4);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(99.0)), 34);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.2)), 0);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(101.1)), 7);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(200.0)), 8);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(100.0)), 0);
+
+        // 5 @ 101.0 and 1 @ 101.1
+        let sweep = order_book.sweep(Side::Ask, 6).unwrap();
+        assert_eq!(sweep.qty, 6);
+        assert_eq!(sweep.worst_price, price(101.1));
+        assert!((sweep.avg_price - (101.0 * 5.0 + 101.1) / 6.0).abs() < 1e-9);
+        assert_eq!(order_book.vwap_to_fill(Side::Ask, 6), Some(sweep.avg_price));
+        assert_eq!(order_book.price_for_qty(Side::Ask, 6), Some(price(101.1)));
+        assert_eq!(order_book.vwap_to_fill(Side::Bid, 4), Some(100.1));
+        assert_eq!(order_book.price_for_qty(Side::Bid, 5), Some(price(100.05)));
+        // not enough qty, the sweep is partial
+        assert_eq!(
+            order_book.sweep(Side::Ask, 10),
+            Some(Sweep {
+                qty: 8,
+                avg_price: 101.15,
+                worst_price: price(102.0),
+            })
+        );
+        assert_eq!(order_book.vwap_to_fill(Side::Ask, 10), None);
+        assert_eq!(order_book.price_for_qty(Side::Ask, 10), None);
+        assert_eq!(order_book.sweep(Side::Bid, 0), None);
+
+        // mid is 100.55, 50 bps are 100.05 to 101.05 after rounding to ticks
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 10.0), Some(0));
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 50.0), Some(24));
+        assert_eq!(order_book.qty_within_bps(Side::Ask, 50.0), Some(5));
+        assert_eq!(order_book.qty_within_bps(Side::Ask, 100.0), Some(7));
+
+        order_book.clear();
+        assert_eq!(order_book.sweep(Side::Bid, 1), None);
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 50.0), None);
     }
 
     #[test]
@@ -407,7 +835,7 @@ mod tests {
         // Insert new bid
 
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.insert(1,(expected_bids[1].0 + 0.01, expected_bids[1].1 + 9));
+        expected_bids.insert(1, (expected_bids[1].0 + 1, expected_bids[1].1 + 9));
 
         test_set.order_book.add_bid(expected_bids[1].0, expected_bids[1].1).unwrap();
 
@@ -420,7 +848,7 @@ mod tests {
         // Insert new ask
 
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.insert(2,(expected_asks[2].0 - 0.01, expected_asks[2].1 + 3));
+        expected_asks.insert(2, (expected_asks[2].0 - 1, expected_asks[2].1 + 3));
 
         test_set.order_book.add_ask(expected_asks[2].0, expected_asks[2].1).unwrap();
 
@@ -464,8 +892,8 @@ mod tests {
     fn test_order_book_add_outside_price_range() {
         let mut test_set = init_orderbook();
         // add outside price range
-        assert!(test_set.order_book.add_bid(89.0, 10).is_err());
-        assert!(test_set.order_book.add_ask(111.0, 10).is_err());
+        assert!(test_set.order_book.add_bid(price(89.0), 10).is_err());
+        assert!(test_set.order_book.add_ask(price(111.0), 10).is_err());
 
         assert_order_book_levels(
             &test_set.order_book,
@@ -474,13 +902,82 @@ mod tests {
         );
     }
 
+    fn init_recenter_orderbook() -> TestSet {
+        let mut test_set = init_orderbook();
+        let mut config = *test_set.order_book.config();
+        config.recenter = true;
+        let mut order_book = Box::new(OrderBook::new(config));
+        order_book.init();
+        for (bid_price, bid_qty) in &test_set.initial_bids {
+            order_book.add_bid(*bid_price, *bid_qty).unwrap();
+        }
+        for (ask_price, ask_qty) in &test_set.initial_asks {
+            order_book.add_ask(*ask_price, *ask_qty).unwrap();
+        }
+        test_set.order_book = order_book;
+        test_set
+    }
+
+    #[test]
+    fn test_order_book_recenter_shift() {
+        let mut test_set = init_recenter_orderbook();
+
+        // add new best ask above max price, the range still fits into the current bounds
+        let mut expected_asks = test_set.initial_asks.clone();
+        expected_asks.push((price(115.0), 7));
+        test_set.order_book.add_ask(price(115.0), 7).unwrap();
+
+        assert_eq!(test_set.order_book.recenter_count(), 1);
+        let config = test_set.order_book.config();
+        assert!(config.min_price <= 100.0 && config.max_price >= 115.0);
+        assert!((config.max_price - config.min_price - 20.0).abs() < 1e-6);
+        assert_order_book_levels(&test_set.order_book, &test_set.initial_bids, &expected_asks);
+
+        // levels added after re-centering
+        let mut expected_bids = test_set.initial_bids.clone();
+        expected_bids.insert(1, (price(100.07), 3));
+        test_set.order_book.add_bid(price(100.07), 3).unwrap();
+        assert_order_book_levels(&test_set.order_book, &expected_bids, &expected_asks);
+    }
+
+    #[test]
+    fn test_order_book_recenter_widen() {
+        let mut test_set = init_recenter_orderbook();
+
+        // add new worst bid far below min price, the range doesn't fit into the current bounds
+        let mut expected_bids = test_set.initial_bids.clone();
+        expected_bids.push((price(70.0), 8));
+        test_set.order_book.add_bid(price(70.0), 8).unwrap();
+
+        assert_eq!(test_set.order_book.recenter_count(), 1);
+        let config = test_set.order_book.config();
+        assert!(config.min_price <= 70.0 && config.max_price >= 102.0);
+        assert!(config.max_price - config.min_price >= 40.0 - 1e-6);
+        assert_order_book_levels(&test_set.order_book, &expected_bids, &test_set.initial_asks);
+    }
+
+    #[test]
+    fn test_order_book_recenter_remove_outside_price_range() {
+        let mut test_set = init_recenter_orderbook();
+
+        test_set.order_book.add_bid(price(80.0), 0).unwrap();
+        test_set.order_book.add_ask(price(120.0), 0).unwrap();
+
+        assert_eq!(test_set.order_book.recenter_count(), 0);
+        assert_order_book_levels(
+            &test_set.order_book,
+            &test_set.initial_bids,
+            &test_set.initial_asks,
+        );
+    }
+
     #[test]
     fn test_order_book_add_best_levels() {
         let mut test_set = init_orderbook();
 
         // insert new best bid
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.insert(0, (expected_bids[0].0 + 0.01, expected_bids[0].1 + 5));
+        expected_bids.insert(0, (expected_bids[0].0 + 1, expected_bids[0].1 + 5));
         test_set.order_book.add_bid(expected_bids[0].0, expected_bids[0].1).unwrap();
 
         assert_order_book_levels(
@@ -491,7 +988,7 @@ mod tests {
 
         // insert new best ask
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.insert(0, (expected_asks[0].0 - 0.01, expected_asks[0].1 + 3));
+        expected_asks.insert(0, (expected_asks[0].0 - 1, expected_asks[0].1 + 3));
         test_set.order_book.add_ask(expected_asks[0].0, expected_asks[0].1).unwrap();
 
         assert_order_book_levels(
@@ -508,7 +1005,7 @@ mod tests {
 
         //insert new worst bid
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.push((expected_bids[2].0 - 0.01, expected_bids[2].1 + 1));
+        expected_bids.push((expected_bids[2].0 - 1, expected_bids[2].1 + 1));
         test_set.order_book.add_bid(expected_bids[3].0, expected_bids[3].1).unwrap();
         assert_order_book_levels(
             &test_set.order_book,
@@ -518,7 +1015,7 @@ mod tests {
 
         //insert new worst ask
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.push((expected_asks[2].0 + 0.01, expected_asks[2].1 + 2));
+        expected_asks.push((expected_asks[2].0 + 1, expected_asks[2].1 + 2));
         test_set.order_book.add_ask(expected_asks[3].0, expected_asks[3].1).unwrap();
         assert_order_book_levels(
             &test_set.order_book,
@@ -527,6 +1024,49 @@ mod tests {
         );
     }
     
+    #[test]
+    fn test_order_book_deep_random_updates() {
+        let config = crate::config::OrderBookConfig {
+            id: 0,
+            min_price: 0.0,
+            max_price: 9_999.99,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        };
+        let mut order_book = Box::new(OrderBook::new(config));
+        order_book.init();
+        let mut expected_bids = std::collections::BTreeMap::new();
+        let mut expected_asks = std::collections::BTreeMap::new();
+
+        // pseudo random sequence of inserts, updates and removals over the whole price range
+        let mut state: u64 = 7;
+        for _ in 0..20_000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let price = Price(((state >> 33) % 1_000_000) as i64);
+            let qty = (state >> 13) % 4;
+            let expected = if state & 1 == 0 {
+                order_book.add_bid(price, qty).unwrap();
+                &mut expected_bids
+            } else {
+                order_book.add_ask(price, qty).unwrap();
+                &mut expected_asks
+            };
+            if qty == 0 {
+                expected.remove(&price);
+            } else {
+                expected.insert(price, qty);
+            }
+        }
+
+        let expected_bids: Vec<(Price, u64)> = expected_bids.into_iter().rev().collect();
+        let expected_asks: Vec<(Price, u64)> = expected_asks.into_iter().collect();
+        assert!(expected_bids.len() > 1_000);
+        assert_order_book_levels(&order_book, &expected_bids, &expected_asks);
+    }
+
     #[test]
     fn test_order_book_clear() {
         let mut test_set = init_orderbook();
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{Read, Seek, SeekFrom},
     path::PathBuf,
 };
@@ -7,19 +7,41 @@ use std::{
 use anyhow::bail;
 use tracing::{debug, info, trace, warn};
 
-use crate::{array_orderbook, ser::Error};
+use crate::{
+    array_orderbook,
+    checkpoint::BookState,
+    observer::{self, Observer},
+    ser::{recovery::GapRecovery, Error, InputMode, SnapshotFormat},
+};
 
 pub mod common;
 pub mod incremental;
 pub mod snapshot;
 
+/// Reads the snapshot file of the given format into the order books created from the configs.
 pub fn read_snapshot_file(
     snapshot_file: PathBuf,
     configs: HashMap<u64, crate::config::OrderBookConfig>,
+    format: SnapshotFormat,
 ) -> anyhow::Result<HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>> {
     // Implement the logic to read the snapshot file
     info!("Reading snapshot file: {:?}", snapshot_file);
 
+    let mut order_books = init_order_books(&configs);
+    let file = std::fs::File::open(snapshot_file)?;
+    let mut reader = std::io::BufReader::new(file);
+    let mut buf: Vec<u8> = Vec::with_capacity(crate::ser::SNAPSHOT_RECORD_SIZE);
+    let mut recovery = GapRecovery::new();
+    while crate::ser::read_snapshot_record(&mut reader, format, &mut buf)? {
+        apply_snapshot_record(&buf, &mut order_books, format, &mut recovery)?;
+    }
+    Ok(order_books)
+}
+
+/// Creates and initializes the order books of the configured instruments.
+pub fn init_order_books(
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+) -> HashMap<u64, Box<array_orderbook::orderbook::OrderBook>> {
     let mut order_books: HashMap<u64, Box<array_orderbook::orderbook::OrderBook>> = HashMap::new();
     for (config_id, config) in configs.iter() {
         // boxed to force heap allocation
@@ -29,31 +51,175 @@ pub fn read_snapshot_file(
     }
 
     debug!("Initialized array orderbooks: {:?}", order_books);
-    let file = std::fs::File::open(snapshot_file)?;
-    let mut reader = std::io::BufReader::new(file);
-    let mut buf: [u8; crate::ser::SNAPSHOT_RECORD_SIZE] = [0; crate::ser::SNAPSHOT_RECORD_SIZE];
-    while reader.read_exact(&mut buf).is_ok() {
-        array_orderbook::ser::snapshot::read(&buf, &mut order_books)?;
+    order_books
+}
+
+/// Reads the snapshot record of the given format into the order book with the same ID.
+/// If the order book was stale, the updates buffered in the recovery are replayed on top of the
+/// snapshot, so the order book is re-synced.
+pub fn apply_snapshot_record(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>,
+    format: SnapshotFormat,
+    recovery: &mut GapRecovery,
+) -> anyhow::Result<()> {
+    apply_snapshot_record_observed(buf, order_books, format, recovery, &mut ())
+}
+
+/// Same as `apply_snapshot_record`, but reports the snapshot and the changes of the replayed
+/// updates to the observer.
+pub fn apply_snapshot_record_observed<O: Observer + ?Sized>(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>,
+    format: SnapshotFormat,
+    recovery: &mut GapRecovery,
+    observer: &mut O,
+) -> anyhow::Result<()> {
+    let id = crate::ser::read_u64_le(buf, crate::ser::SNAPSHOT_ID_OFFSET);
+    let prev_bbo = if observer.is_enabled() {
+        order_books
+            .get(&id)
+            .map(|order_book| observer::bbo(&**order_book))
+    } else {
+        None
+    };
+    match format {
+        SnapshotFormat::Legacy => snapshot::read(buf, order_books)?,
+        SnapshotFormat::Depth => {
+            snapshot::read_depth(buf, order_books)?;
+        }
+    }
+    if let (Some(prev_bbo), Some(order_book)) = (prev_bbo, order_books.get(&id)) {
+        observer::notify_bbo(observer, id, prev_bbo, &**order_book);
+        observer.on_snapshot_applied(id, order_book.seq_no);
+    }
+    recovery.replay(id, |buf| {
+        incremental::read_observed(buf, order_books, observer)
+    })?;
+    Ok(())
+}
+
+/// Restores the order book from its checkpoint state, the order book is created with the saved
+/// config, i.e. with the price bounds at the time of the checkpoint, and with the cross policy
+/// of the instrument's config.
+pub fn restore_order_book(
+    state: &BookState,
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+) -> anyhow::Result<Box<array_orderbook::orderbook::OrderBook>> {
+    let Some(mut config) = state.config else {
+        bail!("Missing config of order book ID {} in checkpoint", state.id);
+    };
+    if let Some(instrument) = configs.get(&state.id) {
+        config.cross_policy = instrument.cross_policy;
+    }
+    let mut order_book = Box::new(array_orderbook::orderbook::OrderBook::new(config));
+    order_book.init();
+    order_book.seq_no = state.seq_no;
+    order_book.timestamp = state.timestamp;
+    order_book.health = state.health;
+    order_book.cross_counts = state.cross_counts;
+    order_book.unknown_deletes = state.unknown_deletes;
+    for (price, qty) in state.bids.iter() {
+        order_book.add_bid(*price, *qty)?;
+    }
+    for (price, qty) in state.asks.iter() {
+        order_book.add_ask(*price, *qty)?;
+    }
+    Ok(order_book)
+}
+
+impl crate::orderbook::OrderBookReader for Box<array_orderbook::orderbook::OrderBook> {
+    fn init_order_books(config: &crate::config::Config) -> HashMap<u64, Self> {
+        init_order_books(&config.instruments)
+    }
+
+    fn apply_snapshot_record_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        config: &crate::config::Config,
+        recovery: &mut GapRecovery,
+        observer: &mut O,
+    ) -> anyhow::Result<()> {
+        apply_snapshot_record_observed(buf, order_books, config.snapshot_format, recovery, observer)
+    }
+
+    #[inline(always)]
+    fn apply_incremental_message_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        observer: &mut O,
+    ) -> Result<usize, Error> {
+        incremental::read_observed(buf, order_books, observer)
+    }
+
+    fn checkpoint(&self) -> BookState {
+        BookState::from_order_book(self, Some(*self.config()))
+    }
+
+    fn restore(state: &BookState, config: &crate::config::Config) -> anyhow::Result<Self> {
+        restore_order_book(state, &config.instruments)
+    }
+
+    fn read_snapshot_file(
+        snapshot_file: PathBuf,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<HashMap<u64, Self>> {
+        match config.input {
+            InputMode::Buffered => read_snapshot_file(
+                snapshot_file,
+                config.instruments.clone(),
+                config.snapshot_format,
+            ),
+            InputMode::Mmap => crate::ser::mmap::read_snapshot_file(snapshot_file, config),
+        }
+    }
+
+    fn read_incremental_file(
+        incremental_file: PathBuf,
+        order_books: &mut HashMap<u64, Self>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<()> {
+        match config.input {
+            InputMode::Buffered => read_incremental_file(
+                incremental_file,
+                order_books,
+                config.incremental_buffer_size,
+                None,
+            ),
+            InputMode::Mmap => {
+                crate::ser::mmap::read_incremental_file(incremental_file, order_books, None)
+            }
+        }
     }
-    Ok(order_books)
 }
 
 /// Reads the incremental updates from the file and applies them to the order books.
 /// Exceptions:
 /// * If the order book with the given ID does not exist, an error is returned.
-/// * If invalid data is encountered, an error is returned.
+/// * If invalid data or an invalid field value (see `crate::ser::validate`) is encountered,
+///   an error is returned.
+///
+/// * If a gap is detected, the order book is marked stale and its updates are buffered in the
+///   recovery until the order book is re-synced from a snapshot, see `apply_snapshot_record`.
+///   Without a recovery, i.e. if no snapshots are applied later, the updates are skipped.
+///
 /// The data is read in chunks, and each chunk is processed until the end of the file.
 /// The buffer size is specified to optimize reading performance.
 pub fn read_incremental_file(
     incremental_file: PathBuf,
     order_books: &mut HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>,
     buffer_size: usize,
+    mut recovery: Option<&mut GapRecovery>,
 ) -> anyhow::Result<()> {
     info!("Reading incremental file: {:?}", incremental_file);
     let file = std::fs::File::open(incremental_file)?;
+    let file_len = file.metadata()?.len();
     let mut reader = std::io::BufReader::new(file);
-    let mut buf: Vec<u8> = vec![0; buffer_size];
+    // the buffer holds at least the metadata of a message and grows for larger messages
+    let mut buf: Vec<u8> = vec![0; buffer_size.max(crate::ser::UPDATE_METADATA_SIZE)];
     let mut reader_offset = 0;
+    // order books with a gap, whose updates are skipped without a recovery
+    let mut stale_ids = HashSet::new();
     // Read the file in chunks
     while let Ok(bytes_read) = reader.read(&mut buf) {
         trace!("Read {} bytes from incremental file", bytes_read);
@@ -83,6 +249,20 @@ pub fn read_incremental_file(
                         Error::BufferTooSmall => {
                             // If the buffer is too small, need to seek back to the start of the current read and read the next chunk
                             trace!("Buffer too small for incremental update");
+                            if offset == 0 {
+                                // no complete message in the chunk, either it's truncated at
+                                // the end of the file, or it's larger than the buffer
+                                if (reader_offset + bytes_read) as u64 >= file_len {
+                                    bail!(
+                                        "Truncated incremental message at offset {}",
+                                        reader_offset
+                                    );
+                                }
+                                if bytes_read == buf.len() {
+                                    debug!("Growing incremental buffer to {} bytes", buf.len() * 2);
+                                    buf.resize(buf.len() * 2, 0);
+                                }
+                            }
                             // reader.seek_relative(-(bytes_read as i64 - offset as i64))?;
                             reader.seek(SeekFrom::Current(-(bytes_read as i64 - offset as i64)))?;
                         }
@@ -90,13 +270,39 @@ pub fn read_incremental_file(
                             // If the data is invalid, log the error and bail out
                             bail!("Invalid incremental update data: {}", msg);
                         }
-                        Error::GapDetected(id, new_offset) => {
-                            // If a gap is detected in the incremental updates
-                            // log a warning and read the next update
-                            warn!(
-                                "Gap detected in incremental updates for order book ID {}",
-                                id
+                        Error::CrossedBook(..) => {
+                            // the order book is crossed with the fail policy
+                            bail!(e);
+                        }
+                        Error::InvalidSide(..)
+                        | Error::InvalidPrice(..)
+                        | Error::OffTickPrice(..)
+                        | Error::InvalidNumUpdates(..)
+                        | Error::UnknownLevel(..) => {
+                            // the offset of the field is within the message
+                            bail!(
+                                "Invalid incremental message at offset {}: {}",
+                                reader_offset,
+                                e
                             );
+                        }
+                        Error::GapDetected(id, new_offset) => {
+                            // If a gap is detected in the incremental updates or the order book
+                            // is stale, buffer the update until the order book is re-synced
+                            if let Some(recovery) = recovery.as_deref_mut() {
+                                if recovery.buffered_size(id) == 0 {
+                                    warn!(
+                                        "Gap detected in incremental updates for order book ID {}",
+                                        id
+                                    );
+                                }
+                                recovery.buffer(id, &buf[offset..offset + new_offset]);
+                            } else if stale_ids.insert(id) {
+                                warn!(
+                                    "Gap detected in incremental updates for order book ID {}, skipping its updates",
+                                    id
+                                );
+                            }
                             offset += new_offset;
                             reader_offset += new_offset;
                             continue;
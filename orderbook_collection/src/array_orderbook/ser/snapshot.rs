@@ -1,9 +1,14 @@
-use crate::array_orderbook::{
-    orderbook::OrderBook,
-    ser::{
-        common::{read_f64, read_u64},
-        Error,
+use crate::{
+    array_orderbook::{
+        orderbook::OrderBook,
+        ser::{
+            common::{read_f64, read_u64},
+            Error,
+        },
     },
+    orderbook::{BookHealth, Side},
+    price::TickSize,
+    ser::validate,
 };
 
 ///
@@ -18,58 +23,154 @@ use crate::array_orderbook::{
 ///   - bid1 qty
 ///   - ask1 price
 ///   - ask1 qty
-///   ...
+///   - ...
 ///   - bid5 price
 ///   - bid5 qty
 ///   - ask5 price
 ///   - ask5 qty
+///
+/// Prices are converted to ticks using the tick size of the order book, levels with zero qty are
+/// empty and skipped, the prices of the other levels are validated, see `crate::ser::validate`.
+/// The order book is cleared and marked live, as it's in sync with the snapshot.
 pub fn read(
     buf: &[u8],
     orderbooks: &mut std::collections::HashMap<u64, Box<OrderBook>>,
 ) -> anyhow::Result<(), Error> {
+    if buf.len() < crate::ser::SNAPSHOT_RECORD_SIZE {
+        return Err(Error::BufferTooSmall);
+    }
     let ptr = buf.as_ptr();
     // Read metadata
-    let timestamp = read_u64(ptr, crate::ser::SNAPSHOT_TIMESTAMP_OFFSET);
-    let seq_no = read_u64(ptr, crate::ser::SNAPSHOT_SEQ_NO_OFFSET);
-    let id = read_u64(ptr, crate::ser::SNAPSHOT_ID_OFFSET);
+    // SAFETY: the buffer holds the whole record, checked above
+    let (timestamp, seq_no, id) = unsafe {
+        (
+            read_u64(ptr, crate::ser::SNAPSHOT_TIMESTAMP_OFFSET),
+            read_u64(ptr, crate::ser::SNAPSHOT_SEQ_NO_OFFSET),
+            read_u64(ptr, crate::ser::SNAPSHOT_ID_OFFSET),
+        )
+    };
 
     let orderbook = orderbooks
         .get_mut(&id)
-        .ok_or_else(|| Error::OrderBookNotFound(id))?;
+        .ok_or(Error::OrderBookNotFound(id))?;
     orderbook.clear();
     orderbook.timestamp = timestamp;
     orderbook.seq_no = seq_no;
+    orderbook.health = BookHealth::Live;
+    let tick_size = orderbook.tick_size();
     // Read bids and asks
+    // SAFETY: the buffer holds the 5 bid and 5 ask levels of the record, checked above
     let mut offset = crate::ser::SNAPSHOT_METADATA_SIZE;
     for _ in 0..5 {
-        let price = read_f64(ptr, offset);
-        offset += crate::ser::LEVEL_PRICE_SIZE;
-        let qty = read_u64(ptr, offset);
-        offset += crate::ser::LEVEL_QTY_SIZE;
-        orderbook.add_bid(price, qty).map_err(|e| {
-            Error::InvalidData(format!(
-                "Failed to add bid: {}, price: {}, qty: {}",
-                e, price, qty
-            ))
-        })?;
-        let price = read_f64(ptr, offset);
-        offset += crate::ser::LEVEL_PRICE_SIZE;
-        let qty = read_u64(ptr, offset);
-        offset += crate::ser::LEVEL_QTY_SIZE;
-        orderbook.add_ask(price, qty).map_err(|e| {
-            Error::InvalidData(format!(
-                "Failed to add ask: {}, price: {}, qty: {}",
-                e, price, qty
-            ))
-        })?;
+        let price = unsafe { read_f64(ptr, offset) };
+        let qty = unsafe { read_u64(ptr, offset + crate::ser::LEVEL_PRICE_SIZE) };
+        add_level(orderbook, Side::Bid, price, qty, &tick_size, offset)?;
+        offset += crate::ser::SNAPSHOT_LEVEL_SIZE;
+        let price = unsafe { read_f64(ptr, offset) };
+        let qty = unsafe { read_u64(ptr, offset + crate::ser::LEVEL_PRICE_SIZE) };
+        add_level(orderbook, Side::Ask, price, qty, &tick_size, offset)?;
+        offset += crate::ser::SNAPSHOT_LEVEL_SIZE;
     }
     Ok(())
 }
 
+/// Adds the level of the snapshot record with the price at the offset to the order book.
+/// Empty levels (zero qty) are skipped, e.g. the zero padding of a legacy record with less than
+/// 5 levels per side, whose zero price is outside of the bounds of the order book. Otherwise the
+/// price is validated, see `crate::ser::validate::price`.
+#[inline(always)]
+fn add_level(
+    orderbook: &mut OrderBook,
+    side: Side,
+    price: f64,
+    qty: u64,
+    tick_size: &TickSize,
+    offset: usize,
+) -> Result<(), Error> {
+    if qty == 0 {
+        return Ok(());
+    }
+    let price_ticks = validate::price(price, tick_size, offset)?;
+    let result = match side {
+        Side::Bid => orderbook.add_bid(price_ticks, qty),
+        Side::Ask => orderbook.add_ask(price_ticks, qty),
+    };
+    result.map_err(|e| {
+        Error::InvalidData(format!(
+            "Failed to add {:?}: {}, price: {}, qty: {}",
+            side, e, price, qty
+        ))
+    })
+}
+
+///
+/// Reads the snapshot data in the depth format (see `crate::ser::SnapshotFormat::Depth`)
+/// from the buffer into the order book.
+/// The buffer is expected to contain the following structure:
+/// - 8 bytes for timestamp (u64)
+/// - 8 bytes for sequence number (u64)
+/// - 8 bytes for ID (u64)
+/// - 8 bytes for number of bids (u64)
+/// - 8 bytes for number of asks (u64)
+/// - pairs of 8 bytes for price (f64) and 8 bytes for qty (u64) for each bid and then
+///   for each ask
+///
+/// Returns the size of the record.
+/// Prices are converted to ticks and validated the same as in `read`.
+/// The order book is cleared and marked live, as it's in sync with the snapshot.
+pub fn read_depth(
+    buf: &[u8],
+    orderbooks: &mut std::collections::HashMap<u64, Box<OrderBook>>,
+) -> anyhow::Result<usize, Error> {
+    let size = crate::ser::snapshot_record_size(buf, crate::ser::SnapshotFormat::Depth)?;
+    if buf.len() < size {
+        return Err(Error::BufferTooSmall);
+    }
+    let ptr = buf.as_ptr();
+    // Read metadata
+    // SAFETY: the buffer holds the whole record, checked above
+    let (timestamp, seq_no, id) = unsafe {
+        (
+            read_u64(ptr, crate::ser::SNAPSHOT_TIMESTAMP_OFFSET),
+            read_u64(ptr, crate::ser::SNAPSHOT_SEQ_NO_OFFSET),
+            read_u64(ptr, crate::ser::SNAPSHOT_ID_OFFSET),
+        )
+    };
+    // SAFETY: same as above
+    let num_bids = unsafe { read_u64(ptr, crate::ser::SNAPSHOT_NUM_BIDS_OFFSET) } as usize;
+
+    let orderbook = orderbooks
+        .get_mut(&id)
+        .ok_or(Error::OrderBookNotFound(id))?;
+    orderbook.clear();
+    orderbook.timestamp = timestamp;
+    orderbook.seq_no = seq_no;
+    orderbook.health = BookHealth::Live;
+    let tick_size = orderbook.tick_size();
+    // Read bids and then asks
+    let bids_end =
+        crate::ser::SNAPSHOT_DEPTH_METADATA_SIZE + num_bids * crate::ser::SNAPSHOT_LEVEL_SIZE;
+    // SAFETY: the buffer holds `size` bytes, i.e. whole levels after the metadata
+    let mut offset = crate::ser::SNAPSHOT_DEPTH_METADATA_SIZE;
+    while offset < size {
+        let price = unsafe { read_f64(ptr, offset) };
+        let qty = unsafe { read_u64(ptr, offset + crate::ser::LEVEL_PRICE_SIZE) };
+        let side = if offset < bids_end {
+            Side::Bid
+        } else {
+            Side::Ask
+        };
+        add_level(orderbook, side, price, qty, &tick_size, offset)?;
+        offset += crate::ser::SNAPSHOT_LEVEL_SIZE;
+    }
+    Ok(size)
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::orderbook::CrossPolicy;
 
     fn write_snapshot() -> Vec<u8> {
         let mut buf: Vec<u8> = Vec::new();
@@ -117,6 +218,8 @@ mod tests {
             min_price: 90.0,
             max_price: 110.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         };
         orderbooks.insert(1, Box::new(OrderBook::new(config)));
         orderbooks.get_mut(&1).unwrap().init();
@@ -130,32 +233,33 @@ mod tests {
 
         read(&buf, &mut orderbooks).unwrap();
         let orderbook = orderbooks.get(&1).unwrap();
+        let tick_size = orderbook.tick_size();
         assert_eq!(orderbook.id(), 1);
         assert_eq!(orderbook.seq_no, 2);
         assert_eq!(orderbook.timestamp, 1);
         assert_eq!(orderbook.get_bids().len(), 5);
         assert_eq!(orderbook.get_asks().len(), 5);
 
-        assert_eq!(orderbook.get_bids()[0].0, 108.0);
+        assert_eq!(orderbook.get_bids()[0].0, tick_size.to_price(108.0));
         assert_eq!(orderbook.get_bids()[0].1, 50);
-        assert_eq!(orderbook.get_bids()[1].0, 106.0);
+        assert_eq!(orderbook.get_bids()[1].0, tick_size.to_price(106.0));
         assert_eq!(orderbook.get_bids()[1].1, 40);
-        assert_eq!(orderbook.get_bids()[2].0, 104.0);
+        assert_eq!(orderbook.get_bids()[2].0, tick_size.to_price(104.0));
         assert_eq!(orderbook.get_bids()[2].1, 30);
-        assert_eq!(orderbook.get_bids()[3].0, 102.0);
+        assert_eq!(orderbook.get_bids()[3].0, tick_size.to_price(102.0));
         assert_eq!(orderbook.get_bids()[3].1, 20);
-        assert_eq!(orderbook.get_bids()[4].0, 100.0);
+        assert_eq!(orderbook.get_bids()[4].0, tick_size.to_price(100.0));
         assert_eq!(orderbook.get_bids()[4].1, 10);
 
-        assert_eq!(orderbook.get_asks()[0].0, 101.0);
+        assert_eq!(orderbook.get_asks()[0].0, tick_size.to_price(101.0));
         assert_eq!(orderbook.get_asks()[0].1, 5);
-        assert_eq!(orderbook.get_asks()[1].0, 103.0);
+        assert_eq!(orderbook.get_asks()[1].0, tick_size.to_price(103.0));
         assert_eq!(orderbook.get_asks()[1].1, 15);
-        assert_eq!(orderbook.get_asks()[2].0, 105.0);
+        assert_eq!(orderbook.get_asks()[2].0, tick_size.to_price(105.0));
         assert_eq!(orderbook.get_asks()[2].1, 25);
-        assert_eq!(orderbook.get_asks()[3].0, 107.0);
+        assert_eq!(orderbook.get_asks()[3].0, tick_size.to_price(107.0));
         assert_eq!(orderbook.get_asks()[3].1, 35);
-        assert_eq!(orderbook.get_asks()[4].0, 109.0);
+        assert_eq!(orderbook.get_asks()[4].0, tick_size.to_price(109.0));
         assert_eq!(orderbook.get_asks()[4].1, 45);
     }
 
@@ -165,30 +269,134 @@ mod tests {
 
         let mut buf = write_snapshot();
         // Modify the bid price to be out of bounds
-        let mut ptr = buf.as_mut_ptr();
         let offset = crate::ser::SNAPSHOT_METADATA_SIZE; // Bid1
         let price_out_of_bounds = 200f64; // Out of bounds price
-        unsafe {
-            ptr = ptr.add(offset);
-            std::ptr::write(ptr as *mut f64, price_out_of_bounds);
-        };
+        buf[offset..offset + 8].copy_from_slice(&price_out_of_bounds.to_le_bytes());
 
         let result = read(&buf, &mut orderbooks);
         assert!(matches!(result, Err(Error::InvalidData(_))));
 
         let mut buf = write_snapshot();
         // Modify the ask price to be out of bounds
-        let mut ptr = buf.as_mut_ptr();
         let offset = crate::ser::SNAPSHOT_METADATA_SIZE
             + crate::ser::LEVEL_PRICE_SIZE
             + crate::ser::LEVEL_QTY_SIZE; // Ask1
         let price_out_of_bounds = 200f64; // Out of bounds price
-        unsafe {
-            ptr = ptr.add(offset);
-            std::ptr::write(ptr as *mut f64, price_out_of_bounds);
-        };
+        buf[offset..offset + 8].copy_from_slice(&price_out_of_bounds.to_le_bytes());
 
         let result = read(&buf, &mut orderbooks);
         assert!(matches!(result, Err(Error::InvalidData(_))));
     }
+
+    fn write_snapshot_depth(bids: &[(f64, u64)], asks: &[(f64, u64)]) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(&1u64.to_le_bytes()); // timestamp
+        buf.extend_from_slice(&2u64.to_le_bytes()); // seq_no
+        buf.extend_from_slice(&1u64.to_le_bytes()); // id
+        buf.extend_from_slice(&(bids.len() as u64).to_le_bytes()); // number of bids
+        buf.extend_from_slice(&(asks.len() as u64).to_le_bytes()); // number of asks
+        for (price, qty) in bids.iter().chain(asks.iter()) {
+            buf.extend_from_slice(&price.to_le_bytes());
+            buf.extend_from_slice(&qty.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_read_snapshot_depth() {
+        let bids: Vec<(f64, u64)> = (0..20).map(|i| (100.0 - i as f64 * 0.5, i + 1)).collect();
+        let asks = vec![(100.5, 7), (101.0, 8)];
+        let mut buf = write_snapshot_depth(&bids, &asks);
+        // trailing data of the next record is not read
+        buf.extend_from_slice(&[0; 8]);
+        let mut orderbooks = init_orderbooks();
+
+        let size = read_depth(&buf, &mut orderbooks).unwrap();
+        assert_eq!(size, buf.len() - 8);
+        let orderbook = orderbooks.get(&1).unwrap();
+        let tick_size = orderbook.tick_size();
+        assert_eq!(orderbook.seq_no, 2);
+        assert_eq!(orderbook.timestamp, 1);
+        let to_prices = |levels: &[(f64, u64)]| -> Vec<(crate::price::Price, u64)> {
+            levels
+                .iter()
+                .map(|(price, qty)| (tick_size.to_price(*price), *qty))
+                .collect()
+        };
+        assert_eq!(orderbook.get_bids(), to_prices(&bids));
+        assert_eq!(orderbook.get_asks(), to_prices(&asks));
+    }
+
+    #[test]
+    fn test_read_snapshot_depth_empty_side_and_buffer_too_small() {
+        let buf = write_snapshot_depth(&[], &[(100.5, 7)]);
+        let mut orderbooks = init_orderbooks();
+
+        read_depth(&buf, &mut orderbooks).unwrap();
+        let orderbook = orderbooks.get(&1).unwrap();
+        assert_eq!(orderbook.get_bids().len(), 0);
+        assert_eq!(orderbook.get_asks().len(), 1);
+
+        let result = read_depth(&buf[..buf.len() - 1], &mut orderbooks);
+        assert!(matches!(result, Err(Error::BufferTooSmall)));
+    }
+
+    #[test]
+    fn test_read_snapshot_invalid_price() {
+        let mut orderbooks = init_orderbooks();
+
+        // empty levels are skipped whatever their price
+        let buf = write_snapshot_depth(&[(100.0, 1), (0.0, 0)], &[(f64::NAN, 0)]);
+        read_depth(&buf, &mut orderbooks).unwrap();
+        assert_eq!(orderbooks[&1].get_bids().len(), 1);
+        assert_eq!(orderbooks[&1].get_asks().len(), 0);
+
+        // the second bid is at offset 56
+        let buf = write_snapshot_depth(&[(100.0, 1), (0.0, 5)], &[]);
+        let result = read_depth(&buf, &mut orderbooks);
+        assert!(matches!(result, Err(Error::InvalidPrice(p, 56)) if p == 0.0));
+
+        let buf = write_snapshot_depth(&[], &[(100.005, 1)]);
+        let result = read_depth(&buf, &mut orderbooks);
+        assert!(matches!(result, Err(Error::OffTickPrice(p, 40)) if p == 100.005));
+
+        // the first ask of the legacy format is at offset 40
+        let mut buf = write_snapshot();
+        buf[40..48].copy_from_slice(&(-101f64).to_le_bytes());
+        let result = read(&buf, &mut orderbooks);
+        assert!(matches!(result, Err(Error::InvalidPrice(p, 40)) if p == -101.0));
+    }
+
+    #[test]
+    fn test_read_snapshot_legacy_padding() {
+        use crate::ser::{
+            writer::{write_snapshot_record, SnapshotRecord},
+            SnapshotFormat,
+        };
+        // the missing levels are written as zero price and qty, out of the bounds of the order
+        // book without recenter
+        let record = SnapshotRecord {
+            timestamp: 1,
+            seq_no: 2,
+            id: 1,
+            bids: vec![(100.0, 10), (99.5, 20)],
+            asks: vec![(100.5, 5)],
+        };
+        let mut buf = vec![];
+        write_snapshot_record(&mut buf, &record, SnapshotFormat::Legacy).unwrap();
+        let mut orderbooks = init_orderbooks();
+        assert!(!orderbooks[&1].config().recenter);
+
+        read(&buf, &mut orderbooks).unwrap();
+        let orderbook = &orderbooks[&1];
+        let tick_size = orderbook.tick_size();
+        assert_eq!(
+            orderbook.get_bids(),
+            vec![
+                (tick_size.to_price(100.0), 10),
+                (tick_size.to_price(99.5), 20)
+            ]
+        );
+        assert_eq!(orderbook.get_asks(), vec![(tick_size.to_price(100.5), 5)]);
+    }
 }
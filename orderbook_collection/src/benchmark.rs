@@ -1,21 +1,31 @@
-use core::f64;
 use std::{collections::HashMap, io::Read};
 
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use orderbook_collection_lib::{
     array_orderbook::{self},
     btree_orderbook::{
         self,
-        ser::{
-            incremental,
-            snapshot::{self, SNAPSHOT_RECORD_SIZE},
+        ser::{incremental, snapshot},
+    },
+    config::{Config, OrderBookConfig},
+    orderbook::{CrossPolicy, OrderBookReader},
+    price::{Price, TickSize},
+    run_array, run_sharded,
+    ser::{
+        writer::{
+            write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+            Side, SnapshotRecord,
         },
+        InputMode, SnapshotFormat, SNAPSHOT_RECORD_SIZE,
     },
 };
 
 pub fn read_u64_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("read_u64_benchmark");
     let buf = 100u64.to_le_bytes().to_vec();
+    // the level fields of incremental messages are at odd offsets
+    let mut unaligned_buf = vec![0u8];
+    unaligned_buf.extend_from_slice(&buf);
 
     group.significance_level(0.01).sample_size(50000);
     group.bench_function("safe_read", |b| {
@@ -28,18 +38,26 @@ pub fn read_u64_benchmark(c: &mut Criterion) {
             _ = unsafe_read(black_box(&mut &buf[0..]));
         })
     });
+    group.bench_function("unsafe_read_unaligned", |b| {
+        b.iter(|| {
+            _ = unsafe_read_unaligned(black_box(&unaligned_buf[0..]));
+        })
+    });
     group.finish();
 }
 
 fn safe_read(buf: &[u8]) -> u64 {
-    let value = btree_orderbook::ser::common::read_u64(&mut &buf[0..]).unwrap();
-    value
+    btree_orderbook::ser::common::read_u64(&mut &buf[0..]).unwrap()
 }
 
 fn unsafe_read(buf: &mut &[u8]) -> u64 {
     let ptr = buf.as_ptr();
-    let value = array_orderbook::ser::common::read_u64(ptr, 0);
-    value
+    unsafe { array_orderbook::ser::common::read_u64(ptr, 0) }
+}
+
+fn unsafe_read_unaligned(buf: &[u8]) -> u64 {
+    assert!(buf.len() >= 9);
+    unsafe { array_orderbook::ser::common::read_u64(buf.as_ptr(), 1) }
 }
 
 pub fn load_benchmark(c: &mut Criterion) {
@@ -92,13 +110,309 @@ pub fn read_levels_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
+pub fn deep_book_benchmark(c: &mut Criterion) {
+    let updates = deep_book_updates(DEEP_BOOK_LEVELS);
+
+    let mut group = c.benchmark_group("deep_book_benchmark");
+    group.significance_level(0.01).sample_size(1000);
+
+    let mut btree_order_book =
+        btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01));
+    group.bench_function("btree_deep_load", |b| {
+        b.iter(|| {
+            btree_deep_load_and_clear(black_box(&mut btree_order_book), black_box(&updates));
+        })
+    });
+    let mut array_order_books = init_array_orderbooks();
+    let array_order_book = array_order_books.get_mut(&1).unwrap();
+    group.bench_function("array_deep_load", |b| {
+        b.iter(|| {
+            array_deep_load_and_clear(black_box(array_order_book), black_box(&updates));
+        })
+    });
+
+    let mut btree_order_books = HashMap::new();
+    let mut btree_order_book =
+        btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01));
+    btree_deep_load(&mut btree_order_book, &updates);
+    btree_order_books.insert(1, btree_order_book);
+    group.bench_function("btree_deep_read_levels", |b| {
+        b.iter(|| {
+            _ = btree_read_levels(black_box(&mut btree_order_books));
+        })
+    });
+    let mut array_order_books = init_array_orderbooks();
+    array_order_books.remove(&2);
+    array_deep_load(array_order_books.get_mut(&1).unwrap(), &updates);
+    group.bench_function("array_deep_read_levels", |b| {
+        b.iter(|| {
+            _ = array_read_levels(black_box(&mut array_order_books));
+        })
+    });
+    group.finish();
+}
+
+const DEEP_BOOK_LEVELS: usize = 10_000;
+
+/// Generates updates for an order book of instrument 1 with the given number of levels per side
+/// in pseudo random order, bids below 5000.0 and asks above it (in ticks of 0.01).
+fn deep_book_updates(levels: usize) -> Vec<(u8, Price, u64)> {
+    let mut ticks: Vec<usize> = (1..=levels).collect();
+    let mut state: u64 = 42;
+    for i in (1..ticks.len()).rev() {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ticks.swap(i, (state >> 33) as usize % (i + 1));
+    }
+    let mid = TickSize::new(0.01).to_price(5000.0);
+    let mut updates = Vec::with_capacity(levels * 2);
+    for (i, tick) in ticks.into_iter().enumerate() {
+        updates.push((0, mid - tick as i64, i as u64 + 1));
+        updates.push((1, mid + tick as i64, i as u64 + 1));
+    }
+    updates
+}
+
+/// Compares reading the incremental file through the buffered reader and memory mapped.
+pub fn input_benchmark(c: &mut Criterion) {
+    let incremental_file = write_incremental_file(INPUT_MESSAGES);
+    let mut group = c.benchmark_group("input_benchmark");
+    group.significance_level(0.01).sample_size(100);
+    for input in [InputMode::Buffered, InputMode::Mmap] {
+        let config = Config {
+            instruments: init_array_orderbooks()
+                .iter()
+                .map(|(id, order_book)| (*id, *order_book.config()))
+                .collect(),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input,
+            recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        };
+        let mut btree_order_books = HashMap::from([(
+            1,
+            btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01)),
+        )]);
+        group.bench_function(format!("btree_{:?}", input).to_lowercase(), |b| {
+            b.iter(|| {
+                // the messages are applied again from the first one
+                btree_order_books.get_mut(&1).unwrap().seq_no = 0;
+                btree_orderbook::orderbook::OrderBook::read_incremental_file(
+                    black_box(incremental_file.clone()),
+                    &mut btree_order_books,
+                    &config,
+                )
+                .unwrap();
+            })
+        });
+        let mut array_order_books = init_array_orderbooks();
+        group.bench_function(format!("array_{:?}", input).to_lowercase(), |b| {
+            b.iter(|| {
+                array_order_books.get_mut(&1).unwrap().seq_no = 0;
+                <Box<array_orderbook::orderbook::OrderBook>>::read_incremental_file(
+                    black_box(incremental_file.clone()),
+                    &mut array_order_books,
+                    &config,
+                )
+                .unwrap();
+            })
+        });
+    }
+    group.finish();
+    std::fs::remove_file(incremental_file).unwrap();
+}
+
+const INPUT_MESSAGES: u64 = 100_000;
+
+/// Writes the incremental file with the given number of messages of order book 1, each with
+/// a bid and an ask update of the deep book.
+fn write_incremental_file(messages: u64) -> std::path::PathBuf {
+    let tick_size = TickSize::new(0.01);
+    let updates = deep_book_updates(1000);
+    let mut buf = vec![];
+    for seq_no in 1..=messages {
+        let i = (seq_no as usize * 2) % updates.len();
+        let message = IncrementalMessage {
+            timestamp: seq_no,
+            seq_no,
+            id: 1,
+            updates: updates[i..i + 2]
+                .iter()
+                .map(|(side, price, qty)| LevelUpdate {
+                    side: if *side == 0 { Side::Bid } else { Side::Ask },
+                    price: tick_size.to_f64(*price),
+                    qty: *qty,
+                })
+                .collect(),
+        };
+        write_incremental_message(&mut buf, &message).unwrap();
+    }
+    let path = std::env::temp_dir().join(format!("incremental_bench_{}.bin", std::process::id()));
+    std::fs::write(&path, buf).unwrap();
+    path
+}
+
+/// Compares the single threaded replay with the replay sharded by instrument ID across
+/// the given number of worker threads.
+pub fn sharded_benchmark(c: &mut Criterion) {
+    let (snapshot_file, incremental_file) =
+        write_sharded_files(SHARDED_INSTRUMENTS, SHARDED_MESSAGES);
+    let config = sharded_config(SHARDED_INSTRUMENTS);
+    let mut group = c.benchmark_group("sharded_benchmark");
+    group.significance_level(0.01).sample_size(20);
+    // messages per second
+    group.throughput(Throughput::Elements(SHARDED_MESSAGES));
+    group.bench_function("array_run", |b| {
+        b.iter(|| {
+            run_array(
+                black_box(snapshot_file.clone()),
+                incremental_file.clone(),
+                config.clone(),
+            )
+            .unwrap()
+        })
+    });
+    for shards in [1, 2, 4, 8] {
+        group.bench_function(format!("array_run_sharded_{}", shards), |b| {
+            b.iter(|| {
+                run_sharded::<Box<array_orderbook::orderbook::OrderBook>>(
+                    black_box(snapshot_file.clone()),
+                    incremental_file.clone(),
+                    config.clone(),
+                    shards,
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+    std::fs::remove_file(snapshot_file).unwrap();
+    std::fs::remove_file(incremental_file).unwrap();
+}
+
+const SHARDED_INSTRUMENTS: u64 = 8;
+const SHARDED_MESSAGES: u64 = 200_000;
+
+fn sharded_config(instruments: u64) -> Config {
+    Config {
+        instruments: (1..=instruments)
+            .map(|id| {
+                let config = OrderBookConfig {
+                    id,
+                    min_price: 4980.0,
+                    max_price: 5020.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                };
+                (id, config)
+            })
+            .collect(),
+        incremental_buffer_size: 1 << 16,
+        snapshot_format: SnapshotFormat::Legacy,
+        input: InputMode::Buffered,
+        recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
+    }
+}
+
+/// Writes the snapshot file with a snapshot of each instrument and the incremental file with
+/// the given number of messages interleaved across the instruments, each with 10 updates of
+/// the deep book.
+fn write_sharded_files(
+    instruments: u64,
+    messages: u64,
+) -> (std::path::PathBuf, std::path::PathBuf) {
+    let tick_size = TickSize::new(0.01);
+    let updates = deep_book_updates(1000);
+    let mut snapshots = vec![];
+    for id in 1..=instruments {
+        let record = SnapshotRecord {
+            timestamp: 0,
+            seq_no: 0,
+            id,
+            bids: vec![(4999.0, 1)],
+            asks: vec![(5001.0, 1)],
+        };
+        write_snapshot_record(&mut snapshots, &record, SnapshotFormat::Legacy).unwrap();
+    }
+    let mut incrementals = vec![];
+    for i in 0..messages {
+        let id = i % instruments + 1;
+        let seq_no = i / instruments + 1;
+        let start = (i as usize * 10) % updates.len();
+        let message = IncrementalMessage {
+            timestamp: i + 1,
+            seq_no,
+            id,
+            updates: updates[start..start + 10]
+                .iter()
+                .map(|(side, price, qty)| LevelUpdate {
+                    side: if *side == 0 { Side::Bid } else { Side::Ask },
+                    price: tick_size.to_f64(*price),
+                    qty: *qty,
+                })
+                .collect(),
+        };
+        write_incremental_message(&mut incrementals, &message).unwrap();
+    }
+    let dir = std::env::temp_dir();
+    let snapshot_file = dir.join(format!("snapshot_sharded_{}.bin", std::process::id()));
+    let incremental_file = dir.join(format!("incremental_sharded_{}.bin", std::process::id()));
+    std::fs::write(&snapshot_file, snapshots).unwrap();
+    std::fs::write(&incremental_file, incrementals).unwrap();
+    (snapshot_file, incremental_file)
+}
+
+fn btree_deep_load(
+    order_book: &mut btree_orderbook::orderbook::OrderBook,
+    updates: &[(u8, Price, u64)],
+) {
+    for (side, price, qty) in updates {
+        if *side == 0 {
+            order_book.add_bid(*price, *qty);
+        } else {
+            order_book.add_ask(*price, *qty);
+        }
+    }
+}
+
+fn btree_deep_load_and_clear(
+    order_book: &mut btree_orderbook::orderbook::OrderBook,
+    updates: &[(u8, Price, u64)],
+) {
+    btree_deep_load(order_book, updates);
+    order_book.clear();
+}
+
+fn array_deep_load(
+    order_book: &mut array_orderbook::orderbook::OrderBook,
+    updates: &[(u8, Price, u64)],
+) {
+    for (side, price, qty) in updates {
+        if *side == 0 {
+            order_book.add_bid(*price, *qty).unwrap();
+        } else {
+            order_book.add_ask(*price, *qty).unwrap();
+        }
+    }
+}
+
+fn array_deep_load_and_clear(
+    order_book: &mut array_orderbook::orderbook::OrderBook,
+    updates: &[(u8, Price, u64)],
+) {
+    array_deep_load(order_book, updates);
+    order_book.clear();
+}
+
 fn btree_read_levels(
     order_books: &mut HashMap<u64, orderbook_collection_lib::btree_orderbook::orderbook::OrderBook>,
-) -> (f64, f64, u64, u64, f64, f64, u64, u64) {
-    let mut best_bid_price = f64::MIN;
-    let mut worst_bid_price = f64::MAX;
-    let mut worst_ask_price = f64::MIN;
-    let mut best_ask_price = f64::MAX;
+) -> (Price, Price, u64, u64, Price, Price, u64, u64) {
+    let mut best_bid_price = Price::MIN;
+    let mut worst_bid_price = Price::MAX;
+    let mut worst_ask_price = Price::MIN;
+    let mut best_ask_price = Price::MAX;
     let mut max_bid_qty = 0;
     let mut min_bid_qty = u64::MAX;
     let mut max_ask_qty = 0;
@@ -140,11 +454,11 @@ fn btree_read_levels(
 
 fn array_read_levels(
     order_books: &mut HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>,
-) -> (f64, f64, u64, u64, f64, f64, u64, u64) {
-    let mut best_bid_price = f64::MIN;
-    let mut worst_bid_price = f64::MAX;
-    let mut worst_ask_price = f64::MIN;
-    let mut best_ask_price = f64::MAX;
+) -> (Price, Price, u64, u64, Price, Price, u64, u64) {
+    let mut best_bid_price = Price::MIN;
+    let mut worst_bid_price = Price::MAX;
+    let mut worst_ask_price = Price::MIN;
+    let mut best_ask_price = Price::MAX;
     let mut max_bid_qty = 0;
     let mut min_bid_qty = u64::MAX;
     let mut max_ask_qty = 0;
@@ -197,12 +511,16 @@ fn init_array_orderbooks() -> HashMap<u64, Box<array_orderbook::orderbook::Order
         min_price: 4000.0,
         max_price: 7000.0,
         tick_size: 0.01,
+        recenter: false,
+        cross_policy: CrossPolicy::Warn,
     };
     let config_2 = orderbook_collection_lib::config::OrderBookConfig {
         id: 2,
         min_price: 599000.0,
         max_price: 602000.0,
         tick_size: 0.01,
+        recenter: false,
+        cross_policy: CrossPolicy::Warn,
     };
 
     let mut order_books: HashMap<u64, Box<array_orderbook::orderbook::OrderBook>> = HashMap::new();
@@ -245,9 +563,10 @@ fn btree_update_incremental(
     order_books: &mut HashMap<u64, orderbook_collection_lib::btree_orderbook::orderbook::OrderBook>,
 ) -> Result<(), anyhow::Error> {
     let mut offset = 0;
-    Ok(while offset < incremental_buf.len() {
+    while offset < incremental_buf.len() {
         offset += incremental::read(&incremental_buf[offset..], order_books)?;
-    })
+    }
+    Ok(())
 }
 
 fn btree_load_snapshot(
@@ -259,7 +578,10 @@ fn btree_load_snapshot(
     let mut order_books = HashMap::new();
     let mut offset = 0;
     while offset < snapshot_buf.len() {
-        let orderbook = snapshot::read(&mut snapshot_buf[offset..offset + SNAPSHOT_RECORD_SIZE])?;
+        let orderbook = snapshot::read(
+            &snapshot_buf[offset..offset + SNAPSHOT_RECORD_SIZE],
+            &HashMap::new(),
+        )?;
         offset += SNAPSHOT_RECORD_SIZE;
         order_books.insert(orderbook.id, orderbook);
     }
@@ -298,9 +620,10 @@ fn array_update_incremental(
     incremental_buf: &mut [u8],
 ) -> Result<(), anyhow::Error> {
     let mut offset = 0;
-    Ok(while offset < incremental_buf.len() {
+    while offset < incremental_buf.len() {
         offset += array_orderbook::ser::incremental::read(&incremental_buf[offset..], order_books)?;
-    })
+    }
+    Ok(())
 }
 
 fn array_load_snapshot(
@@ -308,13 +631,14 @@ fn array_load_snapshot(
     snapshot_buf: &mut [u8],
 ) -> Result<(), anyhow::Error> {
     let mut offset = 0;
-    Ok(while offset < snapshot_buf.len() {
+    while offset < snapshot_buf.len() {
         array_orderbook::ser::snapshot::read(
-            &mut snapshot_buf[offset..offset + SNAPSHOT_RECORD_SIZE],
+            &snapshot_buf[offset..offset + SNAPSHOT_RECORD_SIZE],
             order_books,
         )?;
         offset += SNAPSHOT_RECORD_SIZE;
-    })
+    }
+    Ok(())
 }
 
 fn array_clear(
@@ -329,6 +653,9 @@ criterion_group!(
     benches,
     read_u64_benchmark,
     read_levels_benchmark,
-    load_benchmark
+    load_benchmark,
+    deep_book_benchmark,
+    input_benchmark,
+    sharded_benchmark
 );
 criterion_main!(benches);
@@ -1,78 +1,76 @@
 use std::collections::BTreeMap;
 
+use crate::{
+    orderbook::{BookHealth, CrossCounts, CrossPolicy, Side},
+    price::{Price, TickSize},
+};
+
+/// BTreeMap based order book implementation.
+/// Levels are keyed by price in ticks, so prices are compared as integers.
 #[derive(Default)]
 pub struct OrderBook {
     pub timestamp: u64,
     pub seq_no: u64,
     pub id: u64,
-    pub bids: BTreeMap<PriceLevel, Level>,
-    pub asks: BTreeMap<PriceLevel, Level>,
+    pub tick_size: TickSize,
+    pub health: BookHealth,
+    /// Policy of the instrument's config, see `config::OrderBookConfig::cross_policy`.
+    pub cross_policy: CrossPolicy,
+    pub cross_counts: CrossCounts,
+    pub unknown_deletes: u64,
+    pub bids: BTreeMap<Price, Level>,
+    pub asks: BTreeMap<Price, Level>,
+    /// Decoded updates of the incremental message being applied, kept to reuse the allocation,
+    /// see `ser::incremental::read_observed`.
+    pub(crate) updates: Vec<(Side, Price, u64)>,
 }
 
 pub struct Level {
-    pub price: f64,
+    pub price: Price,
     pub qty: u64,
 }
 
 impl Level {
-    pub fn new(price: f64, volume: u64) -> Self {
+    pub fn new(price: Price, volume: u64) -> Self {
         Self { price, qty: volume }
     }
 }
 
-#[derive(PartialEq)]
-pub struct PriceLevel {
-    pub price: f64,
-}
-
-impl PriceLevel {
-    pub fn new(price: f64) -> Self {
-        Self { price }
-    }
-}
-
-impl Eq for PriceLevel {}
-
-impl PartialOrd for PriceLevel {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.price.partial_cmp(&other.price)
-    }
-}
-
-impl Ord for PriceLevel {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.price
-            .partial_cmp(&other.price)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    }
-}
-
 impl OrderBook {
+    /// Creates an order book with the default tick size, see `price::DEFAULT_TICK_SIZE`.
     pub fn new(id: u64) -> Self {
+        Self::with_tick_size(id, TickSize::default())
+    }
+
+    pub fn with_tick_size(id: u64, tick_size: TickSize) -> Self {
         Self {
             id,
             seq_no: 0,
+            tick_size,
+            health: BookHealth::Live,
+            cross_policy: CrossPolicy::default(),
+            cross_counts: CrossCounts::default(),
+            unknown_deletes: 0,
             bids: BTreeMap::new(),
             asks: BTreeMap::new(),
             timestamp: 0,
+            updates: vec![],
         }
     }
 
-    pub fn add_bid(&mut self, price: f64, volume: u64) {
+    pub fn add_bid(&mut self, price: Price, volume: u64) {
         if volume == 0u64 {
-            self.bids.remove(&PriceLevel::new(price));
+            self.bids.remove(&price);
         } else {
-            self.bids
-                .insert(PriceLevel::new(price), Level::new(price, volume));
+            self.bids.insert(price, Level::new(price, volume));
         }
     }
 
-    pub fn add_ask(&mut self, price: f64, volume: u64) {
+    pub fn add_ask(&mut self, price: Price, volume: u64) {
         if volume == 0u64 {
-            self.asks.remove(&PriceLevel::new(price));
+            self.asks.remove(&price);
         } else {
-            self.asks
-                .insert(PriceLevel::new(price), Level::new(price, volume));
+            self.asks.insert(price, Level::new(price, volume));
         }
     }
 
@@ -81,49 +79,184 @@ impl OrderBook {
         self.asks.clear();
     }
 
-    pub fn get_bids(&self) -> Vec<(f64, u64)> {
+    pub fn get_bids(&self) -> Vec<(Price, u64)> {
         self.bids
             .values()
             .map(|x| (x.price, x.qty))
             .rev()
-            .into_iter()
             .collect()
     }
 
-    pub fn get_asks(&self) -> Vec<(f64, u64)> {
+    pub fn get_asks(&self) -> Vec<(Price, u64)> {
         self.asks
             .values()
             .map(|x| (x.price, x.qty))
-            .into_iter()
             .collect()
     }
 
-    pub fn best_bid(&self) -> Option<(f64, u64)> {
+    pub fn best_bid(&self) -> Option<(Price, u64)> {
         self.bids
             .iter()
             .last()
             .map(|(_, level)| (level.price, level.qty))
     }
 
-    pub fn best_ask(&self) -> Option<(f64, u64)> {
+    pub fn best_ask(&self) -> Option<(Price, u64)> {
         self.asks
             .iter()
             .next()
             .map(|(_, level)| (level.price, level.qty))
     }
 
-    pub fn worst_bid(&self) -> Option<(f64, u64)> {
+    pub fn worst_bid(&self) -> Option<(Price, u64)> {
         self.bids
             .iter()
             .next()
             .map(|(_, level)| (level.price, level.qty))
     }
-    pub fn worst_ask(&self) -> Option<(f64, u64)> {
+    pub fn worst_ask(&self) -> Option<(Price, u64)> {
         self.asks
             .iter()
             .last()
             .map(|(_, level)| (level.price, level.qty))
     }
+
+    pub fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.bids.values().rev().map(|x| (x.price, x.qty))
+    }
+
+    pub fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.asks.values().map(|x| (x.price, x.qty))
+    }
+
+    /// Total qty of the levels on the side at the price or better, iterating only over the range
+    /// of such levels.
+    pub fn qty_up_to(&self, side: Side, price: Price) -> u64 {
+        match side {
+            Side::Bid => self.bids.range(price..).map(|(_, level)| level.qty).sum(),
+            Side::Ask => self.asks.range(..=price).map(|(_, level)| level.qty).sum(),
+        }
+    }
+
+    /// Qty of the level on the side at the price, 0 if there's no such level.
+    pub fn qty_at(&self, side: Side, price: Price) -> u64 {
+        let levels = match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+        levels.get(&price).map_or(0, |level| level.qty)
+    }
+
+    fn to_f64(&self, levels: Vec<(Price, u64)>) -> Vec<(f64, u64)> {
+        levels
+            .into_iter()
+            .map(|(price, qty)| (self.tick_size.to_f64(price), qty))
+            .collect()
+    }
+}
+
+impl crate::orderbook::OrderBook for OrderBook {
+    #[inline(always)]
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline(always)]
+    fn seq_no(&self) -> u64 {
+        self.seq_no
+    }
+
+    #[inline(always)]
+    fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    #[inline(always)]
+    fn tick_size(&self) -> TickSize {
+        self.tick_size
+    }
+
+    #[inline(always)]
+    fn health(&self) -> BookHealth {
+        self.health
+    }
+
+    #[inline(always)]
+    fn cross_counts(&self) -> CrossCounts {
+        self.cross_counts
+    }
+
+    #[inline(always)]
+    fn unknown_deletes(&self) -> u64 {
+        self.unknown_deletes
+    }
+
+    #[inline(always)]
+    fn add_bid(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        self.add_bid(price, qty);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn add_ask(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        self.add_ask(price, qty);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn get_bids(&self) -> Vec<(Price, u64)> {
+        self.get_bids()
+    }
+
+    #[inline(always)]
+    fn get_asks(&self) -> Vec<(Price, u64)> {
+        self.get_asks()
+    }
+
+    #[inline(always)]
+    fn best_bid(&self) -> Option<(Price, u64)> {
+        self.best_bid()
+    }
+
+    #[inline(always)]
+    fn best_ask(&self) -> Option<(Price, u64)> {
+        self.best_ask()
+    }
+
+    #[inline(always)]
+    fn worst_bid(&self) -> Option<(Price, u64)> {
+        self.worst_bid()
+    }
+
+    #[inline(always)]
+    fn worst_ask(&self) -> Option<(Price, u64)> {
+        self.worst_ask()
+    }
+
+    #[inline(always)]
+    fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.iter_bids()
+    }
+
+    #[inline(always)]
+    fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        self.iter_asks()
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) {
+        self.clear()
+    }
+
+    #[inline(always)]
+    fn qty_up_to(&self, side: Side, price: Price) -> u64 {
+        self.qty_up_to(side, price)
+    }
+
+    #[inline(always)]
+    fn qty_at(&self, side: Side, price: Price) -> u64 {
+        self.qty_at(side, price)
+    }
 }
 
 impl std::fmt::Debug for OrderBook {
@@ -134,8 +267,8 @@ impl std::fmt::Debug for OrderBook {
             self.id,
             self.seq_no,
             self.timestamp,
-            self.get_bids(),
-            self.get_asks()
+            self.to_f64(self.get_bids()),
+            self.to_f64(self.get_asks())
         )
     }
 }
@@ -143,25 +276,38 @@ impl std::fmt::Debug for OrderBook {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::orderbook::{OrderBook as _, Sweep};
+
+    fn add_bid(order_book: &mut OrderBook, price: Price, qty: u64) {
+        order_book.add_bid(price, qty);
+    }
 
     struct TestSet {
         order_book: OrderBook,
-        initial_bids: Vec<(f64, u64)>,
-        initial_asks: Vec<(f64, u64)>,
+        initial_bids: Vec<(Price, u64)>,
+        initial_asks: Vec<(Price, u64)>,
+    }
+
+    fn prices(levels: &[(f64, u64)]) -> Vec<(Price, u64)> {
+        let tick_size = TickSize::new(0.01);
+        levels
+            .iter()
+            .map(|(price, qty)| (tick_size.to_price(*price), *qty))
+            .collect()
     }
 
     fn init_orderbook() -> TestSet {
-        let initial_bids = vec![
+        let initial_bids = prices(&[
             (100.1, 4),
             (100.05, 20),
             (100.0, 10),
-        ];
-        let initial_asks = vec![
+        ]);
+        let initial_asks = prices(&[
             (101.0, 5),
             (101.1, 2),
             (102.0, 1),
-        ];
-        let mut order_book = OrderBook::new(1);
+        ]);
+        let mut order_book = OrderBook::with_tick_size(1, TickSize::new(0.01));
 
         for (bid_price, bid_qty) in &initial_bids {
             order_book.add_bid(*bid_price, *bid_qty);
@@ -179,15 +325,96 @@ mod tests {
 
     fn assert_order_book_levels(
         order_book: &OrderBook,
-        expected_bids: &Vec<(f64, u64)>,
-        expected_asks: &Vec<(f64, u64)>,
+        expected_bids: &[(Price, u64)],
+        expected_asks: &[(Price, u64)],
     ) {
-        assert_eq!(order_book.get_bids(), expected_bids.clone());
-        assert_eq!(order_book.get_asks(), expected_asks.clone());
+        assert_eq!(order_book.get_bids(), expected_bids.to_vec());
+        assert_eq!(order_book.get_asks(), expected_asks.to_vec());
         assert_eq!(order_book.best_bid(), expected_bids.first().cloned());
         assert_eq!(order_book.best_ask(), expected_asks.first().cloned());
         assert_eq!(order_book.worst_bid(), expected_bids.last().cloned());
         assert_eq!(order_book.worst_ask(), expected_asks.last().cloned());
+        assert_eq!(
+            order_book.iter_bids().collect::<Vec<_>>(),
+            expected_bids.to_vec()
+        );
+        assert_eq!(
+            order_book.iter_asks().collect::<Vec<_>>(),
+            expected_asks.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_order_book_analytics() {
+        let mut test_set = init_orderbook();
+        let order_book = &mut test_set.order_book;
+        // best bid 100.1 x 4, best ask 101.0 x 5
+        assert_eq!(order_book.mid_price(), Some(100.55));
+        assert_eq!(order_book.spread_ticks(), Some(90));
+        assert!((order_book.spread_bps().unwrap() - 0.9 / 100.55 * 10_000.0).abs() < 1e-9);
+        assert!((order_book.microprice().unwrap() - 100.5).abs() < 1e-9);
+        assert_eq!(order_book.bid_volume(2), 24);
+        assert_eq!(order_book.ask_volume(2), 7);
+        assert_eq!(order_book.ask_volume(10), 8);
+        assert_eq!(order_book.imbalance(2), Some(17.0 / 31.0));
+
+        order_book.clear();
+        assert_eq!(order_book.mid_price(), None);
+        assert_eq!(order_book.spread_ticks(), None);
+        assert_eq!(order_book.spread_bps(), None);
+        assert_eq!(order_book.microprice(), None);
+        assert_eq!(order_book.imbalance(5), None);
+
+        // one sided book has no mid, but has imbalance
+        add_bid(order_book, TickSize::new(0.01).to_price(100.0), 3);
+        assert_eq!(order_book.mid_price(), None);
+        assert_eq!(order_book.imbalance(5), Some(1.0));
+    }
+
+    #[test]
+    fn test_order_book_depth() {
+        let mut test_set = init_orderbook();
+        let order_book = &mut test_set.order_book;
+        let price = |price| TickSize::new(0.01).to_price(price);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.05)), 24);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.06)), 4);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(99.0)), 34);
+        assert_eq!(order_book.qty_up_to(Side::Bid, price(100.2)), 0);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(101.1)), 7);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(200.0)), 8);
+        assert_eq!(order_book.qty_up_to(Side::Ask, price(100.0)), 0);
+
+        // 5 @ 101.0 and 1 @ 101.1
+        let sweep = order_book.sweep(Side::Ask, 6).unwrap();
+        assert_eq!(sweep.qty, 6);
+        assert_eq!(sweep.worst_price, price(101.1));
+        assert!((sweep.avg_price - (101.0 * 5.0 + 101.1) / 6.0).abs() < 1e-9);
+        assert_eq!(order_book.vwap_to_fill(Side::Ask, 6), Some(sweep.avg_price));
+        assert_eq!(order_book.price_for_qty(Side::Ask, 6), Some(price(101.1)));
+        assert_eq!(order_book.vwap_to_fill(Side::Bid, 4), Some(100.1));
+        assert_eq!(order_book.price_for_qty(Side::Bid, 5), Some(price(100.05)));
+        // not enough qty, the sweep is partial
+        assert_eq!(
+            order_book.sweep(Side::Ask, 10),
+            Some(Sweep {
+                qty: 8,
+                avg_price: 101.15,
+                worst_price: price(102.0),
+            })
+        );
+        assert_eq!(order_book.vwap_to_fill(Side::Ask, 10), None);
+        assert_eq!(order_book.price_for_qty(Side::Ask, 10), None);
+        assert_eq!(order_book.sweep(Side::Bid, 0), None);
+
+        // mid is 100.55, 50 bps are 100.05 to 101.05 after rounding to ticks
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 10.0), Some(0));
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 50.0), Some(24));
+        assert_eq!(order_book.qty_within_bps(Side::Ask, 50.0), Some(5));
+        assert_eq!(order_book.qty_within_bps(Side::Ask, 100.0), Some(7));
+
+        order_book.clear();
+        assert_eq!(order_book.sweep(Side::Bid, 1), None);
+        assert_eq!(order_book.qty_within_bps(Side::Bid, 50.0), None);
     }
 
     #[test]
@@ -235,7 +462,7 @@ mod tests {
         // Insert new bid
 
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.insert(1,(expected_bids[1].0 + 0.01, expected_bids[1].1 + 9));
+        expected_bids.insert(1, (expected_bids[1].0 + 1, expected_bids[1].1 + 9));
 
         test_set.order_book.add_bid(expected_bids[1].0, expected_bids[1].1);
 
@@ -248,7 +475,7 @@ mod tests {
         // Insert new ask
 
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.insert(2,(expected_asks[2].0 - 0.01, expected_asks[2].1 + 3));
+        expected_asks.insert(2, (expected_asks[2].0 - 1, expected_asks[2].1 + 3));
 
         test_set.order_book.add_ask(expected_asks[2].0, expected_asks[2].1);
 
@@ -293,7 +520,7 @@ mod tests {
 
         // insert new best bid
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.insert(0, (expected_bids[0].0 + 0.01, expected_bids[0].1 + 5));
+        expected_bids.insert(0, (expected_bids[0].0 + 1, expected_bids[0].1 + 5));
         test_set.order_book.add_bid(expected_bids[0].0, expected_bids[0].1);
 
         assert_order_book_levels(
@@ -304,7 +531,7 @@ mod tests {
 
         // insert new best ask
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.insert(0, (expected_asks[0].0 - 0.01, expected_asks[0].1 + 3));
+        expected_asks.insert(0, (expected_asks[0].0 - 1, expected_asks[0].1 + 3));
         test_set.order_book.add_ask(expected_asks[0].0, expected_asks[0].1);
 
         assert_order_book_levels(
@@ -321,7 +548,7 @@ mod tests {
 
         //insert new worst bid
         let mut expected_bids = test_set.initial_bids.clone();
-        expected_bids.push((expected_bids[2].0 - 0.01, expected_bids[2].1 + 1));
+        expected_bids.push((expected_bids[2].0 - 1, expected_bids[2].1 + 1));
         test_set.order_book.add_bid(expected_bids[3].0, expected_bids[3].1);
         assert_order_book_levels(
             &test_set.order_book,
@@ -331,7 +558,7 @@ mod tests {
 
         //insert new worst ask
         let mut expected_asks = test_set.initial_asks.clone();
-        expected_asks.push((expected_asks[2].0 + 0.01, expected_asks[2].1 + 2));
+        expected_asks.push((expected_asks[2].0 + 1, expected_asks[2].1 + 2));
         test_set.order_book.add_ask(expected_asks[3].0, expected_asks[3].1);
         assert_order_book_levels(
             &test_set.order_book,
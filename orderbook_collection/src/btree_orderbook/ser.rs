@@ -1,14 +1,20 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{Read, Seek, SeekFrom},
     path::PathBuf,
     vec,
 };
 
 use anyhow::bail;
-use tracing::{info, trace, warn};
+use tracing::{debug, info, trace, warn};
 
-use crate::btree_orderbook::orderbook::OrderBook;
+use crate::{
+    btree_orderbook::orderbook::OrderBook,
+    checkpoint::BookState,
+    observer::{self, Observer},
+    price::TickSize,
+    ser::{recovery::GapRecovery, InputMode, SnapshotFormat},
+};
 
 pub mod common;
 pub mod incremental;
@@ -16,36 +22,192 @@ pub mod snapshot;
 
 /// Reads the snapshot file and returns a map of order books indexed by their IDs.
 /// The snapshot file is expected to contain serialized order book data in a specific format.
-pub fn read_snapshot_file(snapshot_file: PathBuf) -> anyhow::Result<HashMap<u64, OrderBook>> {
+/// Tick sizes of the order books are taken from the configs, if configured.
+pub fn read_snapshot_file(
+    snapshot_file: PathBuf,
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+    format: SnapshotFormat,
+) -> anyhow::Result<HashMap<u64, OrderBook>> {
     info!("Reading snapshot file: {:?}", snapshot_file);
     let mut order_books = HashMap::new();
     let file = std::fs::File::open(snapshot_file)?;
     let mut reader = std::io::BufReader::new(file);
-    let mut buf: [u8; snapshot::SNAPSHOT_RECORD_SIZE] = [0; snapshot::SNAPSHOT_RECORD_SIZE];
-    while reader.read_exact(&mut buf).is_ok() {
-        let orderbook = snapshot::read(&buf)?;
-        // Store the order book in the map using its ID
-        order_books.insert(orderbook.id, orderbook);
+    let mut buf: Vec<u8> = Vec::with_capacity(crate::ser::SNAPSHOT_RECORD_SIZE);
+    let mut recovery = GapRecovery::new();
+    while crate::ser::read_snapshot_record(&mut reader, format, &mut buf)? {
+        apply_snapshot_record(&buf, &mut order_books, configs, format, &mut recovery)?;
     }
     Ok(order_books)
 }
 
+/// Reads the snapshot record of the given format and stores the order book in the map,
+/// replacing the order book with the same ID.
+/// If the order book was stale, the updates buffered in the recovery are replayed on top of the
+/// snapshot, so the order book is re-synced.
+pub fn apply_snapshot_record(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, OrderBook>,
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+    format: SnapshotFormat,
+    recovery: &mut GapRecovery,
+) -> anyhow::Result<()> {
+    apply_snapshot_record_observed(buf, order_books, configs, format, recovery, &mut ())
+}
+
+/// Same as `apply_snapshot_record`, but reports the snapshot and the changes of the replayed
+/// updates to the observer.
+pub fn apply_snapshot_record_observed<O: Observer + ?Sized>(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, OrderBook>,
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+    format: SnapshotFormat,
+    recovery: &mut GapRecovery,
+    observer: &mut O,
+) -> anyhow::Result<()> {
+    let mut orderbook = match format {
+        SnapshotFormat::Legacy => snapshot::read(buf, configs)?,
+        SnapshotFormat::Depth => snapshot::read_depth(buf, configs)?,
+    };
+    let id = orderbook.id;
+    if let Some(prev) = order_books.get(&id) {
+        orderbook.cross_counts = prev.cross_counts;
+        orderbook.unknown_deletes = prev.unknown_deletes;
+    }
+    if observer.is_enabled() {
+        // the order book is created by its first snapshot
+        let prev_bbo = order_books.get(&id).map_or((None, None), observer::bbo);
+        observer::notify_bbo(observer, id, prev_bbo, &orderbook);
+        observer.on_snapshot_applied(id, orderbook.seq_no);
+    }
+    // Store the order book in the map using its ID
+    order_books.insert(id, orderbook);
+    recovery.replay(id, |buf| {
+        incremental::read_observed(buf, order_books, observer)
+    })?;
+    Ok(())
+}
+
+/// Restores the order book from its checkpoint state.
+pub fn restore_order_book(
+    state: &BookState,
+    configs: &HashMap<u64, crate::config::OrderBookConfig>,
+) -> OrderBook {
+    let mut order_book = OrderBook::with_tick_size(state.id, TickSize::new(state.tick_size));
+    order_book.seq_no = state.seq_no;
+    order_book.timestamp = state.timestamp;
+    order_book.health = state.health;
+    order_book.cross_counts = state.cross_counts;
+    order_book.unknown_deletes = state.unknown_deletes;
+    if let Some(config) = configs.get(&state.id) {
+        order_book.cross_policy = config.cross_policy;
+    }
+    for (price, qty) in state.bids.iter() {
+        order_book.add_bid(*price, *qty);
+    }
+    for (price, qty) in state.asks.iter() {
+        order_book.add_ask(*price, *qty);
+    }
+    order_book
+}
+
+impl crate::orderbook::OrderBookReader for OrderBook {
+    fn init_order_books(_config: &crate::config::Config) -> HashMap<u64, Self> {
+        // order books are created by the snapshots
+        HashMap::new()
+    }
+
+    fn apply_snapshot_record_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        config: &crate::config::Config,
+        recovery: &mut GapRecovery,
+        observer: &mut O,
+    ) -> anyhow::Result<()> {
+        apply_snapshot_record_observed(
+            buf,
+            order_books,
+            &config.instruments,
+            config.snapshot_format,
+            recovery,
+            observer,
+        )
+    }
+
+    #[inline(always)]
+    fn apply_incremental_message_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        observer: &mut O,
+    ) -> Result<usize, crate::ser::Error> {
+        incremental::read_observed(buf, order_books, observer)
+    }
+
+    fn checkpoint(&self) -> BookState {
+        BookState::from_order_book(self, None)
+    }
+
+    fn restore(state: &BookState, config: &crate::config::Config) -> anyhow::Result<Self> {
+        Ok(restore_order_book(state, &config.instruments))
+    }
+
+    fn read_snapshot_file(
+        snapshot_file: PathBuf,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<HashMap<u64, Self>> {
+        match config.input {
+            InputMode::Buffered => {
+                read_snapshot_file(snapshot_file, &config.instruments, config.snapshot_format)
+            }
+            InputMode::Mmap => crate::ser::mmap::read_snapshot_file(snapshot_file, config),
+        }
+    }
+
+    fn read_incremental_file(
+        incremental_file: PathBuf,
+        order_books: &mut HashMap<u64, Self>,
+        config: &crate::config::Config,
+    ) -> anyhow::Result<()> {
+        match config.input {
+            InputMode::Buffered => read_incremental_file(
+                incremental_file,
+                order_books,
+                config.incremental_buffer_size,
+                None,
+            ),
+            InputMode::Mmap => {
+                crate::ser::mmap::read_incremental_file(incremental_file, order_books, None)
+            }
+        }
+    }
+}
+
 /// Reads the incremental updates from the file and applies them to the order books.
 /// Exceptions:
 /// * If the order book with the given ID does not exist, an error is returned.
-/// * If invalid data is encountered, an error is returned.
+/// * If invalid data or an invalid field value (see `crate::ser::validate`) is encountered,
+///   an error is returned.
+///
+/// * If a gap is detected, the order book is marked stale and its updates are buffered in the
+///   recovery until the order book is re-synced from a snapshot, see `apply_snapshot_record`.
+///   Without a recovery, i.e. if no snapshots are applied later, the updates are skipped.
+///
 /// The data is read in chunks, and each chunk is processed until the end of the file.
 /// The buffer size is specified to optimize reading performance.
 pub fn read_incremental_file(
     incremental_file: PathBuf,
     order_books: &mut HashMap<u64, OrderBook>,
     buffer_size: usize,
+    mut recovery: Option<&mut GapRecovery>,
 ) -> anyhow::Result<()> {
     info!("Reading incremental file: {:?}", incremental_file);
     let file = std::fs::File::open(incremental_file)?;
+    let file_len = file.metadata()?.len();
     let mut reader = std::io::BufReader::new(file);
-    let mut buf: Vec<u8> = vec![0; buffer_size];
+    // the buffer holds at least the metadata of a message and grows for larger messages
+    let mut buf: Vec<u8> = vec![0; buffer_size.max(crate::ser::UPDATE_METADATA_SIZE)];
     let mut reader_offset = 0;
+    // order books with a gap, whose updates are skipped without a recovery
+    let mut stale_ids = HashSet::new();
     // Read the file in chunks
     while let Ok(bytes_read) = reader.read(&mut buf) {
         trace!("Read {} bytes from incremental file", bytes_read);
@@ -75,6 +237,23 @@ pub fn read_incremental_file(
                         crate::ser::Error::BufferTooSmall => {
                             // If the buffer is too small, need to seek back to the start of the current read and read the next chunk
                             trace!("Buffer too small for incremental update");
+                            if offset == 0 {
+                                // no complete message in the chunk, either it's truncated at
+                                // the end of the file, or it's larger than the buffer
+                                if (reader_offset + bytes_read) as u64 >= file_len {
+                                    bail!(
+                                        "Truncated incremental message at offset {}",
+                                        reader_offset
+                                    );
+                                }
+                                if bytes_read == buf.len() {
+                                    debug!(
+                                        "Growing incremental buffer to {} bytes",
+                                        buf.len() * 2
+                                    );
+                                    buf.resize(buf.len() * 2, 0);
+                                }
+                            }
                             // reader.seek_relative(-(bytes_read as i64 - offset as i64))?;
                             reader.seek(SeekFrom::Current(-(bytes_read as i64 - offset as i64)))?;
                         }
@@ -82,13 +261,39 @@ pub fn read_incremental_file(
                             // If the data is invalid, log the error and bail out
                             bail!("Invalid incremental update data: {}", msg);
                         }
-                        crate::ser::Error::GapDetected(id, new_offset) => {
-                            // If a gap is detected in the incremental updates
-                            // log a warning and read the next update
-                            warn!(
-                                "Gap detected in incremental updates for order book ID {}",
-                                id
+                        crate::ser::Error::CrossedBook(..) => {
+                            // the order book is crossed with the fail policy
+                            bail!(e);
+                        }
+                        crate::ser::Error::InvalidSide(..)
+                        | crate::ser::Error::InvalidPrice(..)
+                        | crate::ser::Error::OffTickPrice(..)
+                        | crate::ser::Error::InvalidNumUpdates(..)
+                        | crate::ser::Error::UnknownLevel(..) => {
+                            // the offset of the field is within the message
+                            bail!(
+                                "Invalid incremental message at offset {}: {}",
+                                reader_offset,
+                                e
                             );
+                        }
+                        crate::ser::Error::GapDetected(id, new_offset) => {
+                            // If a gap is detected in the incremental updates or the order book
+                            // is stale, buffer the update until the order book is re-synced
+                            if let Some(recovery) = recovery.as_deref_mut() {
+                                if recovery.buffered_size(id) == 0 {
+                                    warn!(
+                                        "Gap detected in incremental updates for order book ID {}",
+                                        id
+                                    );
+                                }
+                                recovery.buffer(id, &buf[offset..offset + new_offset]);
+                            } else if stale_ids.insert(id) {
+                                warn!(
+                                    "Gap detected in incremental updates for order book ID {}, skipping its updates",
+                                    id
+                                );
+                            }
                             offset += new_offset;
                             reader_offset += new_offset;
                             continue;
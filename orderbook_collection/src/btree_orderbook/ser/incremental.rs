@@ -1,11 +1,16 @@
 use std::collections::HashMap;
 
+use tracing::warn;
+
 use crate::{
     btree_orderbook::{
         orderbook::OrderBook,
         ser::common::{read_f64, read_u64},
     },
-    ser::Error,
+    observer::{self, Observer},
+    orderbook::{BookHealth, CrossPolicy, Side},
+    price::Price,
+    ser::{validate, Error},
 };
 
 /// Reads the incremental update data from the buffer into the order book.
@@ -22,15 +27,32 @@ use crate::{
 /// Exceptions:
 /// * If the order book with the given ID does not exist, an error Error::OrderBookNotFound is returned.
 /// * If the sequence number is older than the current sequence number of the order book,
-/// the update is skipped.
-/// * If the sequence number is greater than the current sequence number + 1,
-/// the update is also skipped.
+///   the update is skipped.
+/// * If the sequence number is greater than the current sequence number + 1, the order book is
+///   marked stale and an error Error::GapDetected is returned. Updates of a stale order book
+///   are skipped with the same error until it is re-synced from a snapshot.
 /// * If the buffer is too small to contain the updates, an error Error::BufferTooSmall is returned.
 /// * If the data is invalid (e.g., cannot read price or volume), an error Error::InvalidData is returned.
+/// * If a field value of an applied update is invalid, i.e. an unknown side, a price which isn't
+///   finite and positive or isn't on the tick grid, or removing a level which doesn't exist,
+///   an error with the offset of the field is returned, see `ser::validate`. The order book is
+///   left unchanged.
+/// * With `CrossPolicy::Drop`, removing a level which doesn't exist is skipped and counted
+///   instead, see `unknown_deletes`.
 ///
 /// Otherwise, the updates are applied to the order book.
+/// Prices are converted to ticks using the tick size of the order book.
 pub fn read(buf: &[u8], orderbooks: &mut HashMap<u64, OrderBook>) -> anyhow::Result<usize, Error> {
-    if buf.len() < crate::ser::UPDATE_METADATA_SIZE + crate::ser::UPDATE_LEVEL_SIZE {
+    read_observed(buf, orderbooks, &mut ())
+}
+
+/// Same as `read`, but reports the changes of the order book to the observer.
+pub fn read_observed<O: Observer + ?Sized>(
+    buf: &[u8],
+    orderbooks: &mut HashMap<u64, OrderBook>,
+    observer: &mut O,
+) -> anyhow::Result<usize, Error> {
+    if buf.len() < crate::ser::UPDATE_METADATA_SIZE {
         return Err(Error::BufferTooSmall);
     }
     // reading metadata
@@ -41,54 +63,162 @@ pub fn read(buf: &[u8], orderbooks: &mut HashMap<u64, OrderBook>) -> anyhow::Res
     let id = read_u64(&mut &buf[crate::ser::UPDATE_ID_OFFSET..])
         .map_err(|_| Error::InvalidData("Failed to read ID".into()))?;
     let num_updates = read_u64(&mut &buf[crate::ser::UPDATE_NUM_UPDATES_OFFSET..])
-        .map_err(|_| Error::InvalidData("Failed to read number of updates".into()))?
-        as usize;
+        .map_err(|_| Error::InvalidData("Failed to read number of updates".into()))?;
+    let size = crate::ser::incremental_message_size(num_updates)?;
     let mut offset = crate::ser::UPDATE_METADATA_SIZE;
     // check if the buffer is large enough for the updates
-    if buf.len() < offset + num_updates * crate::ser::UPDATE_LEVEL_SIZE {
+    if buf.len() < size {
         return Err(Error::BufferTooSmall);
     }
     // get order book and check if update is valid
     let orderbook = orderbooks
         .get_mut(&id)
-        .ok_or_else(|| Error::OrderBookNotFound(id))?;
+        .ok_or(Error::OrderBookNotFound(id))?;
     // update is stale - skip it
     if seq_no < orderbook.seq_no {
-        return Ok(offset + num_updates * crate::ser::UPDATE_LEVEL_SIZE);
+        return Ok(size);
     }
-    // there's a gap - skip the update
-    if seq_no > orderbook.seq_no + 1 {
-        return Err(Error::GapDetected(
-            id,
-            offset + num_updates * crate::ser::UPDATE_LEVEL_SIZE,
-        ));
+    // the order book is invalid until the next snapshot - skip the update
+    if orderbook.health == BookHealth::Invalid {
+        return Ok(size);
     }
-    orderbook.timestamp = timestamp;
-    orderbook.seq_no = seq_no;
+    // there's a gap or the order book is already stale - skip the update
+    if seq_no > orderbook.seq_no.saturating_add(1) || orderbook.health == BookHealth::Stale {
+        if orderbook.health == BookHealth::Live && observer.is_enabled() {
+            observer.on_gap_detected(id, orderbook.seq_no + 1, seq_no);
+        }
+        orderbook.health = BookHealth::Stale;
+        return Err(Error::GapDetected(id, size));
+    }
+    let tick_size = orderbook.tick_size;
+    // the feed can remove a level dropped by `CrossPolicy::Drop`, otherwise removing a level,
+    // which doesn't exist, is invalid
+    let skip_unknown = orderbook.cross_policy == CrossPolicy::Drop;
+    let mut unknown_deletes = 0;
 
-    // reading updates
+    // decoding and validating all the updates before applying any, so an invalid message leaves
+    // the order book unchanged
+    orderbook.updates.clear();
     for _ in 0..num_updates {
-        let side = buf[offset];
+        let level_offset = offset;
+        let side = validate::side(buf[offset], offset)?;
         offset += crate::ser::LEVEL_SIDE_SIZE;
-        let price = read_f64(&mut &buf[offset..])
+        let decimal_price = read_f64(&mut &buf[offset..])
             .map_err(|_| Error::InvalidData("Failed to read price".into()))?;
+        let price = validate::price(decimal_price, &tick_size, offset)?;
         offset += crate::ser::LEVEL_PRICE_SIZE;
         let volume = read_u64(&mut &buf[offset..])
             .map_err(|_| Error::InvalidData("Failed to read volume".into()))?;
         offset += crate::ser::LEVEL_QTY_SIZE;
-        if side == 0 {
+        // e.g. a level dropped by `CrossPolicy::Drop`
+        if volume == 0 && level_volume(orderbook, side, price) == 0 {
+            if !skip_unknown {
+                return Err(Error::UnknownLevel(side, decimal_price, level_offset));
+            }
+            unknown_deletes += 1;
+            warn!(
+                "Removing {:?} level {} of order book ID {}, which doesn't exist, in update {}",
+                side, decimal_price, id, seq_no
+            );
+            continue;
+        }
+        orderbook.updates.push((side, price, volume));
+    }
+
+    orderbook.timestamp = timestamp;
+    orderbook.seq_no = seq_no;
+    orderbook.unknown_deletes += unknown_deletes;
+    let prev_bbo = observer.is_enabled().then(|| observer::bbo(orderbook));
+    // highest bid and lowest ask added by the message, see `orderbook::drop_crossed_levels`
+    let (mut max_bid, mut min_ask): (Option<Price>, Option<Price>) = (None, None);
+
+    // applying the decoded updates
+    for i in 0..orderbook.updates.len() {
+        let (side, price, volume) = orderbook.updates[i];
+        let prev_volume = if observer.is_enabled() {
+            orderbook.qty_at(side, price)
+        } else {
+            0
+        };
+        if side == Side::Bid {
             orderbook.add_bid(price, volume);
         } else {
             orderbook.add_ask(price, volume);
         }
+        if volume > 0 {
+            match side {
+                Side::Bid => max_bid = max_bid.max(Some(price)),
+                Side::Ask => min_ask = Some(min_ask.map_or(price, |ask| ask.min(price))),
+            }
+        }
+        if observer.is_enabled() {
+            observer::notify_level(observer, id, side, price, prev_volume, volume);
+        }
+    }
+
+    if let Some(cross) = crate::orderbook::cross(orderbook) {
+        orderbook.cross_counts.count(cross);
+        match orderbook.cross_policy {
+            CrossPolicy::Warn => {
+                warn!(
+                    "Order book ID {} is {:?} after update {}",
+                    id, cross, seq_no
+                );
+            }
+            CrossPolicy::Drop => {
+                let dropped = crate::orderbook::drop_crossed_levels(orderbook, max_bid, min_ask)
+                    .map_err(|e| {
+                        Error::InvalidData(format!("Failed to drop crossed levels: {}", e))
+                    })?;
+                orderbook.cross_counts.dropped_levels += dropped.len() as u64;
+                if observer.is_enabled() {
+                    for (side, price) in dropped {
+                        observer.on_level_removed(id, side, price);
+                    }
+                }
+            }
+            CrossPolicy::Invalidate => {
+                warn!(
+                    "Order book ID {} is {:?} after update {}, skipping updates until the next snapshot",
+                    id, cross, seq_no
+                );
+                orderbook.health = BookHealth::Invalid;
+            }
+            CrossPolicy::Fail => return Err(Error::CrossedBook(id, seq_no)),
+        }
+    }
+    if let Some(prev_bbo) = prev_bbo {
+        observer::notify_bbo(observer, id, prev_bbo, orderbook);
+        observer.on_book_updated(id, seq_no, timestamp);
     }
     Ok(offset)
 }
 
+/// Volume of the level after the decoded updates of the message, see `OrderBook::updates`.
+fn level_volume(orderbook: &OrderBook, side: Side, price: Price) -> u64 {
+    orderbook
+        .updates
+        .iter()
+        .rev()
+        .find(|update| update.0 == side && update.1 == price)
+        .map_or_else(|| orderbook.qty_at(side, price), |update| update.2)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::btree_orderbook::orderbook::OrderBook;
+    use crate::{
+        btree_orderbook::orderbook::OrderBook,
+        price::TickSize,
+        ser::writer::{write_incremental_message, IncrementalMessage, LevelUpdate, Side},
+    };
+
+    // half a tick of the default tick size
+    const PRICE_OFF_GRID: f64 = 100.000000005;
+
+    fn price(price: f64) -> crate::price::Price {
+        TickSize::default().to_price(price)
+    }
 
     fn init_orderbooks() -> HashMap<u64, OrderBook> {
         let mut order_books = HashMap::new();
@@ -98,24 +228,28 @@ mod tests {
         order_books.get_mut(&3).unwrap().seq_no = 1; // Set initial seq_no
         order_books.get_mut(&3).unwrap().timestamp = 1; // Set initial timestamp
 
-        order_books.get_mut(&3).unwrap().add_bid(100.0, 10); // Add initial bid
-        order_books.get_mut(&3).unwrap().add_ask(101.0, 5); // Add initial ask
+        order_books.get_mut(&3).unwrap().add_bid(price(100.0), 10); // Add initial bid
+        order_books.get_mut(&3).unwrap().add_ask(price(101.0), 5); // Add initial ask
 
         order_books
     }
 
     fn write_update(id: u64, timestamp: u64, seq_no: u64, updates: &[(u8, f64, u64)]) -> Vec<u8> {
+        let message = IncrementalMessage {
+            timestamp,
+            seq_no,
+            id,
+            updates: updates
+                .iter()
+                .map(|(side, price, qty)| LevelUpdate {
+                    side: if *side == 0 { Side::Bid } else { Side::Ask },
+                    price: *price,
+                    qty: *qty,
+                })
+                .collect(),
+        };
         let mut buf = Vec::new();
-        buf.extend_from_slice(&timestamp.to_le_bytes());
-        buf.extend_from_slice(&seq_no.to_le_bytes());
-        buf.extend_from_slice(&id.to_le_bytes());
-        buf.extend_from_slice(&(updates.len() as u64).to_le_bytes());
-
-        for (side, price, qty) in updates {
-            buf.push(*side);
-            buf.extend_from_slice(&price.to_le_bytes());
-            buf.extend_from_slice(&qty.to_le_bytes());
-        }
+        write_incremental_message(&mut buf, &message).unwrap();
         buf
     }
 
@@ -134,10 +268,10 @@ mod tests {
         assert_eq!(order_book.seq_no, 2);
         assert_eq!(order_book.timestamp, 2);
         assert_eq!(order_book.get_bids().len(), 1);
-        assert_eq!(order_book.get_bids()[0].0, 100.0);
+        assert_eq!(order_book.get_bids()[0].0, price(100.0));
         assert_eq!(order_book.get_bids()[0].1, 10);
         assert_eq!(order_book.get_asks().len(), 1);
-        assert_eq!(order_book.get_asks()[0].0, 101.0);
+        assert_eq!(order_book.get_asks()[0].0, price(101.0));
         assert_eq!(order_book.get_asks()[0].1, 5);
     }
 
@@ -162,13 +296,14 @@ mod tests {
         assert_eq!(order_books.len(), 1);
         let order_book = order_books.get(&3).unwrap();
         assert_eq!(order_book.id, 3);
+        assert_eq!(order_book.health, crate::orderbook::BookHealth::Stale);
         assert_eq!(order_book.seq_no, 1);
         assert_eq!(order_book.timestamp, 1);
         assert_eq!(order_book.get_bids().len(), 1);
-        assert_eq!(order_book.get_bids()[0].0, 100.0);
+        assert_eq!(order_book.get_bids()[0].0, price(100.0));
         assert_eq!(order_book.get_bids()[0].1, 10);
         assert_eq!(order_book.get_asks().len(), 1);
-        assert_eq!(order_book.get_asks()[0].0, 101.0);
+        assert_eq!(order_book.get_asks()[0].0, price(101.0));
         assert_eq!(order_book.get_asks()[0].1, 5);
     }
 
@@ -194,10 +329,112 @@ mod tests {
         assert_eq!(order_book.seq_no, 3);
         assert_eq!(order_book.timestamp, 2);
         assert_eq!(order_book.get_bids().len(), 1);
-        assert_eq!(order_book.get_bids()[0].0, 100.0);
+        assert_eq!(order_book.get_bids()[0].0, price(100.0));
         assert_eq!(order_book.get_bids()[0].1, 10);
         assert_eq!(order_book.get_asks().len(), 1);
-        assert_eq!(order_book.get_asks()[0].0, 101.0);
+        assert_eq!(order_book.get_asks()[0].0, price(101.0));
         assert_eq!(order_book.get_asks()[0].1, 5);
     }
+
+    #[test]
+    fn test_read_incremental_prices_are_rounded_to_ticks() {
+        let mut order_books = init_orderbooks();
+
+        // prices off by the rounding error of f64, i.e. 1 ulp
+        let buf = write_update(
+            3,
+            2,
+            2,
+            &[
+                (0, f64::from_bits(100f64.to_bits() + 1), 15),
+                (1, f64::from_bits(101f64.to_bits() - 1), 0),
+            ],
+        );
+
+        read(&buf, &mut order_books).unwrap();
+        let order_book = order_books.get(&3).unwrap();
+        assert_eq!(order_book.get_bids(), vec![(price(100.0), 15)]);
+        assert_eq!(order_book.get_asks().len(), 0);
+    }
+
+    #[test]
+    fn test_read_incremental_invalid_fields() {
+        let read_error = |buf: &[u8]| read(buf, &mut init_orderbooks()).unwrap_err();
+
+        // the first level starts at offset 32, i.e. its side, and its price is at offset 33
+        let mut buf = write_update(3, 2, 2, &[(0, 100f64, 15)]);
+        buf[32] = 2;
+        assert!(matches!(read_error(&buf), Error::InvalidSide(2, 32)));
+
+        for price in [0.0, -100.0, f64::NAN, f64::INFINITY] {
+            let buf = write_update(3, 2, 2, &[(0, 100f64, 15), (1, price, 5)]);
+            assert!(matches!(read_error(&buf), Error::InvalidPrice(_, 50)));
+        }
+
+        let buf = write_update(3, 2, 2, &[(0, PRICE_OFF_GRID, 15)]);
+        assert!(matches!(read_error(&buf), Error::OffTickPrice(p, 33) if p == PRICE_OFF_GRID));
+
+        let mut buf = write_update(3, 2, 2, &[(0, 100f64, 15)]);
+        let num_updates = crate::ser::MAX_NUM_UPDATES + 1;
+        buf[24..32].copy_from_slice(&num_updates.to_le_bytes());
+        assert!(matches!(
+            read_error(&buf),
+            Error::InvalidNumUpdates(n, 24) if n == num_updates
+        ));
+
+        // removing an existing level is valid, removing it again is not
+        let buf = write_update(3, 2, 2, &[(1, 101f64, 0), (1, 101f64, 0)]);
+        assert!(matches!(
+            read_error(&buf),
+            Error::UnknownLevel(crate::orderbook::Side::Ask, p, 49) if p == 101.0
+        ));
+    }
+
+    #[test]
+    fn test_read_incremental_unknown_level() {
+        let mut order_books = init_orderbooks();
+        order_books.get_mut(&3).unwrap().cross_policy = CrossPolicy::Drop;
+
+        // with the drop policy, removing an existing level is valid, removing it again is skipped
+        // and counted
+        let buf = write_update(3, 2, 2, &[(1, 101f64, 0), (1, 101f64, 0), (0, 99f64, 15)]);
+        read(&buf, &mut order_books).unwrap();
+        let order_book = order_books.get(&3).unwrap();
+        assert_eq!(order_book.seq_no, 2);
+        assert_eq!(order_book.unknown_deletes, 1);
+        assert_eq!(
+            order_book.get_bids(),
+            vec![(price(100.0), 10), (price(99.0), 15)]
+        );
+        assert_eq!(order_book.get_asks().len(), 0);
+    }
+
+    #[test]
+    fn test_read_incremental_invalid_message_is_not_applied() {
+        let mut order_books = init_orderbooks();
+        let assert_unchanged = |order_books: &HashMap<u64, OrderBook>| {
+            let order_book = order_books.get(&3).unwrap();
+            assert_eq!(order_book.seq_no, 1);
+            assert_eq!(order_book.timestamp, 1);
+            assert_eq!(order_book.get_bids(), vec![(price(100.0), 10)]);
+            assert_eq!(order_book.get_asks(), vec![(price(101.0), 5)]);
+        };
+
+        // the last level is invalid, the levels before it are not applied either
+        let levels = [(0, 99f64, 15), (1, 101f64, 0), (0, PRICE_OFF_GRID, 1)];
+        let buf = write_update(3, 2, 2, &levels);
+        assert!(matches!(
+            read(&buf, &mut order_books),
+            Err(Error::OffTickPrice(_, 67))
+        ));
+        assert_unchanged(&order_books);
+
+        let levels = [(0, 99f64, 15), (1, 101f64, 0), (1, 101f64, 0)];
+        let buf = write_update(3, 2, 2, &levels);
+        assert!(matches!(
+            read(&buf, &mut order_books),
+            Err(Error::UnknownLevel(_, _, 66))
+        ));
+        assert_unchanged(&order_books);
+    }
 }
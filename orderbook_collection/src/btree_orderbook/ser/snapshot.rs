@@ -1,14 +1,17 @@
-use std::mem;
+use std::{collections::HashMap, mem};
 
+use anyhow::bail;
 use tracing::{debug, trace};
 
 use crate::{
     btree_orderbook::orderbook::OrderBook,
     btree_orderbook::ser::common::{read_f64, read_u64},
+    config::OrderBookConfig,
+    orderbook::Side,
+    price::TickSize,
+    ser::{validate, Error},
 };
 
-pub const SNAPSHOT_RECORD_SIZE: usize = 24 + 5 * (16 + 16); // 24 bytes for metadata, 5 pairs of (price, volume)
-
 ///
 /// Reads the snapshot data from the buffer into the order book.
 /// The buffer is expected to contain the following structure:
@@ -21,14 +24,18 @@ pub const SNAPSHOT_RECORD_SIZE: usize = 24 + 5 * (16 + 16); // 24 bytes for meta
 ///   - bid1 volume
 ///   - ask1 price
 ///   - ask1 volume
-///   ...
+///   - ...
 ///   - bid5 price
 ///   - bid5 volume
 ///   - ask5 price
 ///   - ask5 volume
-pub fn read(buf: &[u8]) -> anyhow::Result<OrderBook> {
+///
+/// Prices are converted to ticks using the tick size from the instrument's config, or the default
+/// tick size if the instrument is not configured. Levels with zero volume are empty and skipped,
+/// the prices of the other levels are validated, see `crate::ser::validate`.
+pub fn read(buf: &[u8], configs: &HashMap<u64, OrderBookConfig>) -> anyhow::Result<OrderBook> {
     let mut orderbook = OrderBook::default();
-    
+
     let mut offset = 0;
 
     // reading metadata
@@ -38,6 +45,11 @@ pub fn read(buf: &[u8]) -> anyhow::Result<OrderBook> {
     offset += mem::size_of::<u64>();
     orderbook.id = read_u64(&mut &buf[offset..])?;
     offset += mem::size_of::<u64>();
+    if let Some(config) = configs.get(&orderbook.id) {
+        orderbook.tick_size = TickSize::new(config.tick_size);
+        orderbook.cross_policy = config.cross_policy;
+    }
+    let tick_size = orderbook.tick_size;
     debug!(
         "Reading snapshot for order book ID: {}, timestamp: {}, seq_no: {}",
         orderbook.id, orderbook.timestamp, orderbook.seq_no
@@ -45,18 +57,102 @@ pub fn read(buf: &[u8]) -> anyhow::Result<OrderBook> {
     // reading bids and asks
     for _ in 0..5 {
         let price = read_f64(&mut &buf[offset..])?;
-        offset += mem::size_of::<f64>();
-        let qty = read_u64(&mut &buf[offset..])?;
-        offset += mem::size_of::<u64>();
+        let qty = read_u64(&mut &buf[offset + mem::size_of::<f64>()..])?;
         trace!("Add bid: price = {}, volume = {}", price, qty);
-        orderbook.add_bid(price, qty);
+        add_level(&mut orderbook, Side::Bid, price, qty, &tick_size, offset)?;
+        offset += mem::size_of::<f64>() + mem::size_of::<u64>();
+
+        let price = read_f64(&mut &buf[offset..])?;
+        let qty = read_u64(&mut &buf[offset + mem::size_of::<f64>()..])?;
+        trace!("Add ask: price = {}, volume = {}", price, qty);
+        add_level(&mut orderbook, Side::Ask, price, qty, &tick_size, offset)?;
+        offset += mem::size_of::<f64>() + mem::size_of::<u64>();
+    }
+
+    Ok(orderbook)
+}
+
+/// Adds the level of the snapshot record with the price at the offset to the order book.
+/// Empty levels (zero volume) are skipped, otherwise the price is validated, see
+/// `crate::ser::validate::price`.
+fn add_level(
+    orderbook: &mut OrderBook,
+    side: Side,
+    price: f64,
+    qty: u64,
+    tick_size: &TickSize,
+    offset: usize,
+) -> Result<(), Error> {
+    if qty == 0 {
+        return Ok(());
+    }
+    let price = validate::price(price, tick_size, offset)?;
+    match side {
+        Side::Bid => orderbook.add_bid(price, qty),
+        Side::Ask => orderbook.add_ask(price, qty),
+    }
+    Ok(())
+}
 
+///
+/// Reads the snapshot data in the depth format (see `crate::ser::SnapshotFormat::Depth`)
+/// from the buffer into the order book.
+/// The buffer is expected to contain the following structure:
+/// - 8 bytes for timestamp (u64)
+/// - 8 bytes for sequence number (u64)
+/// - 8 bytes for ID (u64)
+/// - 8 bytes for number of bids (u64)
+/// - 8 bytes for number of asks (u64)
+/// - pairs of 8 bytes for price (f64) and 8 bytes for volume (u64) for each bid and then
+///   for each ask
+///
+/// Prices are converted to ticks and validated the same as in `read`.
+pub fn read_depth(
+    buf: &[u8],
+    configs: &HashMap<u64, OrderBookConfig>,
+) -> anyhow::Result<OrderBook> {
+    let size = crate::ser::snapshot_record_size(buf, crate::ser::SnapshotFormat::Depth)?;
+    if buf.len() < size {
+        bail!(crate::ser::Error::BufferTooSmall);
+    }
+    let mut orderbook = OrderBook::default();
+
+    let mut offset = 0;
+
+    // reading metadata
+    orderbook.timestamp = read_u64(&mut &buf[offset..])?;
+    offset += mem::size_of::<u64>();
+    orderbook.seq_no = read_u64(&mut &buf[offset..])?;
+    offset += mem::size_of::<u64>();
+    orderbook.id = read_u64(&mut &buf[offset..])?;
+    offset += mem::size_of::<u64>();
+    let num_bids = read_u64(&mut &buf[offset..])?;
+    offset += mem::size_of::<u64>();
+    let num_asks = read_u64(&mut &buf[offset..])?;
+    offset += mem::size_of::<u64>();
+    if let Some(config) = configs.get(&orderbook.id) {
+        orderbook.tick_size = TickSize::new(config.tick_size);
+        orderbook.cross_policy = config.cross_policy;
+    }
+    let tick_size = orderbook.tick_size;
+    debug!(
+        "Reading snapshot for order book ID: {}, timestamp: {}, seq_no: {}, bids: {}, asks: {}",
+        orderbook.id, orderbook.timestamp, orderbook.seq_no, num_bids, num_asks
+    );
+    // reading bids and then asks
+    for _ in 0..num_bids {
         let price = read_f64(&mut &buf[offset..])?;
-        offset += mem::size_of::<f64>();
-        let qty = read_u64(&mut &buf[offset..])?;
-        offset += mem::size_of::<u64>();
+        let qty = read_u64(&mut &buf[offset + mem::size_of::<f64>()..])?;
+        trace!("Add bid: price = {}, volume = {}", price, qty);
+        add_level(&mut orderbook, Side::Bid, price, qty, &tick_size, offset)?;
+        offset += mem::size_of::<f64>() + mem::size_of::<u64>();
+    }
+    for _ in 0..num_asks {
+        let price = read_f64(&mut &buf[offset..])?;
+        let qty = read_u64(&mut &buf[offset + mem::size_of::<f64>()..])?;
         trace!("Add ask: price = {}, volume = {}", price, qty);
-        orderbook.add_ask(price, qty);
+        add_level(&mut orderbook, Side::Ask, price, qty, &tick_size, offset)?;
+        offset += mem::size_of::<f64>() + mem::size_of::<u64>();
     }
 
     Ok(orderbook)
@@ -103,32 +199,108 @@ mod tests {
         buf.extend_from_slice(&109f64.to_le_bytes()); // ask5 price
         buf.extend_from_slice(&45u64.to_le_bytes()); // ask5 volume
 
-        let orderbook = read(&buf).unwrap();
+        let orderbook = read(&buf, &HashMap::new()).unwrap();
+        let tick_size = TickSize::default();
         assert_eq!(orderbook.id, 3);
         assert_eq!(orderbook.seq_no, 2);
         assert_eq!(orderbook.timestamp, 1);
         assert_eq!(orderbook.get_bids().len(), 5);
         assert_eq!(orderbook.get_asks().len(), 5);
-        assert_eq!(orderbook.get_bids()[0].0, 108.0);
+        assert_eq!(orderbook.get_bids()[0].0, tick_size.to_price(108.0));
         assert_eq!(orderbook.get_bids()[0].1, 50);
-        assert_eq!(orderbook.get_bids()[1].0, 106.0);
+        assert_eq!(orderbook.get_bids()[1].0, tick_size.to_price(106.0));
         assert_eq!(orderbook.get_bids()[1].1, 40);
-        assert_eq!(orderbook.get_bids()[2].0, 104.0);
+        assert_eq!(orderbook.get_bids()[2].0, tick_size.to_price(104.0));
         assert_eq!(orderbook.get_bids()[2].1, 30);
-        assert_eq!(orderbook.get_bids()[3].0, 102.0);
+        assert_eq!(orderbook.get_bids()[3].0, tick_size.to_price(102.0));
         assert_eq!(orderbook.get_bids()[3].1, 20);
-        assert_eq!(orderbook.get_bids()[4].0, 100.0);
+        assert_eq!(orderbook.get_bids()[4].0, tick_size.to_price(100.0));
         assert_eq!(orderbook.get_bids()[4].1, 10);
 
-        assert_eq!(orderbook.get_asks()[0].0, 101.0);
+        assert_eq!(orderbook.get_asks()[0].0, tick_size.to_price(101.0));
         assert_eq!(orderbook.get_asks()[0].1, 5);
-        assert_eq!(orderbook.get_asks()[1].0, 103.0);
+        assert_eq!(orderbook.get_asks()[1].0, tick_size.to_price(103.0));
         assert_eq!(orderbook.get_asks()[1].1, 15);
-        assert_eq!(orderbook.get_asks()[2].0, 105.0);
+        assert_eq!(orderbook.get_asks()[2].0, tick_size.to_price(105.0));
         assert_eq!(orderbook.get_asks()[2].1, 25);
-        assert_eq!(orderbook.get_asks()[3].0, 107.0);
+        assert_eq!(orderbook.get_asks()[3].0, tick_size.to_price(107.0));
         assert_eq!(orderbook.get_asks()[3].1, 35);
-        assert_eq!(orderbook.get_asks()[4].0, 109.0);
+        assert_eq!(orderbook.get_asks()[4].0, tick_size.to_price(109.0));
         assert_eq!(orderbook.get_asks()[4].1, 45);
     }
+
+    #[test]
+    fn test_read_snapshot_depth() {
+        let bids: Vec<(f64, u64)> = (0..20).map(|i| (100.0 - i as f64 * 0.5, i + 1)).collect();
+        let asks = vec![(100.5, 7), (101.0, 8)];
+        let mut buf: Vec<u8> = vec![];
+        buf.extend_from_slice(&1u64.to_le_bytes()); // timestamp
+        buf.extend_from_slice(&2u64.to_le_bytes()); // seq_no
+        buf.extend_from_slice(&3u64.to_le_bytes()); // id
+        buf.extend_from_slice(&(bids.len() as u64).to_le_bytes()); // number of bids
+        buf.extend_from_slice(&(asks.len() as u64).to_le_bytes()); // number of asks
+        for (price, qty) in bids.iter().chain(asks.iter()) {
+            buf.extend_from_slice(&price.to_le_bytes());
+            buf.extend_from_slice(&qty.to_le_bytes());
+        }
+
+        let orderbook = read_depth(&buf, &HashMap::new()).unwrap();
+        let tick_size = TickSize::default();
+        assert_eq!(orderbook.id, 3);
+        assert_eq!(orderbook.seq_no, 2);
+        assert_eq!(orderbook.timestamp, 1);
+        let to_prices = |levels: &[(f64, u64)]| -> Vec<(crate::price::Price, u64)> {
+            levels
+                .iter()
+                .map(|(price, qty)| (tick_size.to_price(*price), *qty))
+                .collect()
+        };
+        assert_eq!(orderbook.get_bids(), to_prices(&bids));
+        assert_eq!(orderbook.get_asks(), to_prices(&asks));
+
+        // truncated record
+        assert!(read_depth(&buf[..buf.len() - 1], &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_read_snapshot_invalid_price() {
+        let write_snapshot_depth = |bids: &[(f64, u64)], asks: &[(f64, u64)]| -> Vec<u8> {
+            let mut buf: Vec<u8> = vec![];
+            buf.extend_from_slice(&1u64.to_le_bytes()); // timestamp
+            buf.extend_from_slice(&2u64.to_le_bytes()); // seq_no
+            buf.extend_from_slice(&3u64.to_le_bytes()); // id
+            buf.extend_from_slice(&(bids.len() as u64).to_le_bytes()); // number of bids
+            buf.extend_from_slice(&(asks.len() as u64).to_le_bytes()); // number of asks
+            for (price, qty) in bids.iter().chain(asks.iter()) {
+                buf.extend_from_slice(&price.to_le_bytes());
+                buf.extend_from_slice(&qty.to_le_bytes());
+            }
+            buf
+        };
+        let error = |result: anyhow::Result<OrderBook>| -> Error {
+            result.unwrap_err().downcast::<Error>().unwrap()
+        };
+
+        // empty levels are skipped whatever their price
+        let buf = write_snapshot_depth(&[(100.0, 1), (0.0, 0)], &[(f64::NAN, 0)]);
+        let orderbook = read_depth(&buf, &HashMap::new()).unwrap();
+        assert_eq!(orderbook.get_bids().len(), 1);
+        assert_eq!(orderbook.get_asks().len(), 0);
+
+        // the second bid is at offset 56
+        let buf = write_snapshot_depth(&[(100.0, 1), (0.0, 5)], &[]);
+        let result = read_depth(&buf, &HashMap::new());
+        assert!(matches!(error(result), Error::InvalidPrice(p, 56) if p == 0.0));
+
+        let buf = write_snapshot_depth(&[], &[(100.000000005, 1)]);
+        let result = read_depth(&buf, &HashMap::new());
+        assert!(matches!(error(result), Error::OffTickPrice(_, 40)));
+
+        // the first ask of the legacy format is at offset 40
+        let mut buf = vec![0; crate::ser::SNAPSHOT_RECORD_SIZE];
+        buf[40..48].copy_from_slice(&f64::INFINITY.to_le_bytes());
+        buf[48..56].copy_from_slice(&1u64.to_le_bytes());
+        let result = read(&buf, &HashMap::new());
+        assert!(matches!(error(result), Error::InvalidPrice(_, 40)));
+    }
 }
@@ -0,0 +1,216 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::OrderBookConfig,
+    orderbook::{BookHealth, CrossCounts, OrderBook},
+    price::Price,
+};
+
+/// Complete state of a replay (see `replay::Replay::checkpoint`), from which a later replay can
+/// be restored and continue reading the snapshot and incremental files from the saved offsets,
+/// instead of reprocessing them from the start.
+/// Checkpoints are stored as JSON, prices are in ticks, so the levels are restored exactly.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Byte offset of the next snapshot record in the snapshot file.
+    pub snapshot_offset: u64,
+    /// Byte offset of the next incremental message in the incremental file.
+    pub incremental_offset: u64,
+    /// Order books ordered by ID.
+    pub order_books: Vec<BookState>,
+    /// Incremental messages buffered for the stale order books and the order books without
+    /// a snapshot yet (see `ser::recovery::GapRecovery`), by order book ID.
+    pub buffered: Vec<(u64, Vec<u8>)>,
+}
+
+/// State of a single order book in a checkpoint.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BookState {
+    pub id: u64,
+    pub seq_no: u64,
+    pub timestamp: u64,
+    pub health: BookHealth,
+    pub tick_size: f64,
+    /// Config of the array based order book, with the current (possibly re-centered) bounds.
+    pub config: Option<OrderBookConfig>,
+    pub bids: Vec<(Price, u64)>,
+    pub asks: Vec<(Price, u64)>,
+    /// Missing in the checkpoints written before the counts were added.
+    #[serde(default)]
+    pub cross_counts: CrossCounts,
+    /// See `OrderBook::unknown_deletes`, missing in the checkpoints written before it was added.
+    #[serde(default)]
+    pub unknown_deletes: u64,
+}
+
+impl BookState {
+    pub fn from_order_book<B: OrderBook>(order_book: &B, config: Option<OrderBookConfig>) -> Self {
+        Self {
+            id: order_book.id(),
+            seq_no: order_book.seq_no(),
+            timestamp: order_book.timestamp(),
+            health: order_book.health(),
+            tick_size: order_book.tick_size().as_f64(),
+            config,
+            bids: order_book.get_bids(),
+            asks: order_book.get_asks(),
+            cross_counts: order_book.cross_counts(),
+            unknown_deletes: order_book.unknown_deletes(),
+        }
+    }
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint to the file. The checkpoint is written to a temporary file first,
+    /// which is then renamed, so an existing checkpoint is never left partially written.
+    pub fn write(&self, checkpoint_file: PathBuf) -> anyhow::Result<()> {
+        info!(
+            "Writing checkpoint file: {:?}, snapshot offset: {}, incremental offset: {}",
+            checkpoint_file, self.snapshot_offset, self.incremental_offset
+        );
+        let tmp_file = checkpoint_file.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_file)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(tmp_file, checkpoint_file)?;
+        Ok(())
+    }
+
+    pub fn read(checkpoint_file: PathBuf) -> anyhow::Result<Self> {
+        info!("Reading checkpoint file: {:?}", checkpoint_file);
+        let reader = BufReader::new(File::open(checkpoint_file)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::Config,
+        orderbook::{CrossPolicy, OrderBookReader},
+        price::TickSize,
+        ser::{recovery, InputMode, SnapshotFormat},
+    };
+
+    fn config(instrument: OrderBookConfig) -> Config {
+        Config {
+            instruments: HashMap::from([(instrument.id, instrument)]),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    #[test]
+    fn test_restore_recentered_array_order_book() {
+        let instrument = OrderBookConfig {
+            id: 1,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: true,
+            cross_policy: CrossPolicy::Warn,
+        };
+        let mut order_book = Box::new(array_orderbook::orderbook::OrderBook::new(instrument));
+        order_book.init();
+        order_book.seq_no = 5;
+        order_book.timestamp = 50;
+        order_book.cross_counts = CrossCounts {
+            crossed: 1,
+            locked: 2,
+            dropped_levels: 3,
+        };
+        order_book.unknown_deletes = 4;
+        let tick_size = order_book.tick_size();
+        order_book.add_bid(tick_size.to_price(100.0), 1).unwrap();
+        order_book.add_ask(tick_size.to_price(120.0), 2).unwrap();
+        assert_eq!(order_book.recenter_count(), 1);
+
+        let state = order_book.checkpoint();
+        let restored =
+            <Box<array_orderbook::orderbook::OrderBook>>::restore(&state, &config(instrument))
+                .unwrap();
+        assert_eq!(restored.config(), order_book.config());
+        assert_eq!(restored.checkpoint(), state);
+
+        // the restored order book has the re-centered bounds, no re-centering on restore
+        assert_eq!(restored.recenter_count(), 0);
+
+        // the cross policy is the one of the current config
+        let restored = <Box<array_orderbook::orderbook::OrderBook>>::restore(
+            &state,
+            &config(OrderBookConfig {
+                cross_policy: CrossPolicy::Drop,
+                ..instrument
+            }),
+        )
+        .unwrap();
+        assert_eq!(restored.config().cross_policy, CrossPolicy::Drop);
+        assert_eq!(restored.config().min_price, order_book.config().min_price);
+        assert_eq!(restored.cross_counts, order_book.cross_counts);
+        assert_eq!(restored.unknown_deletes, 4);
+
+        assert!(<Box<array_orderbook::orderbook::OrderBook>>::restore(
+            &BookState {
+                config: None,
+                ..state
+            },
+            &config(instrument)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_restore_btree_order_book() {
+        let tick_size = TickSize::new(0.05);
+        let mut order_book = btree_orderbook::orderbook::OrderBook::with_tick_size(2, tick_size);
+        order_book.seq_no = 7;
+        order_book.timestamp = 70;
+        order_book.health = BookHealth::Stale;
+        order_book.cross_counts = CrossCounts {
+            crossed: 4,
+            locked: 5,
+            dropped_levels: 6,
+        };
+        order_book.unknown_deletes = 7;
+        order_book.add_bid(tick_size.to_price(99.95), 3);
+        order_book.add_ask(tick_size.to_price(100.05), 4);
+
+        let state = order_book.checkpoint();
+        assert_eq!(state.config, None);
+        let config = config(OrderBookConfig {
+            id: 2,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.05,
+            recenter: false,
+            cross_policy: CrossPolicy::Drop,
+        });
+        let restored = btree_orderbook::orderbook::OrderBook::restore(&state, &config).unwrap();
+        assert_eq!(restored.tick_size, tick_size);
+        assert_eq!(restored.cross_policy, CrossPolicy::Drop);
+        assert_eq!(restored.checkpoint(), state);
+    }
+
+    #[test]
+    fn test_read_book_state_without_counts() {
+        let json = r#"{"id":1,"seq_no":2,"timestamp":3,"health":"live","tick_size":0.01,
+            "config":null,"bids":[],"asks":[]}"#;
+        let state: BookState = serde_json::from_str(json).unwrap();
+        assert_eq!(state.cross_counts, CrossCounts::default());
+        assert_eq!(state.unknown_deletes, 0);
+    }
+}
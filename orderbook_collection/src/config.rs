@@ -1,16 +1,40 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::{
+    orderbook::CrossPolicy,
+    ser::{recovery, InputMode, SnapshotFormat},
+};
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub instruments: HashMap<u64, OrderBookConfig>,
     pub incremental_buffer_size: usize,
+    #[serde(default)]
+    pub snapshot_format: SnapshotFormat,
+    #[serde(default)]
+    pub input: InputMode,
+    /// Maximum size in bytes of the incremental messages buffered per stale order book until it's
+    /// re-synced from a snapshot, beyond which the buffered messages are dropped.
+    #[serde(default = "default_recovery_buffer_limit")]
+    pub recovery_buffer_limit: usize,
+}
+
+fn default_recovery_buffer_limit() -> usize {
+    recovery::DEFAULT_BUFFER_LIMIT
 }
 
-#[derive(Debug, Clone, Copy, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct OrderBookConfig {
     pub id: u64,
     pub min_price: f64,
     pub max_price: f64,
     pub tick_size: f64,
+    /// If enabled, the array based order book is rebuilt with shifted or widened price bounds
+    /// when a price outside of [min_price, max_price] is received, otherwise such price is rejected.
+    #[serde(default)]
+    pub recenter: bool,
+    /// What is done when the order book is crossed or locked after an incremental message.
+    #[serde(default)]
+    pub cross_policy: CrossPolicy,
 }
@@ -1,41 +1,117 @@
-use std::path::PathBuf;
-
-use tracing::debug;
+use std::{collections::HashMap, path::PathBuf, time::Duration};
 
 pub mod array_orderbook;
 pub mod btree_orderbook;
+pub mod checkpoint;
 pub mod config;
+pub mod observer;
+pub mod orderbook;
+pub mod output;
+pub mod price;
+pub mod replay;
+pub mod sampler;
 pub mod ser;
+pub mod shard;
+pub mod top;
+pub mod verify;
 pub mod logger;
 
+/// Replays the snapshot and incremental files merged by time (see `replay::Replay`) into
+/// the order books of the given implementation, e.g.
+/// `run::<btree_orderbook::orderbook::OrderBook>(...)`.
+/// The files are memory mapped if configured, see `ser::InputMode`.
+pub fn run<B: orderbook::OrderBookReader>(
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: config::Config,
+) -> Result<HashMap<u64, B>, anyhow::Error> {
+    match config.input {
+        ser::InputMode::Buffered => {
+            replay::Replay::<B>::open(snapshot_file, incremental_file, config)?.run()
+        }
+        ser::InputMode::Mmap => {
+            replay::Replay::<B, _, _>::open_mmap(snapshot_file, incremental_file, config)?.run()
+        }
+    }
+}
+
+/// Same as `run`, but follows the growing incremental file like `tail -f`, see
+/// `replay::Replay::follow`. Returns the order books once no data is appended for the idle
+/// timeout, if set.
+pub fn run_follow<B: orderbook::OrderBookReader>(
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: config::Config,
+    idle_timeout: Option<Duration>,
+) -> Result<HashMap<u64, B>, anyhow::Error> {
+    replay::Replay::<B, _, _>::follow(snapshot_file, incremental_file, config, idle_timeout)?.run()
+}
+
+/// Replays the snapshot and incremental files only up to the given point in time and returns
+/// the order book with the given ID as of that point, see `replay::Replay::run_until`.
+pub fn run_until<B: orderbook::OrderBookReader>(
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: config::Config,
+    id: u64,
+    as_of: replay::AsOf,
+) -> Result<B, anyhow::Error> {
+    match config.input {
+        ser::InputMode::Buffered => {
+            replay::Replay::<B>::open(snapshot_file, incremental_file, config)?.run_until(id, as_of)
+        }
+        ser::InputMode::Mmap => {
+            replay::Replay::<B, _, _>::open_mmap(snapshot_file, incremental_file, config)?
+                .run_until(id, as_of)
+        }
+    }
+}
+
+/// Replays the snapshot and incremental files into both the BTreeMap based and the array based
+/// order books side by side and checks, that they are identical after each event, see
+/// `verify::Verifier`. Returns the number of verified events.
+pub fn run_verify(
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: config::Config,
+) -> Result<u64, anyhow::Error> {
+    verify::Verifier::<
+        btree_orderbook::orderbook::OrderBook,
+        Box<array_orderbook::orderbook::OrderBook>,
+    >::open(snapshot_file, incremental_file, config)?
+    .run()
+}
+
+/// Same as `run`, but the instruments are partitioned across the given number of worker threads,
+/// see `shard::ShardedReplay`.
+pub fn run_sharded<B: orderbook::OrderBookReader + Send>(
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: config::Config,
+    shards: usize,
+) -> Result<HashMap<u64, B>, anyhow::Error> {
+    match config.input {
+        ser::InputMode::Buffered => {
+            shard::ShardedReplay::open(snapshot_file, incremental_file, config, shards)?.run()
+        }
+        ser::InputMode::Mmap => {
+            shard::ShardedReplay::open_mmap(snapshot_file, incremental_file, config, shards)?.run()
+        }
+    }
+}
+
 pub fn run_btree(
     snapshot_file: PathBuf,
     incremental_file: PathBuf,
     config: config::Config,
-) -> Result<std::collections::HashMap<u64, btree_orderbook::orderbook::OrderBook>, anyhow::Error> {
-    let mut order_books = btree_orderbook::ser::read_snapshot_file(snapshot_file)?;
-    debug!("Read {} order books from snapshot file", order_books.len());
-    btree_orderbook::ser::read_incremental_file(incremental_file, &mut order_books, config.incremental_buffer_size)?;
-    debug!(
-        "Processed incremental updates, total order books: {}",
-        order_books.len()
-    );
-    Ok(order_books)
+) -> Result<HashMap<u64, btree_orderbook::orderbook::OrderBook>, anyhow::Error> {
+    run(snapshot_file, incremental_file, config)
 }
 
 pub fn run_array(
     snapshot_file: PathBuf,
     incremental_file: PathBuf,
     config: config::Config,
-) -> Result<std::collections::HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>, anyhow::Error>
-{
-    let mut order_books =
-        array_orderbook::ser::read_snapshot_file(snapshot_file, config.instruments)?;
-    debug!("Read {} order books from snapshot file", order_books.len());
-    array_orderbook::ser::read_incremental_file(incremental_file, &mut order_books, config.incremental_buffer_size)?;
-    debug!(
-        "Processed incremental updates, total order books: {}",
-        order_books.len()
-    );
-    Ok(order_books)
-}
\ No newline at end of file
+) -> Result<HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>, anyhow::Error> {
+    run(snapshot_file, incremental_file, config)
+}
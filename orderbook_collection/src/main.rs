@@ -1,6 +1,21 @@
-use orderbook_collection_lib::logger;
+use anyhow::bail;
+use orderbook_collection_lib::{
+    checkpoint::Checkpoint,
+    logger,
+    orderbook::OrderBookReader,
+    output::{write_order_books, write_output_file, OutputFormat},
+    replay::{AsOf, Replay},
+    sampler::{SampleTrigger, Sampler},
+    ser::InputMode,
+};
 use serde::Deserialize;
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    io::{self, Read},
+    path::PathBuf,
+    time::Duration,
+};
 use structopt::StructOpt;
 use tracing::info;
 
@@ -21,18 +36,87 @@ struct Opt {
     config: Option<String>,
     #[structopt(short = "a", long = "use_array")]
     use_array: bool,
+    /// Follow the incremental file as it grows, like `tail -f`.
+    #[structopt(short = "f", long = "follow")]
+    follow: bool,
+    /// Stop following after no data is appended for the given number of milliseconds.
+    #[structopt(long = "idle_timeout")]
+    idle_timeout: Option<u64>,
+    /// Write the final order books (or the order book of as_of) to the given file.
+    #[structopt(short = "o", long = "output", parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Format of the output file: json or csv.
+    #[structopt(long = "format", default_value = "json")]
+    format: OutputFormat,
+    /// Sample the top levels of the order books during the run to the given CSV file.
+    #[structopt(long = "sample_output", parse(from_os_str))]
+    sample_output: Option<PathBuf>,
+    /// Sample at every multiple of the interval, in units of the timestamps of the input.
+    #[structopt(long = "sample_interval", conflicts_with = "sample_updates")]
+    sample_interval: Option<u64>,
+    /// Sample after every given number of incremental messages.
+    #[structopt(long = "sample_updates")]
+    sample_updates: Option<u64>,
+    /// Number of levels per side in each sample.
+    #[structopt(long = "sample_depth", default_value = "5")]
+    sample_depth: usize,
+    /// Write a checkpoint of the replay to the given file at the end of the run.
+    #[structopt(long = "checkpoint", parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+    /// Restore the replay from the given checkpoint file and continue reading the input files
+    /// from the saved offsets.
+    #[structopt(long = "restore", parse(from_os_str))]
+    restore: Option<PathBuf>,
+    /// Replay both order book implementations side by side and check, that they are identical
+    /// after each snapshot record and incremental message.
+    #[structopt(
+        long = "verify",
+        conflicts_with_all = &["use_array", "follow", "output", "sample_output", "checkpoint", "restore"]
+    )]
+    verify: bool,
+    /// Partition the instruments across the given number of worker threads.
+    #[structopt(
+        long = "shards",
+        conflicts_with_all = &["follow", "sample_output", "checkpoint", "restore", "verify"]
+    )]
+    shards: Option<usize>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Replay only up to the given timestamp or sequence number and print the order book
+    /// (or write it to the output file).
+    #[structopt(name = "as_of")]
+    AsOf {
+        /// ID of the order book.
+        #[structopt(long = "id")]
+        id: u64,
+        /// Timestamp to replay up to, inclusive, in units of the timestamps of the input.
+        #[structopt(long = "timestamp", conflicts_with = "seq_no")]
+        timestamp: Option<u64>,
+        /// Sequence number of the order book to replay up to, inclusive.
+        #[structopt(long = "seq_no")]
+        seq_no: Option<u64>,
+    },
 }
 
 pub fn main() -> anyhow::Result<()> {
     let opt = Opt::from_args();
     let snapshot_file = opt
         .snapshot
+        .clone()
         .unwrap_or_else(|| PathBuf::from("orderbook_collection/resources/snapshot.bin"));
     let incremental_file = opt
         .incremental
+        .clone()
         .unwrap_or_else(|| PathBuf::from("orderbook_collection/resources/incremental.bin"));
     let use_array = opt.use_array;
-    let config_file = opt.config.unwrap_or_else(|| "orderbook_collection/config/test.yaml".into());
+    let config_file = opt
+        .config
+        .clone()
+        .unwrap_or_else(|| "orderbook_collection/config/test.yaml".into());
 
     let config: orderbook_collection_lib::config::Config =
         load_config(&config_file).unwrap_or_else(|_| {
@@ -40,16 +124,147 @@ pub fn main() -> anyhow::Result<()> {
             default_config()
         });
     info!("Config: {:?}", config);
-    if use_array {
+    if opt.verify {
+        if opt.command.is_some() {
+            bail!("as_of can't be used with verify");
+        }
+        let events = orderbook_collection_lib::run_verify(snapshot_file, incremental_file, config)?;
+        info!("Verified events: {}", events);
+    } else if let Some(shards) = opt.shards {
+        if opt.command.is_some() {
+            bail!("as_of can't be used with shards");
+        }
+        if use_array {
+            info!("Using array orderbook");
+            let order_books = orderbook_collection_lib::run_sharded::<
+                Box<orderbook_collection_lib::array_orderbook::orderbook::OrderBook>,
+            >(snapshot_file, incremental_file, config, shards)?;
+            finish_sharded(&opt, order_books)?;
+        } else {
+            info!("Using btree orderbook");
+            let order_books = orderbook_collection_lib::run_sharded::<
+                orderbook_collection_lib::btree_orderbook::orderbook::OrderBook,
+            >(snapshot_file, incremental_file, config, shards)?;
+            finish_sharded(&opt, order_books)?;
+        }
+    } else if use_array {
         info!("Using array orderbook");
-        let order_books =
-            orderbook_collection_lib::run_array(snapshot_file, incremental_file, config)?;
-        info!("Order books: {:?}", order_books);
+        run::<Box<orderbook_collection_lib::array_orderbook::orderbook::OrderBook>>(
+            &opt,
+            snapshot_file,
+            incremental_file,
+            config,
+        )?;
     } else {
         info!("Using btree orderbook");
-        let order_books =
-            orderbook_collection_lib::run_btree(snapshot_file, incremental_file, config)?;
-        info!("Order books: {:?}", order_books);
+        run::<orderbook_collection_lib::btree_orderbook::orderbook::OrderBook>(
+            &opt,
+            snapshot_file,
+            incremental_file,
+            config,
+        )?;
+    }
+    Ok(())
+}
+
+fn run<B: OrderBookReader + Debug>(
+    opt: &Opt,
+    snapshot_file: PathBuf,
+    incremental_file: PathBuf,
+    config: orderbook_collection_lib::config::Config,
+) -> anyhow::Result<()> {
+    let sampler = match &opt.sample_output {
+        Some(sample_file) => {
+            let trigger = match (opt.sample_interval, opt.sample_updates) {
+                (Some(interval), None) => SampleTrigger::Interval(interval),
+                (None, Some(updates)) => SampleTrigger::Updates(updates),
+                _ => bail!("Either sample_interval or sample_updates is required for sampling"),
+            };
+            Some(Sampler::create(
+                sample_file.clone(),
+                trigger,
+                opt.sample_depth,
+            )?)
+        }
+        None => None,
+    };
+    let checkpoint = match &opt.restore {
+        Some(checkpoint_file) => Checkpoint::read(checkpoint_file.clone())?,
+        None => Checkpoint::default(),
+    };
+    if opt.follow {
+        if config.input == InputMode::Mmap {
+            info!("Following the incremental file with the buffered reader, it can't be mapped");
+        }
+        let idle_timeout = opt.idle_timeout.map(Duration::from_millis);
+        let replay = Replay::<B, _, _>::follow_restore(
+            snapshot_file,
+            incremental_file,
+            config,
+            idle_timeout,
+            checkpoint,
+        )?;
+        finish(opt, replay.with_sampler(sampler))
+    } else if config.input == InputMode::Mmap {
+        let replay =
+            Replay::<B, _, _>::restore_mmap(snapshot_file, incremental_file, config, checkpoint)?;
+        finish(opt, replay.with_sampler(sampler))
+    } else {
+        let replay = Replay::<B>::restore(snapshot_file, incremental_file, config, checkpoint)?;
+        finish(opt, replay.with_sampler(sampler))
+    }
+}
+
+fn finish<B: OrderBookReader + Debug, S: Read, I: Read>(
+    opt: &Opt,
+    mut replay: Replay<B, S, I>,
+) -> anyhow::Result<()> {
+    match opt.command {
+        Some(Command::AsOf {
+            id,
+            timestamp,
+            seq_no,
+        }) => {
+            if opt.checkpoint.is_some() {
+                bail!("Checkpoint can't be written with as_of");
+            }
+            let as_of = match (timestamp, seq_no) {
+                (Some(timestamp), None) => AsOf::Timestamp(timestamp),
+                (None, Some(seq_no)) => AsOf::SeqNo(seq_no),
+                _ => bail!("Either timestamp or seq_no is required"),
+            };
+            let order_book = replay.run_until(id, as_of)?;
+            info!("Order book as of {:?}: {:?}", as_of, order_book);
+            let order_books = HashMap::from([(id, order_book)]);
+            match &opt.output {
+                Some(output_file) => {
+                    write_output_file(output_file.clone(), &order_books, opt.format)?
+                }
+                None => write_order_books(io::stdout().lock(), &order_books, opt.format)?,
+            }
+        }
+        None => {
+            if let Some(checkpoint_file) = &opt.checkpoint {
+                while replay.step()? {}
+                replay.checkpoint().write(checkpoint_file.clone())?;
+            }
+            let order_books = replay.run()?;
+            info!("Order books: {:?}", order_books);
+            if let Some(output_file) = &opt.output {
+                write_output_file(output_file.clone(), &order_books, opt.format)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn finish_sharded<B: OrderBookReader + Debug>(
+    opt: &Opt,
+    order_books: HashMap<u64, B>,
+) -> anyhow::Result<()> {
+    info!("Order books: {:?}", order_books);
+    if let Some(output_file) = &opt.output {
+        write_output_file(output_file.clone(), &order_books, opt.format)?;
     }
     Ok(())
 }
@@ -65,5 +280,8 @@ pub fn default_config() -> orderbook_collection_lib::config::Config {
     orderbook_collection_lib::config::Config {
         instruments: std::collections::HashMap::new(),
         incremental_buffer_size: 2048,
+        snapshot_format: orderbook_collection_lib::ser::SnapshotFormat::Legacy,
+        input: InputMode::Buffered,
+        recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
     }
 }
@@ -0,0 +1,307 @@
+use crate::{
+    orderbook::{OrderBook, Side},
+    price::Price,
+};
+
+/// Best bid and best ask of an order book.
+pub type Bbo = (Option<(Price, u64)>, Option<(Price, u64)>);
+
+/// Handlers of the changes of the order books, invoked by the readers of both implementations
+/// (`incremental::read_observed` and `apply_snapshot_record_observed` of the implementation's
+/// `ser` module) while the snapshot records and incremental messages are applied, e.g. attached
+/// to a replay with `replay::Replay::with_observer`.
+///
+/// All handlers do nothing by default, so an observer implements only the ones it needs.
+/// For an incremental message, the levels are reported as they are applied, followed by the
+/// change of the best bid or ask, if any, and then by `on_book_updated`.
+pub trait Observer {
+    /// Whether the changes are tracked for the observer, the readers skip tracking them
+    /// otherwise, so reading without an observer (`()`) doesn't add any overhead.
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    /// An incremental message was applied to the order book.
+    fn on_book_updated(&mut self, _id: u64, _seq_no: u64, _timestamp: u64) {}
+
+    /// The best bid or the best ask (price or qty) of the order book changed after an
+    /// incremental message or a snapshot.
+    fn on_bbo_changed(&mut self, _id: u64, _bbo: Bbo) {}
+
+    /// A level, which didn't exist, was added by an incremental message.
+    fn on_level_added(&mut self, _id: u64, _side: Side, _price: Price, _qty: u64) {}
+
+    /// A level was removed by an incremental message.
+    fn on_level_removed(&mut self, _id: u64, _side: Side, _price: Price) {}
+
+    /// A gap in sequence numbers was detected, i.e. the message with `seq_no` was received
+    /// instead of `expected_seq_no`, and the order book is stale until it's re-synced.
+    fn on_gap_detected(&mut self, _id: u64, _expected_seq_no: u64, _seq_no: u64) {}
+
+    /// A snapshot was applied to the order book, the levels of the snapshot are not reported
+    /// one by one. Buffered messages replayed on top of the snapshot are reported afterwards.
+    fn on_snapshot_applied(&mut self, _id: u64, _seq_no: u64) {}
+}
+
+/// No observer.
+impl Observer for () {
+    #[inline(always)]
+    fn is_enabled(&self) -> bool {
+        false
+    }
+}
+
+pub(crate) fn bbo<B: OrderBook>(order_book: &B) -> Bbo {
+    (order_book.best_bid(), order_book.best_ask())
+}
+
+/// Reports the level change from the previous qty to the qty, if it added or removed the level.
+#[inline(always)]
+pub(crate) fn notify_level<O: Observer + ?Sized>(
+    observer: &mut O,
+    id: u64,
+    side: Side,
+    price: Price,
+    prev_qty: u64,
+    qty: u64,
+) {
+    if prev_qty == 0 && qty > 0 {
+        observer.on_level_added(id, side, price, qty);
+    } else if prev_qty > 0 && qty == 0 {
+        observer.on_level_removed(id, side, price);
+    }
+}
+
+/// Reports the change of the best bid or ask, if it changed from the previous one.
+#[inline(always)]
+pub(crate) fn notify_bbo<O: Observer + ?Sized, B: OrderBook>(
+    observer: &mut O,
+    id: u64,
+    prev_bbo: Bbo,
+    order_book: &B,
+) {
+    let bbo = bbo(order_book);
+    if bbo != prev_bbo {
+        observer.on_bbo_changed(id, bbo);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::{Config, OrderBookConfig},
+        orderbook::{CrossPolicy, OrderBookReader},
+        price::TickSize,
+        ser::{
+            recovery::GapRecovery,
+            writer::{
+                self, write_incremental_message, write_snapshot_record, IncrementalMessage,
+                LevelUpdate, SnapshotRecord,
+            },
+            InputMode, SnapshotFormat,
+        },
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        BookUpdated(u64),
+        BboChanged(Bbo),
+        LevelAdded(Side, Price, u64),
+        LevelRemoved(Side, Price),
+        GapDetected(u64, u64),
+        SnapshotApplied(u64),
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<Event>,
+    }
+
+    impl Observer for Recorder {
+        fn on_book_updated(&mut self, id: u64, seq_no: u64, _timestamp: u64) {
+            assert_eq!(id, 1);
+            self.events.push(Event::BookUpdated(seq_no));
+        }
+
+        fn on_bbo_changed(&mut self, _id: u64, bbo: Bbo) {
+            self.events.push(Event::BboChanged(bbo));
+        }
+
+        fn on_level_added(&mut self, _id: u64, side: Side, price: Price, qty: u64) {
+            self.events.push(Event::LevelAdded(side, price, qty));
+        }
+
+        fn on_level_removed(&mut self, _id: u64, side: Side, price: Price) {
+            self.events.push(Event::LevelRemoved(side, price));
+        }
+
+        fn on_gap_detected(&mut self, _id: u64, expected_seq_no: u64, seq_no: u64) {
+            self.events
+                .push(Event::GapDetected(expected_seq_no, seq_no));
+        }
+
+        fn on_snapshot_applied(&mut self, _id: u64, seq_no: u64) {
+            self.events.push(Event::SnapshotApplied(seq_no));
+        }
+    }
+
+    fn config() -> Config {
+        Config {
+            instruments: HashMap::from([(
+                1,
+                OrderBookConfig {
+                    id: 1,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                },
+            )]),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    fn price(price: f64) -> Price {
+        TickSize::new(0.01).to_price(price)
+    }
+
+    fn message(seq_no: u64, updates: &[(writer::Side, f64, u64)]) -> Vec<u8> {
+        let message = IncrementalMessage {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id: 1,
+            updates: updates
+                .iter()
+                .map(|(side, price, qty)| LevelUpdate {
+                    side: *side,
+                    price: *price,
+                    qty: *qty,
+                })
+                .collect(),
+        };
+        let mut buf = vec![];
+        write_incremental_message(&mut buf, &message).unwrap();
+        buf
+    }
+
+    fn test_observer<B: OrderBookReader>() {
+        let config = config();
+        let mut order_books = B::init_order_books(&config);
+        let mut recovery = GapRecovery::new();
+        let mut observer = Recorder::default();
+
+        let mut snapshot = vec![];
+        let record = SnapshotRecord {
+            timestamp: 1000,
+            seq_no: 1,
+            id: 1,
+            bids: vec![(100.0, 1)],
+            asks: vec![(101.0, 5)],
+        };
+        write_snapshot_record(&mut snapshot, &record, SnapshotFormat::Legacy).unwrap();
+        B::apply_snapshot_record_observed(
+            &snapshot,
+            &mut order_books,
+            &config,
+            &mut recovery,
+            &mut observer,
+        )
+        .unwrap();
+        let bbo = (Some((price(100.0), 1)), Some((price(101.0), 5)));
+        assert_eq!(
+            observer.events,
+            [Event::BboChanged(bbo), Event::SnapshotApplied(1)]
+        );
+
+        // a new best bid and a new level behind the best ask
+        observer.events.clear();
+        let buf = message(
+            2,
+            &[(writer::Side::Bid, 100.5, 2), (writer::Side::Ask, 102.0, 3)],
+        );
+        B::apply_incremental_message_observed(&buf, &mut order_books, &mut observer).unwrap();
+        assert_eq!(
+            observer.events,
+            [
+                Event::LevelAdded(Side::Bid, price(100.5), 2),
+                Event::LevelAdded(Side::Ask, price(102.0), 3),
+                Event::BboChanged((Some((price(100.5), 2)), Some((price(101.0), 5)))),
+                Event::BookUpdated(2),
+            ]
+        );
+
+        // a qty change of the best ask and a removed level
+        observer.events.clear();
+        let buf = message(
+            3,
+            &[(writer::Side::Ask, 101.0, 4), (writer::Side::Bid, 100.0, 0)],
+        );
+        B::apply_incremental_message_observed(&buf, &mut order_books, &mut observer).unwrap();
+        assert_eq!(
+            observer.events,
+            [
+                Event::LevelRemoved(Side::Bid, price(100.0)),
+                Event::BboChanged((Some((price(100.5), 2)), Some((price(101.0), 4)))),
+                Event::BookUpdated(3),
+            ]
+        );
+
+        // a gap is reported once, later messages of the stale order book are not reported
+        observer.events.clear();
+        for seq_no in [5, 6] {
+            let buf = message(seq_no, &[(writer::Side::Bid, 99.0, 1)]);
+            assert!(
+                B::apply_incremental_message_observed(&buf, &mut order_books, &mut observer)
+                    .is_err()
+            );
+            recovery.buffer(1, &buf);
+        }
+        assert_eq!(observer.events, [Event::GapDetected(4, 5)]);
+
+        // a later snapshot re-syncs the order book and the buffered messages are replayed
+        observer.events.clear();
+        let mut snapshot = vec![];
+        let record = SnapshotRecord {
+            seq_no: 4,
+            ..record
+        };
+        write_snapshot_record(&mut snapshot, &record, SnapshotFormat::Legacy).unwrap();
+        B::apply_snapshot_record_observed(
+            &snapshot,
+            &mut order_books,
+            &config,
+            &mut recovery,
+            &mut observer,
+        )
+        .unwrap();
+        assert_eq!(
+            observer.events,
+            [
+                Event::BboChanged(bbo),
+                Event::SnapshotApplied(4),
+                Event::LevelAdded(Side::Bid, price(99.0), 1),
+                Event::BookUpdated(5),
+                // the level is already added, so only the update is reported
+                Event::BookUpdated(6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_btree() {
+        test_observer::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_observer_array() {
+        test_observer::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+}
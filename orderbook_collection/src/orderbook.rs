@@ -0,0 +1,726 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checkpoint::BookState,
+    config,
+    observer::Observer,
+    price::{Price, TickSize},
+    ser::{self, recovery::GapRecovery},
+};
+
+/// Health of an order book, i.e. whether it's in sync with the incremental updates.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BookHealth {
+    /// All updates since the last snapshot were applied.
+    #[default]
+    Live,
+    /// A gap in sequence numbers was detected, the order book is not updated until it's re-synced
+    /// from a snapshot, see `ser::recovery::GapRecovery`.
+    Stale,
+    /// The order book was crossed or locked after an incremental message with the
+    /// `CrossPolicy::Invalidate` policy, its updates are skipped until the next snapshot.
+    Invalid,
+}
+
+/// What is done when an order book is crossed (best bid above best ask) or locked (best bid
+/// equal to best ask) after an incremental message. It's counted with any policy, see
+/// `CrossCounts`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossPolicy {
+    /// A warning is logged and the order book is kept as is.
+    #[default]
+    Warn,
+    /// The levels of the side updated by the message, which cross the best level of the other
+    /// side, are removed, see `drop_crossed_levels`.
+    Drop,
+    /// The order book is marked invalid (`BookHealth::Invalid`) until the next snapshot.
+    Invalidate,
+    /// The message fails with `ser::Error::CrossedBook`, which fails the run.
+    Fail,
+}
+
+/// Crossed or locked order book.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cross {
+    /// Best bid above best ask.
+    Crossed,
+    /// Best bid equal to best ask.
+    Locked,
+}
+
+/// Number of incremental messages, which left the order book crossed or locked, and of the
+/// levels removed by `CrossPolicy::Drop`. Kept across snapshots and in checkpoints.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrossCounts {
+    pub crossed: u64,
+    pub locked: u64,
+    pub dropped_levels: u64,
+}
+
+impl CrossCounts {
+    pub fn count(&mut self, cross: Cross) {
+        match cross {
+            Cross::Crossed => self.crossed += 1,
+            Cross::Locked => self.locked += 1,
+        }
+    }
+}
+
+/// Returns whether the order book is crossed or locked, None if either side is empty.
+#[inline(always)]
+pub fn cross<B: OrderBook>(order_book: &B) -> Option<Cross> {
+    let (bid, _) = order_book.best_bid()?;
+    let (ask, _) = order_book.best_ask()?;
+    match bid.cmp(&ask) {
+        std::cmp::Ordering::Less => None,
+        std::cmp::Ordering::Equal => Some(Cross::Locked),
+        std::cmp::Ordering::Greater => Some(Cross::Crossed),
+    }
+}
+
+/// Removes the crossed levels of the side, which caused the cross, i.e. the bids at or above the
+/// best ask, if the message added a bid at or above it, and then the asks at or below the best
+/// bid, if the message added an ask at or below it. `max_bid` and `min_ask` are the highest bid
+/// and the lowest ask added by the message. If neither crosses the other side, e.g. the order
+/// book was already crossed by its snapshot, no levels are removed.
+/// Returns the removed levels.
+pub fn drop_crossed_levels<B: OrderBook>(
+    order_book: &mut B,
+    max_bid: Option<Price>,
+    min_ask: Option<Price>,
+) -> anyhow::Result<Vec<(Side, Price)>> {
+    let mut dropped = vec![];
+    if let (Some(max_bid), Some((best_ask, _))) = (max_bid, order_book.best_ask()) {
+        if max_bid >= best_ask {
+            let crossed: Vec<Price> = order_book
+                .iter_bids()
+                .take_while(|(price, _)| *price >= best_ask)
+                .map(|(price, _)| price)
+                .collect();
+            for price in crossed {
+                order_book.add_bid(price, 0)?;
+                dropped.push((Side::Bid, price));
+            }
+        }
+    }
+    if let (Some(min_ask), Some((best_bid, _))) = (min_ask, order_book.best_bid()) {
+        if min_ask <= best_bid {
+            let crossed: Vec<Price> = order_book
+                .iter_asks()
+                .take_while(|(price, _)| *price <= best_bid)
+                .map(|(price, _)| price)
+                .collect();
+            for price in crossed {
+                order_book.add_ask(price, 0)?;
+                dropped.push((Side::Ask, price));
+            }
+        }
+    }
+    Ok(dropped)
+}
+
+/// Side of an order book, encoded as 0 for bid and 1 for ask in incremental messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Bid = 0,
+    Ask = 1,
+}
+
+/// Result of sweeping the levels of a side from the best one, i.e. of filling a market order
+/// against it, see `OrderBook::sweep`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sweep {
+    /// Filled qty, less than the requested qty if there's not enough qty on the side.
+    pub qty: u64,
+    /// Volume weighted average price of the fill (VWAP).
+    pub avg_price: f64,
+    /// Price of the last level reached by the sweep.
+    pub worst_price: Price,
+}
+
+impl Sweep {
+    /// Sweeps the levels, which are ordered from the best one, until the qty is filled.
+    /// None if the qty is 0 or there are no levels.
+    pub fn from_levels(
+        levels: impl Iterator<Item = (Price, u64)>,
+        qty: u64,
+        tick_size: TickSize,
+    ) -> Option<Self> {
+        let mut filled = 0;
+        // sum of price in ticks times qty, exact for any realistic prices and quantities
+        let mut notional: i128 = 0;
+        let mut worst_price = None;
+        for (price, level_qty) in levels {
+            if filled == qty {
+                break;
+            }
+            let fill = level_qty.min(qty - filled);
+            filled += fill;
+            notional += price.ticks() as i128 * fill as i128;
+            worst_price = Some(price);
+        }
+        let worst_price = worst_price?;
+        Some(Self {
+            qty: filled,
+            avg_price: tick_size.ticks_to_f64(notional as f64 / filled as f64),
+            worst_price,
+        })
+    }
+}
+
+/// Common interface of the order book implementations.
+/// Both the array based and the BTreeMap based order books implement this trait, which allows
+/// writing code once and running it against either implementation. The trait is meant to be used
+/// with generics (static dispatch), so it does not add any overhead compared to calling the
+/// implementations directly.
+///
+/// Prices are in ticks of the instrument's tick size, which is used to convert them to decimal
+/// prices for output. Levels are returned sorted by distance to mid, i.e. bids in descending
+/// order and asks in ascending order.
+pub trait OrderBook {
+    fn id(&self) -> u64;
+    fn seq_no(&self) -> u64;
+    fn timestamp(&self) -> u64;
+    fn tick_size(&self) -> TickSize;
+    fn health(&self) -> BookHealth;
+    /// Counts of the crossed and locked order book, see `CrossPolicy`.
+    fn cross_counts(&self) -> CrossCounts;
+    /// Number of removed levels (qty 0), which didn't exist, e.g. levels removed before by
+    /// `CrossPolicy::Drop`. Such updates are only skipped with `CrossPolicy::Drop`, otherwise
+    /// they fail with `ser::Error::UnknownLevel`. Kept across snapshots.
+    fn unknown_deletes(&self) -> u64;
+
+    /// Adds, updates or removes (if qty is 0) a bid level.
+    fn add_bid(&mut self, price: Price, qty: u64) -> anyhow::Result<()>;
+    /// Adds, updates or removes (if qty is 0) an ask level.
+    fn add_ask(&mut self, price: Price, qty: u64) -> anyhow::Result<()>;
+
+    fn get_bids(&self) -> Vec<(Price, u64)>;
+    fn get_asks(&self) -> Vec<(Price, u64)>;
+
+    fn best_bid(&self) -> Option<(Price, u64)>;
+    fn best_ask(&self) -> Option<(Price, u64)>;
+    fn worst_bid(&self) -> Option<(Price, u64)>;
+    fn worst_ask(&self) -> Option<(Price, u64)>;
+
+    /// Iterates over the bid levels from the best one, without allocating.
+    fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_;
+    /// Iterates over the ask levels from the best one, without allocating.
+    fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_;
+
+    fn clear(&mut self);
+
+    /// Total qty of the levels on the side at the price or better, i.e. bids at or above and
+    /// asks at or below it.
+    fn qty_up_to(&self, side: Side, price: Price) -> u64;
+
+    /// Qty of the level on the side at the price, 0 if there's no such level.
+    fn qty_at(&self, side: Side, price: Price) -> u64;
+
+    /// Mid price, i.e. the average of the best bid and ask prices.
+    /// None if either side is empty, as are the other metrics of the top of the book.
+    fn mid_price(&self) -> Option<f64> {
+        let tick_size = self.tick_size();
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some((tick_size.to_f64(bid) + tick_size.to_f64(ask)) / 2.0)
+    }
+
+    /// Difference between the best ask and bid prices in ticks, negative if the book is crossed.
+    fn spread_ticks(&self) -> Option<i64> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        Some(ask.ticks() - bid.ticks())
+    }
+
+    /// Spread relative to the mid price in basis points.
+    fn spread_bps(&self) -> Option<f64> {
+        let spread = self.spread_ticks()? as f64 * self.tick_size().as_f64();
+        Some(spread / self.mid_price()? * 10_000.0)
+    }
+
+    /// Size weighted mid price, i.e. the best bid and ask prices weighted by the qty of
+    /// the opposite side, which moves it towards the side with less qty.
+    fn microprice(&self) -> Option<f64> {
+        let tick_size = self.tick_size();
+        let (bid, bid_qty) = self.best_bid()?;
+        let (ask, ask_qty) = self.best_ask()?;
+        let (bid_qty, ask_qty) = (bid_qty as f64, ask_qty as f64);
+        Some(
+            (tick_size.to_f64(bid) * ask_qty + tick_size.to_f64(ask) * bid_qty)
+                / (bid_qty + ask_qty),
+        )
+    }
+
+    /// Total qty of the top `levels` bid levels.
+    fn bid_volume(&self, levels: usize) -> u64 {
+        self.iter_bids().take(levels).map(|(_, qty)| qty).sum()
+    }
+
+    /// Total qty of the top `levels` ask levels.
+    fn ask_volume(&self, levels: usize) -> u64 {
+        self.iter_asks().take(levels).map(|(_, qty)| qty).sum()
+    }
+
+    /// Volume imbalance of the top `levels` levels per side in the range [-1, 1]:
+    /// `(bid_volume - ask_volume) / (bid_volume + ask_volume)`, positive if there's more bid qty.
+    /// None if both sides are empty.
+    fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid_volume = self.bid_volume(levels) as f64;
+        let ask_volume = self.ask_volume(levels) as f64;
+        if bid_volume + ask_volume == 0.0 {
+            return None;
+        }
+        Some((bid_volume - ask_volume) / (bid_volume + ask_volume))
+    }
+
+    /// Sweeps the levels of the side from the best one until the qty is filled, e.g. the ask side
+    /// for a buy market order. The sweep is partial, if there's not enough qty on the side.
+    fn sweep(&self, side: Side, qty: u64) -> Option<Sweep> {
+        match side {
+            Side::Bid => Sweep::from_levels(self.iter_bids(), qty, self.tick_size()),
+            Side::Ask => Sweep::from_levels(self.iter_asks(), qty, self.tick_size()),
+        }
+    }
+
+    /// Average price of filling the qty by sweeping the side (VWAP to fill).
+    /// None if there's not enough qty on the side.
+    fn vwap_to_fill(&self, side: Side, qty: u64) -> Option<f64> {
+        self.sweep(side, qty)
+            .filter(|sweep| sweep.qty == qty)
+            .map(|sweep| sweep.avg_price)
+    }
+
+    /// Worst price reached by filling the qty by sweeping the side.
+    /// None if there's not enough qty on the side.
+    fn price_for_qty(&self, side: Side, qty: u64) -> Option<Price> {
+        self.sweep(side, qty)
+            .filter(|sweep| sweep.qty == qty)
+            .map(|sweep| sweep.worst_price)
+    }
+
+    /// Total qty of the levels on the side within the given number of basis points of the mid
+    /// price, inclusive. None if there's no mid price.
+    fn qty_within_bps(&self, side: Side, bps: f64) -> Option<u64> {
+        let (bid, _) = self.best_bid()?;
+        let (ask, _) = self.best_ask()?;
+        // in ticks, so the bound is rounded to the tick size, with tolerance for the rounding
+        // errors of the multiplication
+        let mid = (bid.ticks() + ask.ticks()) as f64 / 2.0;
+        let price = match side {
+            Side::Bid => Price((mid * (1.0 - bps / 10_000.0) - 1e-9).ceil() as i64),
+            Side::Ask => Price((mid * (1.0 + bps / 10_000.0) + 1e-9).floor() as i64),
+        };
+        Some(self.qty_up_to(side, price))
+    }
+}
+
+/// Reading order books of a specific implementation from the snapshot and incremental files.
+/// It is implemented by the element type of the order books map, e.g. `Box<OrderBook>` for the
+/// array based order book.
+/// Besides reading whole files, it allows applying single snapshot records and incremental
+/// messages, which is used to replay both files merged by time, see `replay::Replay`.
+pub trait OrderBookReader: OrderBook + Sized {
+    /// Creates the order books, which exist before any snapshot is applied, e.g. the array based
+    /// order books of the configured instruments.
+    fn init_order_books(config: &config::Config) -> HashMap<u64, Self>;
+
+    /// Applies the snapshot record in the configured format to the order books and re-syncs
+    /// the order book from the recovery, if it was stale.
+    fn apply_snapshot_record(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        config: &config::Config,
+        recovery: &mut GapRecovery,
+    ) -> anyhow::Result<()> {
+        Self::apply_snapshot_record_observed(buf, order_books, config, recovery, &mut ())
+    }
+
+    /// Same as `apply_snapshot_record`, but reports the changes to the observer.
+    fn apply_snapshot_record_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        config: &config::Config,
+        recovery: &mut GapRecovery,
+        observer: &mut O,
+    ) -> anyhow::Result<()>;
+
+    /// Applies the incremental message at the start of the buffer to the order books and
+    /// returns the size of the message.
+    #[inline(always)]
+    fn apply_incremental_message(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+    ) -> Result<usize, ser::Error> {
+        Self::apply_incremental_message_observed(buf, order_books, &mut ())
+    }
+
+    /// Same as `apply_incremental_message`, but reports the changes to the observer.
+    fn apply_incremental_message_observed<O: Observer + ?Sized>(
+        buf: &[u8],
+        order_books: &mut HashMap<u64, Self>,
+        observer: &mut O,
+    ) -> Result<usize, ser::Error>;
+
+    /// State of the order book for a checkpoint, see `checkpoint::Checkpoint`.
+    fn checkpoint(&self) -> BookState;
+
+    /// Restores the order book from its checkpoint state. The cross policy is the one of the
+    /// instrument's config, as the config can change between the runs.
+    fn restore(state: &BookState, config: &config::Config) -> anyhow::Result<Self>;
+
+    /// Reads the snapshot file and returns a map of order books indexed by their IDs.
+    fn read_snapshot_file(
+        snapshot_file: PathBuf,
+        config: &config::Config,
+    ) -> anyhow::Result<HashMap<u64, Self>>;
+
+    /// Reads the incremental updates from the file and applies them to the order books.
+    fn read_incremental_file(
+        incremental_file: PathBuf,
+        order_books: &mut HashMap<u64, Self>,
+        config: &config::Config,
+    ) -> anyhow::Result<()>;
+}
+
+impl<B: OrderBook + ?Sized> OrderBook for Box<B> {
+    #[inline(always)]
+    fn id(&self) -> u64 {
+        (**self).id()
+    }
+
+    #[inline(always)]
+    fn seq_no(&self) -> u64 {
+        (**self).seq_no()
+    }
+
+    #[inline(always)]
+    fn timestamp(&self) -> u64 {
+        (**self).timestamp()
+    }
+
+    #[inline(always)]
+    fn tick_size(&self) -> TickSize {
+        (**self).tick_size()
+    }
+
+    #[inline(always)]
+    fn health(&self) -> BookHealth {
+        (**self).health()
+    }
+
+    #[inline(always)]
+    fn cross_counts(&self) -> CrossCounts {
+        (**self).cross_counts()
+    }
+
+    #[inline(always)]
+    fn unknown_deletes(&self) -> u64 {
+        (**self).unknown_deletes()
+    }
+
+    #[inline(always)]
+    fn add_bid(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        (**self).add_bid(price, qty)
+    }
+
+    #[inline(always)]
+    fn add_ask(&mut self, price: Price, qty: u64) -> anyhow::Result<()> {
+        (**self).add_ask(price, qty)
+    }
+
+    #[inline(always)]
+    fn get_bids(&self) -> Vec<(Price, u64)> {
+        (**self).get_bids()
+    }
+
+    #[inline(always)]
+    fn get_asks(&self) -> Vec<(Price, u64)> {
+        (**self).get_asks()
+    }
+
+    #[inline(always)]
+    fn best_bid(&self) -> Option<(Price, u64)> {
+        (**self).best_bid()
+    }
+
+    #[inline(always)]
+    fn best_ask(&self) -> Option<(Price, u64)> {
+        (**self).best_ask()
+    }
+
+    #[inline(always)]
+    fn worst_bid(&self) -> Option<(Price, u64)> {
+        (**self).worst_bid()
+    }
+
+    #[inline(always)]
+    fn worst_ask(&self) -> Option<(Price, u64)> {
+        (**self).worst_ask()
+    }
+
+    #[inline(always)]
+    fn iter_bids(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        (**self).iter_bids()
+    }
+
+    #[inline(always)]
+    fn iter_asks(&self) -> impl Iterator<Item = (Price, u64)> + '_ {
+        (**self).iter_asks()
+    }
+
+    #[inline(always)]
+    fn clear(&mut self) {
+        (**self).clear()
+    }
+
+    #[inline(always)]
+    fn qty_up_to(&self, side: Side, price: Price) -> u64 {
+        (**self).qty_up_to(side, price)
+    }
+
+    #[inline(always)]
+    fn qty_at(&self, side: Side, price: Price) -> u64 {
+        (**self).qty_at(side, price)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+                SnapshotRecord,
+            },
+            Error, InputMode, SnapshotFormat,
+        },
+    };
+
+    fn price(price: f64) -> Price {
+        TickSize::new(0.01).to_price(price)
+    }
+
+    fn prices(levels: &[(f64, u64)]) -> Vec<(Price, u64)> {
+        levels.iter().map(|(p, qty)| (price(*p), *qty)).collect()
+    }
+
+    fn apply_levels<B: OrderBook>(order_book: &mut B) {
+        order_book.add_bid(price(100.0), 10).unwrap();
+        order_book.add_bid(price(100.1), 4).unwrap();
+        order_book.add_bid(price(100.05), 20).unwrap();
+        order_book.add_ask(price(101.1), 2).unwrap();
+        order_book.add_ask(price(101.0), 5).unwrap();
+        order_book.add_ask(price(102.0), 1).unwrap();
+        order_book.add_ask(price(101.1), 0).unwrap();
+    }
+
+    fn assert_levels<B: OrderBook>(order_book: &B) {
+        assert_eq!(
+            order_book.get_bids(),
+            prices(&[(100.1, 4), (100.05, 20), (100.0, 10)])
+        );
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 5), (102.0, 1)]));
+        assert_eq!(order_book.best_bid(), Some((price(100.1), 4)));
+        assert_eq!(order_book.best_ask(), Some((price(101.0), 5)));
+        assert_eq!(order_book.worst_bid(), Some((price(100.0), 10)));
+        assert_eq!(order_book.worst_ask(), Some((price(102.0), 1)));
+        assert_eq!(order_book.qty_at(Side::Bid, price(100.05)), 20);
+        assert_eq!(order_book.qty_at(Side::Ask, price(101.1)), 0);
+        assert_eq!(order_book.qty_at(Side::Ask, price(100.05)), 0);
+        let tick_size = order_book.tick_size();
+        assert_eq!(tick_size.to_f64(order_book.best_bid().unwrap().0), 100.1);
+    }
+
+    #[test]
+    fn test_btree_order_book() {
+        let mut order_book =
+            btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01));
+        apply_levels(&mut order_book);
+        assert_levels(&order_book);
+        assert_eq!(OrderBook::id(&order_book), 1);
+        assert_eq!(OrderBook::health(&order_book), BookHealth::Live);
+        OrderBook::clear(&mut order_book);
+        assert_eq!(OrderBook::best_bid(&order_book), None);
+    }
+
+    #[test]
+    fn test_array_order_book() {
+        let config = config::OrderBookConfig {
+            id: 1,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        };
+        let mut order_book = Box::new(array_orderbook::orderbook::OrderBook::new(config));
+        order_book.init();
+        apply_levels(&mut order_book);
+        assert_levels(&order_book);
+        assert_eq!(OrderBook::id(&order_book), 1);
+        assert_eq!(OrderBook::health(&order_book), BookHealth::Live);
+        OrderBook::clear(&mut order_book);
+        assert_eq!(OrderBook::best_bid(&order_book), None);
+    }
+
+    fn cross_config(cross_policy: CrossPolicy) -> config::Config {
+        config::Config {
+            instruments: HashMap::from([(
+                1,
+                config::OrderBookConfig {
+                    id: 1,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy,
+                },
+            )]),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    /// Applies a snapshot with the bid 100.0 and the ask 101.0.
+    fn apply_snapshot<B: OrderBookReader>(
+        order_books: &mut HashMap<u64, B>,
+        config: &config::Config,
+        seq_no: u64,
+    ) {
+        let record = SnapshotRecord {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id: 1,
+            bids: vec![(100.0, 1)],
+            asks: vec![(101.0, 5)],
+        };
+        let mut buf = vec![];
+        write_snapshot_record(&mut buf, &record, SnapshotFormat::Legacy).unwrap();
+        B::apply_snapshot_record(&buf, order_books, config, &mut GapRecovery::new()).unwrap();
+    }
+
+    fn apply_message<B: OrderBookReader>(
+        order_books: &mut HashMap<u64, B>,
+        seq_no: u64,
+        updates: &[(ser::writer::Side, f64, u64)],
+    ) -> Result<usize, Error> {
+        let message = IncrementalMessage {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id: 1,
+            updates: updates
+                .iter()
+                .map(|(side, price, qty)| LevelUpdate {
+                    side: *side,
+                    price: *price,
+                    qty: *qty,
+                })
+                .collect(),
+        };
+        let mut buf = vec![];
+        write_incremental_message(&mut buf, &message).unwrap();
+        B::apply_incremental_message(&buf, order_books)
+    }
+
+    fn test_cross_policy<B: OrderBookReader>() {
+        use ser::writer::Side::{Ask, Bid};
+
+        // the bids at 101.0 (locked) and 101.5 cross the ask 101.0
+        let crossing = [(Bid, 101.0, 2), (Bid, 101.5, 3)];
+
+        let config = cross_config(CrossPolicy::Warn);
+        let mut order_books = B::init_order_books(&config);
+        apply_snapshot(&mut order_books, &config, 1);
+        apply_message(&mut order_books, 2, &[(Bid, 101.0, 2)]).unwrap();
+        apply_message(&mut order_books, 3, &crossing).unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.health(), BookHealth::Live);
+        assert_eq!(order_book.best_bid(), Some((price(101.5), 3)));
+        assert_eq!(
+            order_book.cross_counts(),
+            CrossCounts {
+                crossed: 1,
+                locked: 1,
+                dropped_levels: 0
+            }
+        );
+        // the counts are kept across snapshots
+        apply_snapshot(&mut order_books, &config, 4);
+        assert_eq!(order_books[&1].cross_counts().crossed, 1);
+        assert_eq!(cross(&order_books[&1]), None);
+
+        let config = cross_config(CrossPolicy::Drop);
+        let mut order_books = B::init_order_books(&config);
+        apply_snapshot(&mut order_books, &config, 1);
+        apply_message(&mut order_books, 2, &crossing).unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 1)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 5)]));
+        // an ask below the best bid drops the ask, the bids are kept
+        apply_message(&mut order_books, 3, &[(Ask, 101.0, 4), (Ask, 99.5, 1)]).unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 1)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 4)]));
+        assert_eq!(
+            order_book.cross_counts(),
+            CrossCounts {
+                crossed: 2,
+                locked: 0,
+                dropped_levels: 3
+            }
+        );
+        // the feed removing the dropped levels later is skipped and counted
+        apply_message(&mut order_books, 4, &[(Bid, 101.5, 0), (Ask, 99.5, 0)]).unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.seq_no(), 4);
+        assert_eq!(order_book.unknown_deletes(), 2);
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 1)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 4)]));
+
+        let config = cross_config(CrossPolicy::Invalidate);
+        let mut order_books = B::init_order_books(&config);
+        apply_snapshot(&mut order_books, &config, 1);
+        apply_message(&mut order_books, 2, &crossing).unwrap();
+        assert_eq!(order_books[&1].health(), BookHealth::Invalid);
+        assert_eq!(order_books[&1].cross_counts().crossed, 1);
+        // updates are skipped until the next snapshot
+        apply_message(&mut order_books, 3, &[(Ask, 102.0, 1)]).unwrap();
+        assert_eq!(order_books[&1].seq_no(), 2);
+        apply_snapshot(&mut order_books, &config, 4);
+        assert_eq!(order_books[&1].health(), BookHealth::Live);
+        apply_message(&mut order_books, 5, &[(Ask, 102.0, 1)]).unwrap();
+        assert_eq!(order_books[&1].seq_no(), 5);
+        assert_eq!(
+            order_books[&1].get_asks(),
+            prices(&[(101.0, 5), (102.0, 1)])
+        );
+
+        let config = cross_config(CrossPolicy::Fail);
+        let mut order_books = B::init_order_books(&config);
+        apply_snapshot(&mut order_books, &config, 1);
+        assert!(matches!(
+            apply_message(&mut order_books, 2, &crossing),
+            Err(Error::CrossedBook(1, 2))
+        ));
+        assert_eq!(order_books[&1].cross_counts().crossed, 1);
+    }
+
+    #[test]
+    fn test_cross_policy_btree() {
+        test_cross_policy::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_cross_policy_array() {
+        test_cross_policy::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    orderbook::{BookHealth, OrderBook},
+    price::{Price, TickSize},
+};
+
+/// Format of the order books output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// JSON array of `OrderBookOutput` objects.
+    #[default]
+    Json,
+    /// One `CsvRow` per level.
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(anyhow!(
+                "Unknown output format: {}, expected json or csv",
+                s
+            )),
+        }
+    }
+}
+
+/// Price level with decimal price.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    pub price: f64,
+    pub qty: u64,
+}
+
+/// Output of a single order book, independent of the order book implementation.
+/// Levels are sorted by distance to mid, i.e. bids in descending and asks in ascending order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrderBookOutput {
+    pub id: u64,
+    pub seq_no: u64,
+    pub timestamp: u64,
+    pub health: BookHealth,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+impl OrderBookOutput {
+    pub fn from_order_book<B: OrderBook>(order_book: &B) -> Self {
+        let tick_size = order_book.tick_size();
+        Self {
+            id: order_book.id(),
+            seq_no: order_book.seq_no(),
+            timestamp: order_book.timestamp(),
+            health: order_book.health(),
+            bids: to_levels(order_book.get_bids(), tick_size),
+            asks: to_levels(order_book.get_asks(), tick_size),
+        }
+    }
+}
+
+/// Row of the CSV output, `level` is the 0-based index of the level on its side.
+/// Order books without any levels have no rows.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CsvRow {
+    pub id: u64,
+    pub seq_no: u64,
+    pub timestamp: u64,
+    pub side: CsvSide,
+    pub level: usize,
+    pub price: f64,
+    pub qty: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvSide {
+    Bid,
+    Ask,
+}
+
+fn to_levels(levels: Vec<(Price, u64)>, tick_size: TickSize) -> Vec<Level> {
+    levels
+        .into_iter()
+        .map(|(price, qty)| Level {
+            price: tick_size.to_f64(price),
+            qty,
+        })
+        .collect()
+}
+
+/// Writes the order books, ordered by ID, in the given format.
+pub fn write_order_books<B: OrderBook, W: Write>(
+    mut writer: W,
+    order_books: &HashMap<u64, B>,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    let mut ids: Vec<&u64> = order_books.keys().collect();
+    ids.sort();
+    let outputs = ids
+        .into_iter()
+        .map(|id| OrderBookOutput::from_order_book(&order_books[id]));
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &outputs.collect::<Vec<_>>())?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for output in outputs {
+                for (side, levels) in [(CsvSide::Bid, &output.bids), (CsvSide::Ask, &output.asks)] {
+                    for (level, Level { price, qty }) in levels.iter().enumerate() {
+                        writer.serialize(CsvRow {
+                            id: output.id,
+                            seq_no: output.seq_no,
+                            timestamp: output.timestamp,
+                            side,
+                            level,
+                            price: *price,
+                            qty: *qty,
+                        })?;
+                    }
+                }
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the order books to the output file, see `write_order_books`.
+pub fn write_output_file<B: OrderBook>(
+    output_file: PathBuf,
+    order_books: &HashMap<u64, B>,
+    format: OutputFormat,
+) -> anyhow::Result<()> {
+    info!("Writing {:?} output file: {:?}", format, output_file);
+    let mut writer = BufWriter::new(File::create(output_file)?);
+    write_order_books(&mut writer, order_books, format)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook, config::OrderBookConfig, orderbook::CrossPolicy,
+    };
+
+    fn order_books() -> (
+        HashMap<u64, btree_orderbook::orderbook::OrderBook>,
+        HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>,
+    ) {
+        let mut btree_books = HashMap::new();
+        let mut array_books = HashMap::new();
+        for id in [2, 1] {
+            let tick_size = TickSize::new(0.01);
+            let mut btree_book =
+                btree_orderbook::orderbook::OrderBook::with_tick_size(id, tick_size);
+            let mut array_book = Box::new(array_orderbook::orderbook::OrderBook::new(
+                OrderBookConfig {
+                    id,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                },
+            ));
+            array_book.init();
+            btree_book.seq_no = 10 + id;
+            btree_book.timestamp = 100 + id;
+            array_book.seq_no = 10 + id;
+            array_book.timestamp = 100 + id;
+            if id == 1 {
+                for (price, qty) in [(100.05, 1), (100.0, 2)] {
+                    btree_book.add_bid(tick_size.to_price(price), qty);
+                    array_book.add_bid(tick_size.to_price(price), qty).unwrap();
+                }
+                btree_book.add_ask(tick_size.to_price(100.1), 3);
+                array_book.add_ask(tick_size.to_price(100.1), 3).unwrap();
+            }
+            btree_books.insert(id, btree_book);
+            array_books.insert(id, array_book);
+        }
+        (btree_books, array_books)
+    }
+
+    fn write(order_books: &HashMap<u64, impl OrderBook>, format: OutputFormat) -> String {
+        let mut buf = vec![];
+        write_order_books(&mut buf, order_books, format).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_write_json() {
+        let (btree_books, array_books) = order_books();
+        let json = write(&btree_books, OutputFormat::Json);
+        assert_eq!(json, write(&array_books, OutputFormat::Json));
+
+        let outputs: Vec<OrderBookOutput> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            outputs,
+            vec![
+                OrderBookOutput {
+                    id: 1,
+                    seq_no: 11,
+                    timestamp: 101,
+                    health: BookHealth::Live,
+                    bids: vec![
+                        Level {
+                            price: 100.05,
+                            qty: 1
+                        },
+                        Level {
+                            price: 100.0,
+                            qty: 2
+                        }
+                    ],
+                    asks: vec![Level {
+                        price: 100.1,
+                        qty: 3
+                    }],
+                },
+                OrderBookOutput {
+                    id: 2,
+                    seq_no: 12,
+                    timestamp: 102,
+                    health: BookHealth::Live,
+                    bids: vec![],
+                    asks: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let (btree_books, array_books) = order_books();
+        let csv = write(&btree_books, OutputFormat::Csv);
+        assert_eq!(csv, write(&array_books, OutputFormat::Csv));
+        assert_eq!(
+            csv,
+            "id,seq_no,timestamp,side,level,price,qty\n\
+             1,11,101,bid,0,100.05,1\n\
+             1,11,101,bid,1,100.0,2\n\
+             1,11,101,ask,0,100.1,3\n"
+        );
+    }
+
+    #[test]
+    fn test_output_format_from_str() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!("CSV".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -0,0 +1,182 @@
+use std::ops::{Add, Sub};
+
+use serde::{Deserialize, Serialize};
+
+/// Default tick size for instruments without configuration, fine enough to represent prices
+/// with up to 8 decimals.
+pub const DEFAULT_TICK_SIZE: f64 = 0.000_000_01;
+
+const MAX_TICK_SIZE_DECIMALS: u32 = 12;
+
+/// Price as an integer number of ticks.
+/// Order books store and compare prices only in ticks, the conversion from and to decimal prices
+/// is done using the instrument's `TickSize` when decoding input and producing output.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Price(pub i64);
+
+impl Price {
+    pub const MIN: Price = Price(i64::MIN);
+    pub const MAX: Price = Price(i64::MAX);
+
+    #[inline(always)]
+    pub fn ticks(self) -> i64 {
+        self.0
+    }
+}
+
+impl Add<i64> for Price {
+    type Output = Price;
+
+    #[inline(always)]
+    fn add(self, ticks: i64) -> Price {
+        Price(self.0 + ticks)
+    }
+}
+
+impl Sub<i64> for Price {
+    type Output = Price;
+
+    #[inline(always)]
+    fn sub(self, ticks: i64) -> Price {
+        Price(self.0 - ticks)
+    }
+}
+
+/// Tick size as a fixed point decimal `units / scale`, where scale is a power of 10,
+/// e.g. 0.05 is stored as 5 / 100.
+/// Converting ticks to decimal price divides exact integers, so the result is the closest `f64`
+/// to the decimal price, e.g. 10005 ticks of 0.01 is 100.05 and not 100.05000000000001.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TickSize {
+    units: i64,
+    scale: i64,
+}
+
+impl TickSize {
+    pub fn new(tick_size: f64) -> Self {
+        assert!(
+            tick_size.is_finite() && tick_size > 0.0,
+            "Tick size must be positive, got {}",
+            tick_size
+        );
+        let mut scale: i64 = 1;
+        let mut decimals = 0;
+        while ((tick_size * scale as f64) - (tick_size * scale as f64).round()).abs() > 1e-9
+            && decimals < MAX_TICK_SIZE_DECIMALS
+        {
+            scale *= 10;
+            decimals += 1;
+        }
+        Self {
+            units: (tick_size * scale as f64).round() as i64,
+            scale,
+        }
+    }
+
+    /// Converts decimal price to the nearest number of ticks.
+    #[inline(always)]
+    pub fn to_price(&self, price: f64) -> Price {
+        Price((price * self.scale as f64 / self.units as f64).round() as i64)
+    }
+
+    /// Converts decimal price to ticks, if it's on the tick grid, i.e. it's a whole number of
+    /// ticks up to the rounding error of `f64`, otherwise returns None.
+    #[inline(always)]
+    pub fn to_price_on_grid(&self, price: f64) -> Option<Price> {
+        let ticks = price * self.scale as f64 / self.units as f64;
+        let rounded = ticks.round();
+        // the error of the conversion is relative to the number of ticks, e.g. the default tick
+        // size of 1e-8 leaves only a few bits of `f64` for the fraction of a tick
+        let tolerance = (rounded.abs() * 4.0 * f64::EPSILON).max(1e-9);
+        ((ticks - rounded).abs() <= tolerance).then_some(Price(rounded as i64))
+    }
+
+    /// Converts number of ticks to decimal price, used only for output.
+    #[inline(always)]
+    pub fn to_f64(&self, price: Price) -> f64 {
+        // in i128, as the ticks times units can overflow i64 for prices decoded from the input
+        (price.0 as i128 * self.units as i128) as f64 / self.scale as f64
+    }
+
+    /// Converts fractional number of ticks, e.g. an average price, to decimal price.
+    #[inline(always)]
+    pub fn ticks_to_f64(&self, ticks: f64) -> f64 {
+        ticks * self.units as f64 / self.scale as f64
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.units as f64 / self.scale as f64
+    }
+}
+
+impl Default for TickSize {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tick_size() {
+        let tick_size = TickSize::new(0.01);
+        assert_eq!(tick_size.as_f64(), 0.01);
+        assert_eq!(tick_size.to_price(100.05), Price(10005));
+        assert_eq!(tick_size.to_f64(Price(10005)), 100.05);
+        assert_eq!(
+            tick_size.to_price(5000.7),
+            tick_size.to_price(5000.700000001)
+        );
+
+        let tick_size = TickSize::new(0.05);
+        assert_eq!(tick_size.to_price(100.05), Price(2001));
+        assert_eq!(tick_size.to_f64(Price(2001)), 100.05);
+
+        let tick_size = TickSize::new(5.0);
+        assert_eq!(tick_size.to_price(600_000.0), Price(120_000));
+        assert_eq!(tick_size.to_f64(Price(120_000)), 600_000.0);
+    }
+
+    #[test]
+    fn test_to_price_on_grid() {
+        let tick_size = TickSize::new(0.01);
+        assert_eq!(tick_size.to_price_on_grid(100.05), Some(Price(10005)));
+        assert_eq!(tick_size.to_price_on_grid(0.1 + 0.2), Some(Price(30)));
+        assert_eq!(tick_size.to_price_on_grid(100.055), None);
+        assert_eq!(tick_size.to_price_on_grid(100.0500001), None);
+
+        let tick_size = TickSize::new(0.05);
+        assert_eq!(tick_size.to_price_on_grid(100.05), Some(Price(2001)));
+        assert_eq!(tick_size.to_price_on_grid(100.01), None);
+
+        let tick_size = TickSize::default();
+        for price in [5000.75, 0.12345678, 600_000.00000001] {
+            assert_eq!(
+                tick_size.to_price_on_grid(price),
+                Some(tick_size.to_price(price))
+            );
+        }
+        assert_eq!(tick_size.to_price_on_grid(100.000000005), None);
+    }
+
+    #[test]
+    fn test_default_tick_size() {
+        let tick_size = TickSize::default();
+        assert_eq!(tick_size.as_f64(), DEFAULT_TICK_SIZE);
+        for price in [5000.75, 5000.7, 600_000.0, 0.12345678] {
+            assert_eq!(tick_size.to_f64(tick_size.to_price(price)), price);
+        }
+    }
+
+    #[test]
+    fn test_price_arithmetic() {
+        assert_eq!(Price(100) + 5, Price(105));
+        assert_eq!(Price(100) - 5, Price(95));
+        assert!(Price(100) < Price(101));
+    }
+}
@@ -0,0 +1,919 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use memmap2::Mmap;
+use tracing::{debug, info, trace, warn};
+
+use crate::{
+    checkpoint::Checkpoint,
+    config::Config,
+    observer::Observer,
+    orderbook::{BookHealth, OrderBookReader},
+    sampler::Sampler,
+    ser::{
+        self, recovery::GapRecovery, tail::TailReader, Error, SNAPSHOT_ID_OFFSET,
+        SNAPSHOT_SEQ_NO_OFFSET, SNAPSHOT_TIMESTAMP_OFFSET, UPDATE_ID_OFFSET, UPDATE_METADATA_SIZE,
+        UPDATE_SEQ_NO_OFFSET, UPDATE_TIMESTAMP_OFFSET,
+    },
+    top::Publishers,
+};
+
+/// Header of a snapshot record or incremental message of the replay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    /// Whether the event is a snapshot record or an incremental message.
+    pub snapshot: bool,
+    pub timestamp: u64,
+    pub seq_no: u64,
+    pub id: u64,
+}
+
+/// Point in time to replay up to, see `Replay::run_until`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AsOf {
+    /// All events with a timestamp up to and including the given one are applied.
+    Timestamp(u64),
+    /// All events with a sequence number up to and including the given one are applied, until
+    /// an event of the order book with a greater sequence number is reached.
+    SeqNo(u64),
+}
+
+impl Event {
+    /// Header of the snapshot record at the start of the buffer.
+    pub(crate) fn snapshot(buf: &[u8]) -> Self {
+        Self {
+            snapshot: true,
+            timestamp: ser::read_u64_le(buf, SNAPSHOT_TIMESTAMP_OFFSET),
+            seq_no: ser::read_u64_le(buf, SNAPSHOT_SEQ_NO_OFFSET),
+            id: ser::read_u64_le(buf, SNAPSHOT_ID_OFFSET),
+        }
+    }
+
+    /// Header of the incremental message at the start of the buffer.
+    pub(crate) fn incremental(buf: &[u8]) -> Self {
+        Self {
+            snapshot: false,
+            timestamp: ser::read_u64_le(buf, UPDATE_TIMESTAMP_OFFSET),
+            seq_no: ser::read_u64_le(buf, UPDATE_SEQ_NO_OFFSET),
+            id: ser::read_u64_le(buf, UPDATE_ID_OFFSET),
+        }
+    }
+
+    /// The earlier of the next snapshot record and the next incremental message, i.e. the next
+    /// event of the merged streams, see `Replay`.
+    pub(crate) fn earlier(snapshot: Option<Event>, incremental: Option<Event>) -> Option<Event> {
+        match (snapshot, incremental) {
+            (Some(snapshot), Some(incremental)) => {
+                if (snapshot.timestamp, snapshot.seq_no)
+                    <= (incremental.timestamp, incremental.seq_no)
+                {
+                    Some(snapshot)
+                } else {
+                    Some(incremental)
+                }
+            }
+            (snapshot, incremental) => snapshot.or(incremental),
+        }
+    }
+}
+
+impl AsOf {
+    /// Whether the point in time is before the event, i.e. the replay of the order book with
+    /// the given ID stops at the event.
+    fn is_before(&self, event: &Event, id: u64) -> bool {
+        match *self {
+            AsOf::Timestamp(timestamp) => event.timestamp > timestamp,
+            AsOf::SeqNo(seq_no) => event.id == id && event.seq_no > seq_no,
+        }
+    }
+}
+
+/// Replay of the snapshot and incremental streams merged by timestamp and sequence number.
+/// Each step applies the earlier of the next snapshot record and the next incremental message,
+/// so a snapshot resets the order book at its point in time: incremental messages before it are
+/// applied to the previous state, the ones after it, which are older than the snapshot, are
+/// discarded, and the newer ones are applied on top of it. A snapshot is applied before an
+/// incremental message with the same timestamp and sequence number.
+///
+/// Incremental messages of the order books without a snapshot yet are buffered in the same way
+/// as messages of stale order books (see `ser::recovery::GapRecovery`) and replayed once
+/// the snapshot is applied.
+pub struct Replay<B: OrderBookReader, S: Read = BufReader<File>, I: Read = BufReader<File>> {
+    config: Config,
+    snapshots: S,
+    incrementals: I,
+    snapshot_buf: Vec<u8>,
+    incremental_buf: Vec<u8>,
+    // whether the buffers hold the next record of the stream
+    has_snapshot: bool,
+    has_incremental: bool,
+    // offsets of the next records of the streams, i.e. of the buffered ones
+    snapshot_offset: u64,
+    incremental_offset: u64,
+    order_books: HashMap<u64, B>,
+    recovery: GapRecovery,
+    sampler: Option<Sampler>,
+    publishers: Option<Publishers>,
+    observer: Option<Box<dyn Observer>>,
+}
+
+impl<B: OrderBookReader> Replay<B> {
+    /// Opens the snapshot and incremental files for the replay.
+    pub fn open(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+    ) -> anyhow::Result<Self> {
+        Self::restore(
+            snapshot_file,
+            incremental_file,
+            config,
+            Checkpoint::default(),
+        )
+    }
+
+    /// Opens the snapshot and incremental files for the replay restored from the checkpoint,
+    /// the files are read from the offsets saved in the checkpoint.
+    pub fn restore(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        checkpoint: Checkpoint,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Replaying snapshot file: {:?} from offset {} and incremental file: {:?} from offset {}",
+            snapshot_file,
+            checkpoint.snapshot_offset,
+            incremental_file,
+            checkpoint.incremental_offset
+        );
+        let snapshots = BufReader::new(open_at(snapshot_file, checkpoint.snapshot_offset)?);
+        let incrementals = BufReader::with_capacity(
+            config.incremental_buffer_size,
+            open_at(incremental_file, checkpoint.incremental_offset)?,
+        );
+        Self::from_checkpoint(snapshots, incrementals, config, checkpoint)
+    }
+}
+
+impl<B: OrderBookReader> Replay<B, Cursor<Mmap>, Cursor<Mmap>> {
+    /// Same as `open`, but the snapshot and incremental files are memory mapped (see `ser::mmap`),
+    /// so they are read without system calls. The records are still copied into the buffers of
+    /// the replay, as both streams are merged.
+    pub fn open_mmap(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+    ) -> anyhow::Result<Self> {
+        Self::restore_mmap(
+            snapshot_file,
+            incremental_file,
+            config,
+            Checkpoint::default(),
+        )
+    }
+
+    /// Same as `restore`, but the files are memory mapped, see `open_mmap`.
+    pub fn restore_mmap(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        checkpoint: Checkpoint,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Replaying memory mapped snapshot file: {:?} from offset {} and incremental file: \
+             {:?} from offset {}",
+            snapshot_file,
+            checkpoint.snapshot_offset,
+            incremental_file,
+            checkpoint.incremental_offset
+        );
+        let snapshots = ser::mmap::open_at(snapshot_file, checkpoint.snapshot_offset)?;
+        let incrementals = ser::mmap::open_at(incremental_file, checkpoint.incremental_offset)?;
+        Self::from_checkpoint(snapshots, incrementals, config, checkpoint)
+    }
+}
+
+impl<B: OrderBookReader> Replay<B, BufReader<File>, TailReader> {
+    /// Opens the snapshot file and follows the growing incremental file (see `ser::tail`),
+    /// so the replay keeps the order books live. The replay ends once no data is appended to
+    /// the incremental file for the idle timeout, if set, otherwise it never ends.
+    pub fn follow(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        idle_timeout: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        Self::follow_restore(
+            snapshot_file,
+            incremental_file,
+            config,
+            idle_timeout,
+            Checkpoint::default(),
+        )
+    }
+
+    /// Same as `follow`, but restored from the checkpoint, see `Replay::restore`.
+    pub fn follow_restore(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        idle_timeout: Option<Duration>,
+        checkpoint: Checkpoint,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Replaying snapshot file: {:?} from offset {} and following incremental file: {:?} \
+             from offset {}",
+            snapshot_file,
+            checkpoint.snapshot_offset,
+            incremental_file,
+            checkpoint.incremental_offset
+        );
+        let snapshots = BufReader::new(open_at(snapshot_file, checkpoint.snapshot_offset)?);
+        let incrementals = TailReader::open_at(
+            incremental_file,
+            config.incremental_buffer_size,
+            checkpoint.incremental_offset,
+        )?
+        .with_idle_timeout(idle_timeout);
+        Self::from_checkpoint(snapshots, incrementals, config, checkpoint)
+    }
+}
+
+impl<B: OrderBookReader, S: Read, I: Read> Replay<B, S, I> {
+    pub fn new(snapshots: S, incrementals: I, config: Config) -> anyhow::Result<Self> {
+        Self::from_checkpoint(snapshots, incrementals, config, Checkpoint::default())
+    }
+
+    /// Creates the replay with the state of the checkpoint, the streams are expected to start
+    /// at the offsets saved in the checkpoint.
+    pub fn from_checkpoint(
+        snapshots: S,
+        incrementals: I,
+        config: Config,
+        checkpoint: Checkpoint,
+    ) -> anyhow::Result<Self> {
+        let mut order_books = B::init_order_books(&config);
+        for state in checkpoint.order_books.iter() {
+            order_books.insert(state.id, B::restore(state, &config)?);
+        }
+        let mut recovery = GapRecovery::with_limit(config.recovery_buffer_limit);
+        for (id, buf) in checkpoint.buffered.iter() {
+            recovery.buffer(*id, buf);
+        }
+        let mut replay = Self {
+            order_books,
+            config,
+            snapshots,
+            incrementals,
+            snapshot_buf: Vec::with_capacity(ser::SNAPSHOT_RECORD_SIZE),
+            incremental_buf: Vec::with_capacity(UPDATE_METADATA_SIZE),
+            has_snapshot: false,
+            has_incremental: false,
+            snapshot_offset: checkpoint.snapshot_offset,
+            incremental_offset: checkpoint.incremental_offset,
+            recovery,
+            sampler: None,
+            publishers: None,
+            observer: None,
+        };
+        replay.next_snapshot()?;
+        replay.next_incremental()?;
+        Ok(replay)
+    }
+
+    /// Sets the sampler of the order books, which is fed with the events of the replay.
+    pub fn with_sampler(mut self, sampler: Option<Sampler>) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Sets the publishers of the top of book (see `top::Publishers`), the top of book of
+    /// an order book with subscribers is published after each of its events.
+    pub fn with_publishers(mut self, publishers: Option<Publishers>) -> Self {
+        self.publishers = publishers;
+        self
+    }
+
+    /// Sets the observer of the changes of the order books (see `observer::Observer`), which is
+    /// invoked while the events of the replay are applied.
+    pub fn with_observer(mut self, observer: Option<Box<dyn Observer>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub fn order_books(&self) -> &HashMap<u64, B> {
+        &self.order_books
+    }
+
+    pub fn recovery(&self) -> &GapRecovery {
+        &self.recovery
+    }
+
+    /// Complete state of the replay, from which it can be restored, see `Replay::restore`.
+    /// The checkpoint can be taken at any point between steps.
+    /// In follow mode, the offset of the incremental file is not reset on truncation or rotation
+    /// of the file.
+    pub fn checkpoint(&self) -> Checkpoint {
+        let mut ids: Vec<&u64> = self.order_books.keys().collect();
+        ids.sort();
+        let mut buffered_ids: Vec<u64> = self.recovery.buffered_ids().collect();
+        buffered_ids.sort();
+        Checkpoint {
+            snapshot_offset: self.snapshot_offset,
+            incremental_offset: self.incremental_offset,
+            order_books: ids
+                .into_iter()
+                .map(|id| self.order_books[id].checkpoint())
+                .collect(),
+            buffered: buffered_ids
+                .into_iter()
+                .map(|id| (id, self.recovery.buffered(id).to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Header of the next snapshot record or incremental message, whichever is earlier, i.e.
+    /// the event applied by the next `step`. Returns None if both streams are exhausted.
+    pub fn peek(&self) -> Option<Event> {
+        Event::earlier(
+            self.has_snapshot
+                .then(|| Event::snapshot(&self.snapshot_buf)),
+            self.has_incremental
+                .then(|| Event::incremental(&self.incremental_buf)),
+        )
+    }
+
+    /// Byte offset in its file and raw bytes of the snapshot record or incremental message of
+    /// the event returned by `peek`.
+    pub fn peek_record(&self, event: &Event) -> (u64, &[u8]) {
+        if event.snapshot {
+            (self.snapshot_offset, &self.snapshot_buf)
+        } else {
+            (self.incremental_offset, &self.incremental_buf)
+        }
+    }
+
+    /// Applies the next snapshot record or incremental message, whichever is earlier.
+    /// Returns false if both streams are exhausted.
+    pub fn step(&mut self) -> anyhow::Result<bool> {
+        let Some(event) = self.peek() else {
+            return Ok(false);
+        };
+        if let Some(sampler) = self.sampler.as_mut() {
+            sampler.before_event(event.timestamp, &self.order_books)?;
+        }
+        // the offsets are of the current record in its file, the same as in the file readers
+        if event.snapshot {
+            self.apply_snapshot().with_context(|| {
+                format!(
+                    "Failed to apply snapshot record at offset {}",
+                    self.snapshot_offset
+                )
+            })?;
+            self.snapshot_offset += self.snapshot_buf.len() as u64;
+            self.next_snapshot()?;
+        } else {
+            self.apply_incremental().with_context(|| {
+                format!(
+                    "Failed to apply incremental message at offset {}",
+                    self.incremental_offset
+                )
+            })?;
+            if let Some(sampler) = self.sampler.as_mut() {
+                sampler.after_incremental(event.timestamp, &self.order_books)?;
+            }
+            self.incremental_offset += self.incremental_buf.len() as u64;
+            self.next_incremental()?;
+        }
+        if let Some(publishers) = self.publishers.as_mut() {
+            publishers.publish(&self.order_books, event.id);
+        }
+        Ok(true)
+    }
+
+    /// Replays both streams to the end and returns the order books.
+    /// Exceptions:
+    /// * If there are incremental messages for an order book, which has no snapshot,
+    ///   an error is returned.
+    pub fn run(mut self) -> anyhow::Result<HashMap<u64, B>> {
+        while self.step()? {}
+        if let Some(sampler) = self.sampler.as_mut() {
+            sampler.flush()?;
+        }
+        if let Some(id) = self
+            .recovery
+            .buffered_ids()
+            .find(|id| !self.order_books.contains_key(id))
+        {
+            bail!("Order book with ID {} not found", id);
+        }
+        debug!(
+            "Replay finished, total order books: {}",
+            self.order_books.len()
+        );
+        Ok(self.order_books)
+    }
+
+    /// Replays the streams only up to the given point in time and returns the order book with
+    /// the given ID as of that point, the rest of the streams is not read.
+    /// Exceptions:
+    /// * If the order book has no snapshot up to that point, an error is returned.
+    /// * If the order book is stale at that point (see `orderbook::BookHealth`), it's returned
+    ///   with its last live state and a warning is logged.
+    pub fn run_until(mut self, id: u64, as_of: AsOf) -> anyhow::Result<B> {
+        while let Some(event) = self.peek() {
+            if as_of.is_before(&event, id) {
+                debug!("Replay stopped before {:?}", event);
+                break;
+            }
+            self.step()?;
+        }
+        if let Some(sampler) = self.sampler.as_mut() {
+            sampler.flush()?;
+        }
+        // the array based order books are preallocated, but have zero timestamp until a snapshot
+        let Some(order_book) = self
+            .order_books
+            .remove(&id)
+            .filter(|order_book| order_book.timestamp() > 0)
+        else {
+            bail!("No snapshot of order book with ID {} as of {:?}", id, as_of);
+        };
+        if order_book.health() != BookHealth::Live {
+            warn!(
+                "Order book ID {} is {:?} as of {:?}, last seq_no: {}",
+                id,
+                order_book.health(),
+                as_of,
+                order_book.seq_no()
+            );
+        }
+        Ok(order_book)
+    }
+
+    fn apply_snapshot(&mut self) -> anyhow::Result<()> {
+        match self.observer.as_deref_mut() {
+            Some(observer) => B::apply_snapshot_record_observed(
+                &self.snapshot_buf,
+                &mut self.order_books,
+                &self.config,
+                &mut self.recovery,
+                observer,
+            ),
+            None => B::apply_snapshot_record(
+                &self.snapshot_buf,
+                &mut self.order_books,
+                &self.config,
+                &mut self.recovery,
+            ),
+        }
+    }
+
+    fn apply_incremental(&mut self) -> anyhow::Result<()> {
+        match self.observer.as_deref_mut() {
+            Some(observer) => apply_incremental_message(
+                &self.incremental_buf,
+                &mut self.order_books,
+                &mut self.recovery,
+                observer,
+            ),
+            None => apply_incremental_message(
+                &self.incremental_buf,
+                &mut self.order_books,
+                &mut self.recovery,
+                &mut (),
+            ),
+        }
+    }
+
+    fn next_snapshot(&mut self) -> anyhow::Result<()> {
+        self.has_snapshot = ser::read_snapshot_record(
+            &mut self.snapshots,
+            self.config.snapshot_format,
+            &mut self.snapshot_buf,
+        )?;
+        Ok(())
+    }
+
+    fn next_incremental(&mut self) -> anyhow::Result<()> {
+        self.has_incremental =
+            ser::read_incremental_message(&mut self.incrementals, &mut self.incremental_buf)?;
+        Ok(())
+    }
+}
+
+/// Applies the incremental message of the replay to the order books: messages behind a gap and
+/// messages of the order books without a snapshot yet are buffered in the recovery.
+/// The changes of the order books are reported to the observer.
+pub(crate) fn apply_incremental_message<B: OrderBookReader, O: Observer + ?Sized>(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, B>,
+    recovery: &mut GapRecovery,
+    observer: &mut O,
+) -> anyhow::Result<()> {
+    match B::apply_incremental_message_observed(buf, order_books, observer) {
+        Ok(_) => {}
+        Err(Error::GapDetected(id, _)) => {
+            if recovery.buffered_size(id) == 0 {
+                warn!(
+                    "Gap detected in incremental updates for order book ID {}",
+                    id
+                );
+            }
+            recovery.buffer(id, buf);
+        }
+        Err(Error::OrderBookNotFound(id)) => {
+            trace!("No snapshot yet for order book ID {}, buffering update", id);
+            recovery.buffer(id, buf);
+        }
+        Err(e) => bail!(e),
+    }
+    Ok(())
+}
+
+/// Opens the file at the offset, which must be within the file.
+fn open_at(path: PathBuf, offset: u64) -> anyhow::Result<File> {
+    let mut file = File::open(&path)?;
+    let len = file.metadata()?.len();
+    if offset > len {
+        bail!(
+            "Offset {} is past the end of file {:?} of {} bytes",
+            offset,
+            path,
+            len
+        );
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::{BookHealth, CrossPolicy},
+        price::{Price, TickSize},
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+                Side, SnapshotRecord,
+            },
+            InputMode, SnapshotFormat,
+        },
+        top::TopOfBook,
+    };
+
+    fn config() -> Config {
+        Config {
+            instruments: HashMap::from([(
+                1,
+                OrderBookConfig {
+                    id: 1,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                },
+            )]),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    fn prices(levels: &[(f64, u64)]) -> Vec<(Price, u64)> {
+        let tick_size = TickSize::new(0.01);
+        levels
+            .iter()
+            .map(|(price, qty)| (tick_size.to_price(*price), *qty))
+            .collect()
+    }
+
+    fn write_snapshot(buf: &mut Vec<u8>, seq_no: u64, bid: (f64, u64), ask: (f64, u64)) {
+        let record = SnapshotRecord {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id: 1,
+            bids: vec![bid],
+            asks: vec![ask],
+        };
+        write_snapshot_record(buf, &record, SnapshotFormat::Legacy).unwrap();
+    }
+
+    fn write_update(buf: &mut Vec<u8>, id: u64, seq_no: u64, side: Side, price: f64, qty: u64) {
+        let message = IncrementalMessage {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id,
+            updates: vec![LevelUpdate { side, price, qty }],
+        };
+        write_incremental_message(buf, &message).unwrap();
+    }
+
+    /// Two snapshots of the same order book, with updates before, between and after them.
+    fn streams() -> (Vec<u8>, Vec<u8>) {
+        let mut snapshots = vec![];
+        write_snapshot(&mut snapshots, 10, (100.0, 1), (101.0, 5));
+        write_snapshot(&mut snapshots, 14, (99.0, 14), (101.0, 14));
+        let mut incrementals = vec![];
+        // before the first snapshot, covered by it
+        write_update(&mut incrementals, 1, 9, Side::Bid, 95.0, 9);
+        write_update(&mut incrementals, 1, 11, Side::Bid, 100.0, 11);
+        write_update(&mut incrementals, 1, 12, Side::Bid, 100.0, 12);
+        write_update(&mut incrementals, 1, 13, Side::Ask, 101.0, 13);
+        // after the second snapshot
+        write_update(&mut incrementals, 1, 15, Side::Bid, 100.0, 15);
+        (snapshots, incrementals)
+    }
+
+    fn test_replay<B: OrderBookReader>() {
+        let (snapshots, incrementals) = streams();
+        let mut replay =
+            Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config()).unwrap();
+
+        // update 9, first snapshot and updates 11, 12, 13
+        for _ in 0..5 {
+            assert!(replay.step().unwrap());
+        }
+        let order_book = &replay.order_books()[&1];
+        assert_eq!(order_book.seq_no(), 13);
+        assert_eq!(order_book.health(), BookHealth::Live);
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 12)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 13)]));
+
+        // second snapshot resets the order book
+        assert!(replay.step().unwrap());
+        let order_book = &replay.order_books()[&1];
+        assert_eq!(order_book.seq_no(), 14);
+        assert_eq!(order_book.get_bids(), prices(&[(99.0, 14)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 14)]));
+
+        let order_books = replay.run().unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.seq_no(), 15);
+        assert_eq!(order_book.timestamp(), 15000);
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 15), (99.0, 14)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 14)]));
+    }
+
+    #[test]
+    fn test_replay_btree() {
+        test_replay::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_replay_array() {
+        test_replay::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    fn test_publish_top_of_book<B: OrderBookReader>() {
+        let (snapshots, incrementals) = streams();
+        let mut publishers = Publishers::new();
+        let subscriber = publishers.subscribe(1);
+        let mut replay = Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config())
+            .unwrap()
+            .with_publishers(Some(publishers));
+
+        for _ in 0..6 {
+            assert!(replay.step().unwrap());
+            // the btree order books are created by their first snapshot
+            if let Some(order_book) = replay.order_books().get(&1) {
+                assert_eq!(subscriber.read(), TopOfBook::from_order_book(order_book));
+            }
+        }
+        let order_books = replay.run().unwrap();
+        let top = subscriber.read();
+        assert_eq!(top, TopOfBook::from_order_book(&order_books[&1]));
+        assert_eq!(top.seq_no, 15);
+        assert_eq!(top.best_bid(), prices(&[(100.0, 15)]).first().copied());
+    }
+
+    #[test]
+    fn test_publish_top_of_book_btree() {
+        test_publish_top_of_book::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_publish_top_of_book_array() {
+        test_publish_top_of_book::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    /// Sequence numbers of the applied snapshots and incremental messages.
+    #[derive(Default)]
+    struct SeqNos(Rc<RefCell<Vec<(bool, u64)>>>);
+
+    impl Observer for SeqNos {
+        fn on_book_updated(&mut self, _id: u64, seq_no: u64, _timestamp: u64) {
+            self.0.borrow_mut().push((false, seq_no));
+        }
+
+        fn on_snapshot_applied(&mut self, _id: u64, seq_no: u64) {
+            self.0.borrow_mut().push((true, seq_no));
+        }
+    }
+
+    fn test_observer<B: OrderBookReader>() {
+        let (snapshots, incrementals) = streams();
+        let seq_nos = SeqNos::default();
+        let applied = seq_nos.0.clone();
+        Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config())
+            .unwrap()
+            .with_observer(Some(Box::new(seq_nos)))
+            .run()
+            .unwrap();
+        // the update before the first snapshot is covered by it, so it's not applied
+        assert_eq!(
+            *applied.borrow(),
+            [
+                (true, 10),
+                (false, 11),
+                (false, 12),
+                (false, 13),
+                (true, 14),
+                (false, 15)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_btree() {
+        test_observer::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_observer_array() {
+        test_observer::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    fn test_run_until<B: OrderBookReader>() {
+        let (snapshots, mut incrementals) = streams();
+        // the rest of the stream isn't read once the point in time is reached
+        let mut invalid = vec![];
+        write_update(&mut invalid, 1, 16, Side::Bid, 100.0, 16);
+        invalid[ser::UPDATE_NUM_UPDATES_OFFSET..ser::UPDATE_METADATA_SIZE]
+            .copy_from_slice(&u64::MAX.to_le_bytes());
+        incrementals.extend_from_slice(&invalid);
+        let replay = || Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config());
+
+        let order_book = replay()
+            .unwrap()
+            .run_until(1, AsOf::Timestamp(12000))
+            .unwrap();
+        assert_eq!(order_book.seq_no(), 12);
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 12)]));
+        assert_eq!(order_book.get_asks(), prices(&[(101.0, 5)]));
+
+        let order_book = replay().unwrap().run_until(1, AsOf::SeqNo(14)).unwrap();
+        assert_eq!(order_book.seq_no(), 14);
+        assert_eq!(order_book.timestamp(), 14000);
+        assert_eq!(order_book.get_bids(), prices(&[(99.0, 14)]));
+
+        assert!(replay()
+            .unwrap()
+            .run_until(1, AsOf::Timestamp(9500))
+            .is_err());
+        assert!(replay().unwrap().run_until(2, AsOf::SeqNo(13)).is_err());
+        // the whole stream can't be read
+        assert!(replay().unwrap().run().is_err());
+    }
+
+    #[test]
+    fn test_run_until_btree() {
+        test_run_until::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_run_until_array() {
+        test_run_until::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    fn test_checkpoint<B: OrderBookReader>() {
+        let (snapshots, incrementals) = streams();
+        let expected = Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config())
+            .unwrap()
+            .run()
+            .unwrap();
+
+        for steps in 0..7 {
+            let mut replay =
+                Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config()).unwrap();
+            for _ in 0..steps {
+                assert!(replay.step().unwrap());
+            }
+            let json = serde_json::to_string(&replay.checkpoint()).unwrap();
+            let checkpoint: Checkpoint = serde_json::from_str(&json).unwrap();
+            assert_eq!(checkpoint, replay.checkpoint());
+
+            let snapshots = &snapshots[checkpoint.snapshot_offset as usize..];
+            let incrementals = &incrementals[checkpoint.incremental_offset as usize..];
+            let restored =
+                Replay::<B, _, _>::from_checkpoint(snapshots, incrementals, config(), checkpoint)
+                    .unwrap();
+            assert_eq!(restored.checkpoint(), replay.checkpoint());
+            let order_books = restored.run().unwrap();
+            let order_book = &order_books[&1];
+            assert_eq!(order_book.seq_no(), expected[&1].seq_no());
+            assert_eq!(order_book.get_bids(), expected[&1].get_bids());
+            assert_eq!(order_book.get_asks(), expected[&1].get_asks());
+        }
+    }
+
+    #[test]
+    fn test_checkpoint_btree() {
+        test_checkpoint::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_checkpoint_array() {
+        test_checkpoint::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    #[test]
+    fn test_replay_buffers_updates_without_snapshot() {
+        let mut snapshots = vec![];
+        write_snapshot(&mut snapshots, 10, (100.0, 1), (101.0, 5));
+        let mut incrementals = vec![];
+        // received before the snapshot, but newer than it
+        let message = IncrementalMessage {
+            timestamp: 9000,
+            seq_no: 11,
+            id: 1,
+            updates: vec![LevelUpdate {
+                side: Side::Bid,
+                price: 100.0,
+                qty: 11,
+            }],
+        };
+        write_incremental_message(&mut incrementals, &message).unwrap();
+        // messages without updates only advance the sequence number
+        let message = IncrementalMessage {
+            timestamp: 9500,
+            seq_no: 12,
+            id: 1,
+            updates: vec![],
+        };
+        write_incremental_message(&mut incrementals, &message).unwrap();
+
+        let order_books = Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+            &snapshots[..],
+            &incrementals[..],
+            config(),
+        )
+        .unwrap()
+        .run()
+        .unwrap();
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.seq_no, 12);
+        assert_eq!(order_book.health, BookHealth::Live);
+        assert_eq!(order_book.get_bids(), prices(&[(100.0, 11)]));
+    }
+
+    #[test]
+    fn test_replay_order_book_not_found() {
+        let (snapshots, mut incrementals) = streams();
+        write_update(&mut incrementals, 2, 16, Side::Bid, 100.0, 16);
+        let result = Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+            &snapshots[..],
+            &incrementals[..],
+            config(),
+        )
+        .unwrap()
+        .run();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_replay_error_offset() {
+        let (snapshots, mut incrementals) = streams();
+        let offset = incrementals.len();
+        write_update(&mut incrementals, 1, 16, Side::Bid, 100.005, 16);
+        let error = Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+            &snapshots[..],
+            &incrementals[..],
+            config(),
+        )
+        .unwrap()
+        .run()
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            format!(
+                "Failed to apply incremental message at offset {}: Price 100.005 at offset 33 is not on the tick grid",
+                offset
+            )
+        );
+    }
+}
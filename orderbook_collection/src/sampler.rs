@@ -0,0 +1,288 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use tracing::{debug, info};
+
+use crate::orderbook::{BookHealth, OrderBook};
+
+/// When the sampler takes a sample of the order books.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleTrigger {
+    /// At every multiple of the interval in event time, i.e. in the units of the `timestamp`
+    /// field of the snapshot records and incremental messages.
+    Interval(u64),
+    /// After every N incremental messages.
+    Updates(u64),
+}
+
+/// Sampler of the top levels of the order books during a replay (see `replay::Replay`), which
+/// writes a CSV time series with one row per order book and sample, ordered by ID, with columns:
+/// `sample_timestamp,id,timestamp,seq_no,health,bid_price_0,bid_qty_0,...,ask_price_0,ask_qty_0,...`
+/// Levels are sorted by distance to mid, missing levels are left empty. Order books, which
+/// haven't been loaded from a snapshot yet (i.e. with zero timestamp, like the preallocated array
+/// based order books), are skipped, so the samples are the same for both implementations.
+///
+/// With `SampleTrigger::Interval`, the sample at a multiple of the interval holds the state after
+/// all events with a timestamp up to it, and is taken when the first event past it is received.
+/// If events are further apart than the interval, the same state is sampled at each multiple in
+/// between, so the time series stays regular. There's no sample after the last event.
+///
+/// With `SampleTrigger::Updates`, the sample is taken after every N-th incremental message and
+/// its timestamp is the timestamp of the message.
+pub struct Sampler {
+    writer: csv::Writer<Box<dyn Write>>,
+    trigger: SampleTrigger,
+    depth: usize,
+    // next multiple of the interval to sample at
+    next_timestamp: Option<u64>,
+    updates: u64,
+    samples: u64,
+    ids: Vec<u64>,
+    record: Vec<String>,
+}
+
+impl Sampler {
+    /// Creates the sampler of the top `depth` levels per side and writes the CSV header.
+    pub fn new(
+        writer: Box<dyn Write>,
+        trigger: SampleTrigger,
+        depth: usize,
+    ) -> anyhow::Result<Self> {
+        if matches!(
+            trigger,
+            SampleTrigger::Interval(0) | SampleTrigger::Updates(0)
+        ) {
+            anyhow::bail!("Sample trigger must be positive: {:?}", trigger);
+        }
+        let mut sampler = Self {
+            writer: csv::Writer::from_writer(writer),
+            trigger,
+            depth,
+            next_timestamp: None,
+            updates: 0,
+            samples: 0,
+            ids: vec![],
+            record: Vec::with_capacity(5 + 4 * depth),
+        };
+        sampler.write_header()?;
+        Ok(sampler)
+    }
+
+    /// Creates the sampler writing to the given file.
+    pub fn create(
+        sample_file: PathBuf,
+        trigger: SampleTrigger,
+        depth: usize,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Sampling order books to: {:?}, trigger: {:?}, depth: {}",
+            sample_file, trigger, depth
+        );
+        let writer = BufWriter::new(File::create(sample_file)?);
+        Self::new(Box::new(writer), trigger, depth)
+    }
+
+    /// Number of samples taken, each with a row per order book.
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Called before applying an event (snapshot record or incremental message) with
+    /// the given timestamp, samples the order books at the interval multiples before it.
+    pub fn before_event<B: OrderBook>(
+        &mut self,
+        timestamp: u64,
+        order_books: &HashMap<u64, B>,
+    ) -> anyhow::Result<()> {
+        let SampleTrigger::Interval(interval) = self.trigger else {
+            return Ok(());
+        };
+        let mut next_timestamp = *self
+            .next_timestamp
+            .get_or_insert(timestamp.div_ceil(interval) * interval);
+        while timestamp > next_timestamp {
+            self.sample(next_timestamp, order_books)?;
+            next_timestamp += interval;
+        }
+        self.next_timestamp = Some(next_timestamp);
+        Ok(())
+    }
+
+    /// Called after applying an incremental message with the given timestamp.
+    pub fn after_incremental<B: OrderBook>(
+        &mut self,
+        timestamp: u64,
+        order_books: &HashMap<u64, B>,
+    ) -> anyhow::Result<()> {
+        let SampleTrigger::Updates(updates) = self.trigger else {
+            return Ok(());
+        };
+        self.updates += 1;
+        if self.updates == updates {
+            self.updates = 0;
+            self.sample(timestamp, order_books)?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        debug!("Samples taken: {}", self.samples);
+        Ok(())
+    }
+
+    fn sample<B: OrderBook>(
+        &mut self,
+        sample_timestamp: u64,
+        order_books: &HashMap<u64, B>,
+    ) -> anyhow::Result<()> {
+        self.ids.clear();
+        self.ids.extend(order_books.keys());
+        self.ids.sort_unstable();
+        for id in self.ids.iter() {
+            let order_book = &order_books[id];
+            if order_book.timestamp() == 0 {
+                continue;
+            }
+            let tick_size = order_book.tick_size();
+            self.record.clear();
+            self.record.push(sample_timestamp.to_string());
+            self.record.push(order_book.id().to_string());
+            self.record.push(order_book.timestamp().to_string());
+            self.record.push(order_book.seq_no().to_string());
+            self.record.push(
+                match order_book.health() {
+                    BookHealth::Live => "live",
+                    BookHealth::Stale => "stale",
+                    BookHealth::Invalid => "invalid",
+                }
+                .to_string(),
+            );
+            for levels in [order_book.get_bids(), order_book.get_asks()] {
+                for i in 0..self.depth {
+                    match levels.get(i) {
+                        Some((price, qty)) => {
+                            // same formatting as the CSV output, e.g. 100.0
+                            self.record.push(format!("{:?}", tick_size.to_f64(*price)));
+                            self.record.push(qty.to_string());
+                        }
+                        None => {
+                            self.record.push(String::new());
+                            self.record.push(String::new());
+                        }
+                    }
+                }
+            }
+            self.writer.write_record(&self.record)?;
+        }
+        self.samples += 1;
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        let mut header: Vec<String> = ["sample_timestamp", "id", "timestamp", "seq_no", "health"]
+            .iter()
+            .map(|column| column.to_string())
+            .collect();
+        for side in ["bid", "ask"] {
+            for i in 0..self.depth {
+                header.push(format!("{}_price_{}", side, i));
+                header.push(format!("{}_qty_{}", side, i));
+            }
+        }
+        self.writer.write_record(&header)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{btree_orderbook::orderbook::OrderBook, price::TickSize};
+
+    fn order_books(seq_no: u64, qty: u64) -> HashMap<u64, OrderBook> {
+        let tick_size = TickSize::new(0.01);
+        let mut order_book = OrderBook::with_tick_size(1, tick_size);
+        order_book.seq_no = seq_no;
+        order_book.timestamp = seq_no * 10;
+        order_book.add_bid(tick_size.to_price(100.0), qty);
+        order_book.add_bid(tick_size.to_price(99.5), 1);
+        order_book.add_ask(tick_size.to_price(100.5), 2);
+        HashMap::from([(1, order_book)])
+    }
+
+    fn read_lines(file: PathBuf) -> Vec<String> {
+        let lines = std::fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect();
+        std::fs::remove_file(file).unwrap();
+        lines
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}_{}.csv", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_sample_interval() {
+        let file = temp_file("sample_interval");
+        let mut sampler = Sampler::create(file.clone(), SampleTrigger::Interval(100), 2).unwrap();
+        // events at 95, 100, 250 and 310, samples at 100 (including the event at 100),
+        // 200 and 300
+        sampler.before_event(95, &order_books(1, 10)).unwrap();
+        sampler.before_event(100, &order_books(1, 10)).unwrap();
+        sampler.before_event(250, &order_books(2, 20)).unwrap();
+        sampler.before_event(310, &order_books(3, 30)).unwrap();
+        sampler.after_incremental(310, &order_books(4, 40)).unwrap();
+        sampler.flush().unwrap();
+        assert_eq!(sampler.samples(), 3);
+
+        assert_eq!(
+            read_lines(file),
+            vec![
+                "sample_timestamp,id,timestamp,seq_no,health,bid_price_0,bid_qty_0,bid_price_1,\
+                 bid_qty_1,ask_price_0,ask_qty_0,ask_price_1,ask_qty_1",
+                "100,1,20,2,live,100.0,20,99.5,1,100.5,2,,",
+                "200,1,20,2,live,100.0,20,99.5,1,100.5,2,,",
+                "300,1,30,3,live,100.0,30,99.5,1,100.5,2,,",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample_updates() {
+        let file = temp_file("sample_updates");
+        let mut sampler = Sampler::create(file.clone(), SampleTrigger::Updates(2), 1).unwrap();
+        for seq_no in 1..=5 {
+            sampler
+                .before_event(seq_no * 10, &order_books(seq_no, 1))
+                .unwrap();
+            sampler
+                .after_incremental(seq_no * 10, &order_books(seq_no, seq_no))
+                .unwrap();
+        }
+        sampler.flush().unwrap();
+
+        assert_eq!(
+            read_lines(file),
+            vec![
+                "sample_timestamp,id,timestamp,seq_no,health,bid_price_0,bid_qty_0,ask_price_0,\
+                 ask_qty_0",
+                "20,1,20,2,live,100.0,2,100.5,2",
+                "40,1,40,4,live,100.0,4,100.5,2",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample_trigger_must_be_positive() {
+        assert!(Sampler::new(Box::new(vec![]), SampleTrigger::Interval(0), 5).is_err());
+        assert!(Sampler::new(Box::new(vec![]), SampleTrigger::Updates(0), 5).is_err());
+    }
+}
@@ -1,4 +1,15 @@
-use std::mem;
+use std::{io::Read, mem};
+
+use serde::Deserialize;
+
+use crate::orderbook::Side;
+
+pub mod diff;
+pub mod mmap;
+pub mod recovery;
+pub mod tail;
+pub mod validate;
+pub mod writer;
 
 pub const UPDATE_LEVEL_SIZE: usize =
     mem::size_of::<u8>() + mem::size_of::<f64>() + mem::size_of::<u64>(); // 1 byte for side + 8 bytes for price + 8 bytes for qty
@@ -8,6 +19,9 @@ pub const UPDATE_TIMESTAMP_OFFSET: usize = 0;
 pub const UPDATE_SEQ_NO_OFFSET: usize = UPDATE_TIMESTAMP_OFFSET + mem::size_of::<u64>();
 pub const UPDATE_ID_OFFSET: usize = UPDATE_SEQ_NO_OFFSET + mem::size_of::<u64>();
 pub const UPDATE_NUM_UPDATES_OFFSET: usize = UPDATE_ID_OFFSET + mem::size_of::<u64>();
+/// Maximum number of updates of an incremental message, far more than the levels of any order
+/// book, so a larger number is corrupted input rather than a large message.
+pub const MAX_NUM_UPDATES: u64 = 1_000_000;
 
 pub const SNAPSHOT_METADATA_SIZE: usize =  mem::size_of::<u64>() * 3; // 8 bytes for timestamp + 8 bytes for seq_no + 8 bytes for ID
 pub const SNAPSHOT_LEVELS_SIZE: usize = 10*(mem::size_of::<f64>() + mem::size_of::<u64>()); // 8 bytes for price + 8 bytes for qty, 5 per side (bid/ask)
@@ -15,11 +29,145 @@ pub const SNAPSHOT_RECORD_SIZE: usize = SNAPSHOT_METADATA_SIZE + SNAPSHOT_LEVELS
 pub const SNAPSHOT_TIMESTAMP_OFFSET: usize = 0;
 pub const SNAPSHOT_SEQ_NO_OFFSET: usize = SNAPSHOT_TIMESTAMP_OFFSET + mem::size_of::<u64>();
 pub const SNAPSHOT_ID_OFFSET: usize = SNAPSHOT_SEQ_NO_OFFSET + mem::size_of::<u64>();
+pub const SNAPSHOT_LEGACY_LEVELS: usize = 5; // number of (bid, ask) pairs in the legacy format
+
+// Depth snapshot format: metadata followed by the number of bids and asks, bids and asks
+pub const SNAPSHOT_NUM_BIDS_OFFSET: usize = SNAPSHOT_ID_OFFSET + mem::size_of::<u64>();
+pub const SNAPSHOT_NUM_ASKS_OFFSET: usize = SNAPSHOT_NUM_BIDS_OFFSET + mem::size_of::<u64>();
+pub const SNAPSHOT_DEPTH_METADATA_SIZE: usize = SNAPSHOT_METADATA_SIZE + mem::size_of::<u64>() * 2; // 24 bytes for metadata + 8 bytes for number of bids + 8 bytes for number of asks
+pub const SNAPSHOT_LEVEL_SIZE: usize = LEVEL_PRICE_SIZE + LEVEL_QTY_SIZE; // 8 bytes for price + 8 bytes for qty
+/// Maximum number of levels per side of a depth snapshot record, far more than the levels of any
+/// order book, so a larger number is corrupted input, which must not be allocated for.
+pub const MAX_SNAPSHOT_LEVELS: u64 = 1_000_000;
 
 pub const LEVEL_PRICE_SIZE: usize = mem::size_of::<f64>();
 pub const LEVEL_QTY_SIZE: usize = mem::size_of::<u64>();
 pub const LEVEL_SIDE_SIZE: usize = mem::size_of::<u8>();
 
+/// Layout of the snapshot records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotFormat {
+    /// Fixed size records with 5 (bid, ask) pairs of levels, see `SNAPSHOT_RECORD_SIZE`.
+    #[default]
+    Legacy,
+    /// Variable size records, which carry the number of levels per side:
+    /// - 8 bytes for timestamp (u64)
+    /// - 8 bytes for sequence number (u64)
+    /// - 8 bytes for ID (u64)
+    /// - 8 bytes for number of bids (u64)
+    /// - 8 bytes for number of asks (u64)
+    /// - for each bid and then for each ask 8 bytes for price (f64) and 8 bytes for qty (u64)
+    Depth,
+}
+
+/// How the snapshot and incremental files are read.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputMode {
+    /// Files are read through buffered readers, which also work for pipes and growing files.
+    #[default]
+    Buffered,
+    /// Files are memory mapped and the records are decoded in place, see `mmap`.
+    /// The files must not change while they are read.
+    Mmap,
+}
+
+/// Returns the size of the snapshot record at the start of the buffer.
+/// For depth format the buffer must contain at least the record metadata,
+/// otherwise Error::BufferTooSmall is returned. The numbers of levels are read from the input,
+/// so Error::InvalidData is returned if either is larger than `MAX_SNAPSHOT_LEVELS`.
+pub fn snapshot_record_size(buf: &[u8], format: SnapshotFormat) -> Result<usize, Error> {
+    match format {
+        SnapshotFormat::Legacy => Ok(SNAPSHOT_RECORD_SIZE),
+        SnapshotFormat::Depth => {
+            if buf.len() < SNAPSHOT_DEPTH_METADATA_SIZE {
+                return Err(Error::BufferTooSmall);
+            }
+            let num_bids = read_u64_le(buf, SNAPSHOT_NUM_BIDS_OFFSET);
+            let num_asks = read_u64_le(buf, SNAPSHOT_NUM_ASKS_OFFSET);
+            let invalid = || {
+                Error::InvalidData(format!(
+                    "Invalid number of snapshot levels, bids: {}, asks: {}",
+                    num_bids, num_asks
+                ))
+            };
+            if num_bids > MAX_SNAPSHOT_LEVELS || num_asks > MAX_SNAPSHOT_LEVELS {
+                return Err(invalid());
+            }
+            num_bids
+                .checked_add(num_asks)
+                .and_then(|levels| levels.checked_mul(SNAPSHOT_LEVEL_SIZE as u64))
+                .and_then(|size| size.checked_add(SNAPSHOT_DEPTH_METADATA_SIZE as u64))
+                .and_then(|size| usize::try_from(size).ok())
+                .ok_or_else(invalid)
+        }
+    }
+}
+
+/// Reads the next snapshot record of the given format from the reader into the buffer,
+/// resizing the buffer to the record size.
+/// Returns false if the end of the reader is reached before the start of the record.
+pub fn read_snapshot_record<R: Read>(
+    reader: &mut R,
+    format: SnapshotFormat,
+    buf: &mut Vec<u8>,
+) -> anyhow::Result<bool> {
+    match format {
+        SnapshotFormat::Legacy => {
+            buf.resize(SNAPSHOT_RECORD_SIZE, 0);
+            Ok(reader.read_exact(buf).is_ok())
+        }
+        SnapshotFormat::Depth => {
+            buf.resize(SNAPSHOT_DEPTH_METADATA_SIZE, 0);
+            if reader.read_exact(buf).is_err() {
+                return Ok(false);
+            }
+            let size = snapshot_record_size(buf, format)?;
+            buf.resize(size, 0);
+            reader.read_exact(&mut buf[SNAPSHOT_DEPTH_METADATA_SIZE..])?;
+            Ok(true)
+        }
+    }
+}
+
+/// Reads the next incremental message from the reader into the buffer, resizing the buffer to
+/// the message size.
+/// Returns false if the end of the reader is reached before the start of the message.
+pub fn read_incremental_message<R: Read>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> anyhow::Result<bool> {
+    buf.resize(UPDATE_METADATA_SIZE, 0);
+    if reader.read_exact(buf).is_err() {
+        return Ok(false);
+    }
+    let size = incremental_message_size(read_u64_le(buf, UPDATE_NUM_UPDATES_OFFSET))?;
+    buf.resize(size, 0);
+    reader.read_exact(&mut buf[UPDATE_METADATA_SIZE..])?;
+    Ok(true)
+}
+
+/// Returns the size of the incremental message with the given number of updates.
+/// The number of updates is read from the input, so Error::InvalidNumUpdates is returned if it's
+/// larger than `MAX_NUM_UPDATES`, instead of waiting for a message that never ends or wrapping
+/// around the size and passing the buffer size checks of the readers.
+pub fn incremental_message_size(num_updates: u64) -> Result<usize, Error> {
+    if num_updates > MAX_NUM_UPDATES {
+        return Err(Error::InvalidNumUpdates(
+            num_updates,
+            UPDATE_NUM_UPDATES_OFFSET,
+        ));
+    }
+    Ok(UPDATE_METADATA_SIZE + num_updates as usize * UPDATE_LEVEL_SIZE)
+}
+
+pub(crate) fn read_u64_le(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; mem::size_of::<u64>()];
+    bytes.copy_from_slice(&buf[offset..offset + mem::size_of::<u64>()]);
+    u64::from_le_bytes(bytes)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Order book with ID {0} not found")]
@@ -30,5 +178,20 @@ pub enum Error {
     InvalidData(String),
     #[error("Gap detected in incremental updates for order book ID {0}")]
     GapDetected(u64, usize),
+    #[error("Order book with ID {0} is crossed or locked after update {1}")]
+    CrossedBook(u64, u64),
+    // The errors of the field values, see `validate`, carry the byte offset of the field
+    // within the incremental message or the snapshot record.
+    #[error("Invalid side {0} at offset {1}")]
+    InvalidSide(u8, usize),
+    #[error("Invalid price {0} at offset {1}")]
+    InvalidPrice(f64, usize),
+    #[error("Price {0} at offset {1} is not on the tick grid")]
+    OffTickPrice(f64, usize),
+    #[error("Invalid number of updates {0} at offset {1}")]
+    InvalidNumUpdates(u64, usize),
+    /// A zero qty update of a level, which doesn't exist, the offset is of the level update.
+    #[error("Removing {0:?} level {1}, which doesn't exist, at offset {2}")]
+    UnknownLevel(Side, f64, usize),
 }
 
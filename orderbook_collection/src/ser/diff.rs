@@ -0,0 +1,256 @@
+use std::iter::Peekable;
+
+use anyhow::bail;
+
+use crate::{
+    orderbook::{OrderBook, Side},
+    price::{Price, TickSize},
+    ser::writer::{IncrementalMessage, LevelUpdate},
+};
+
+/// Returns the minimal level updates, which turn the `from` order book into the `to` order book:
+/// an update with the new qty for each level, which was added or whose qty changed, and an update
+/// with zero qty for each level, which was removed. Levels with the same qty are skipped.
+/// Bids come before asks and the updates of each side are sorted by distance to mid.
+///
+/// Both order books must be of the same instrument, i.e. have the same ID and tick size, but can
+/// be of different implementations, e.g. a rebuilt array based order book and a BTreeMap based
+/// order book read from a later snapshot.
+pub fn diff_levels<A: OrderBook, B: OrderBook>(
+    from: &A,
+    to: &B,
+) -> anyhow::Result<Vec<LevelUpdate>> {
+    if from.id() != to.id() {
+        bail!(
+            "Can't diff order books of different instruments: {} and {}",
+            from.id(),
+            to.id()
+        );
+    }
+    let tick_size = to.tick_size();
+    if from.tick_size() != tick_size {
+        bail!(
+            "Can't diff order books with different tick sizes: {} and {}",
+            from.tick_size().as_f64(),
+            tick_size.as_f64()
+        );
+    }
+    let mut updates = vec![];
+    diff_side(
+        Side::Bid,
+        from.iter_bids().peekable(),
+        to.iter_bids().peekable(),
+        tick_size,
+        &mut updates,
+    );
+    diff_side(
+        Side::Ask,
+        from.iter_asks().peekable(),
+        to.iter_asks().peekable(),
+        tick_size,
+        &mut updates,
+    );
+    Ok(updates)
+}
+
+/// Returns the incremental message with the level updates from `diff_levels` and the timestamp,
+/// sequence number and ID of the `to` order book, which can be written with
+/// `writer::write_incremental_message`.
+/// Note the incremental readers apply the message only if its sequence number follows the one of
+/// the order book, otherwise it's a gap (see `recovery::GapRecovery`), so for a replay `to` should
+/// be one sequence number ahead of `from`, or the sequence number of the message should be set.
+pub fn diff_message<A: OrderBook, B: OrderBook>(
+    from: &A,
+    to: &B,
+) -> anyhow::Result<IncrementalMessage> {
+    Ok(IncrementalMessage {
+        timestamp: to.timestamp(),
+        seq_no: to.seq_no(),
+        id: to.id(),
+        updates: diff_levels(from, to)?,
+    })
+}
+
+/// Merges the levels of a side of both order books, which are sorted from the best one.
+fn diff_side(
+    side: Side,
+    mut from: Peekable<impl Iterator<Item = (Price, u64)>>,
+    mut to: Peekable<impl Iterator<Item = (Price, u64)>>,
+    tick_size: TickSize,
+    updates: &mut Vec<LevelUpdate>,
+) {
+    let is_better = |price: Price, other: Price| match side {
+        Side::Bid => price > other,
+        Side::Ask => price < other,
+    };
+    loop {
+        let (price, qty) = match (from.peek().copied(), to.peek().copied()) {
+            (None, None) => break,
+            (Some((from_price, _)), None) => {
+                from.next();
+                (from_price, 0)
+            }
+            (None, Some(level)) => {
+                to.next();
+                level
+            }
+            (Some((from_price, from_qty)), Some((to_price, to_qty))) => {
+                if from_price == to_price {
+                    from.next();
+                    to.next();
+                    if from_qty == to_qty {
+                        continue;
+                    }
+                    (to_price, to_qty)
+                } else if is_better(from_price, to_price) {
+                    // the level of `from` is not in `to`
+                    from.next();
+                    (from_price, 0)
+                } else {
+                    to.next();
+                    (to_price, to_qty)
+                }
+            }
+        };
+        updates.push(LevelUpdate {
+            side,
+            price: tick_size.to_f64(price),
+            qty,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::{CrossPolicy, OrderBookReader},
+        ser::writer::write_incremental_message,
+    };
+
+    fn btree_order_book(
+        seq_no: u64,
+        bids: &[(f64, u64)],
+        asks: &[(f64, u64)],
+    ) -> btree_orderbook::orderbook::OrderBook {
+        let tick_size = TickSize::new(0.01);
+        let mut order_book = btree_orderbook::orderbook::OrderBook::with_tick_size(1, tick_size);
+        order_book.seq_no = seq_no;
+        order_book.timestamp = seq_no * 10;
+        for (price, qty) in bids {
+            order_book.add_bid(tick_size.to_price(*price), *qty);
+        }
+        for (price, qty) in asks {
+            order_book.add_ask(tick_size.to_price(*price), *qty);
+        }
+        order_book
+    }
+
+    fn array_order_book(
+        seq_no: u64,
+        bids: &[(f64, u64)],
+        asks: &[(f64, u64)],
+    ) -> Box<array_orderbook::orderbook::OrderBook> {
+        let mut order_book = Box::new(array_orderbook::orderbook::OrderBook::new(
+            OrderBookConfig {
+                id: 1,
+                min_price: 90.0,
+                max_price: 110.0,
+                tick_size: 0.01,
+                recenter: false,
+                cross_policy: CrossPolicy::Warn,
+            },
+        ));
+        order_book.init();
+        order_book.seq_no = seq_no;
+        order_book.timestamp = seq_no * 10;
+        let tick_size = order_book.tick_size();
+        for (price, qty) in bids {
+            order_book
+                .add_bid(tick_size.to_price(*price), *qty)
+                .unwrap();
+        }
+        for (price, qty) in asks {
+            order_book
+                .add_ask(tick_size.to_price(*price), *qty)
+                .unwrap();
+        }
+        order_book
+    }
+
+    const FROM_BIDS: &[(f64, u64)] = &[(100.1, 4), (100.05, 20), (100.0, 10)];
+    const FROM_ASKS: &[(f64, u64)] = &[(101.0, 5), (101.1, 2)];
+    const TO_BIDS: &[(f64, u64)] = &[(100.2, 1), (100.1, 4), (100.0, 15)];
+    const TO_ASKS: &[(f64, u64)] = &[(101.1, 2), (101.2, 3)];
+
+    fn update(side: Side, price: f64, qty: u64) -> LevelUpdate {
+        LevelUpdate { side, price, qty }
+    }
+
+    #[test]
+    fn test_diff_levels() {
+        let from = btree_order_book(1, FROM_BIDS, FROM_ASKS);
+        let to = btree_order_book(2, TO_BIDS, TO_ASKS);
+        let expected = vec![
+            update(Side::Bid, 100.2, 1),
+            update(Side::Bid, 100.05, 0),
+            update(Side::Bid, 100.0, 15),
+            update(Side::Ask, 101.0, 0),
+            update(Side::Ask, 101.2, 3),
+        ];
+        assert_eq!(diff_levels(&from, &to).unwrap(), expected);
+        // the same between implementations
+        let array_from = array_order_book(1, FROM_BIDS, FROM_ASKS);
+        assert_eq!(diff_levels(&array_from, &to).unwrap(), expected);
+
+        assert_eq!(diff_levels(&to, &to).unwrap(), vec![]);
+        let empty = btree_order_book(0, &[], &[]);
+        assert_eq!(
+            diff_levels(&empty, &from).unwrap(),
+            vec![
+                update(Side::Bid, 100.1, 4),
+                update(Side::Bid, 100.05, 20),
+                update(Side::Bid, 100.0, 10),
+                update(Side::Ask, 101.0, 5),
+                update(Side::Ask, 101.1, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_different_instruments() {
+        let from = btree_order_book(1, FROM_BIDS, FROM_ASKS);
+        let mut to = btree_order_book(2, TO_BIDS, TO_ASKS);
+        to.tick_size = TickSize::new(0.05);
+        assert!(diff_levels(&from, &to).is_err());
+        to.tick_size = from.tick_size;
+        to.id = 2;
+        assert!(diff_message(&from, &to).is_err());
+    }
+
+    #[test]
+    fn test_apply_diff_message() {
+        let from = array_order_book(1, FROM_BIDS, FROM_ASKS);
+        let to = array_order_book(2, TO_BIDS, TO_ASKS);
+        let message = diff_message(&from, &to).unwrap();
+        assert_eq!((message.id, message.seq_no, message.timestamp), (1, 2, 20));
+        let mut buf = vec![];
+        write_incremental_message(&mut buf, &message).unwrap();
+
+        let mut order_books = HashMap::from([(1, from)]);
+        let size = <Box<array_orderbook::orderbook::OrderBook>>::apply_incremental_message(
+            &buf,
+            &mut order_books,
+        )
+        .unwrap();
+        assert_eq!(size, buf.len());
+        let order_book = &order_books[&1];
+        assert_eq!(order_book.get_bids(), to.get_bids());
+        assert_eq!(order_book.get_asks(), to.get_asks());
+        assert_eq!(order_book.seq_no, 2);
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Cursor,
+    path::PathBuf,
+};
+
+use anyhow::bail;
+use memmap2::Mmap;
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    orderbook::OrderBookReader,
+    ser::{recovery::GapRecovery, snapshot_record_size, Error, SnapshotFormat},
+};
+
+/// Memory maps the whole file for reading.
+/// The file must not be truncated or modified while it's mapped, so only complete files are
+/// mapped, pipes and growing files are read with the buffered readers (see `tail::TailReader`).
+pub fn map_file(path: PathBuf) -> anyhow::Result<Mmap> {
+    let file = File::open(&path)?;
+    // SAFETY: the mapping is read only and the file is expected not to change while it's read,
+    // see above
+    Ok(unsafe { Mmap::map(&file)? })
+}
+
+/// Memory maps the file and returns a reader of it at the offset, which must be within the file,
+/// e.g. the offset saved in a checkpoint.
+pub fn open_at(path: PathBuf, offset: u64) -> anyhow::Result<Cursor<Mmap>> {
+    let mmap = map_file(path.clone())?;
+    if offset > mmap.len() as u64 {
+        bail!(
+            "Offset {} is past the end of file {:?} of {} bytes",
+            offset,
+            path,
+            mmap.len()
+        );
+    }
+    let mut reader = Cursor::new(mmap);
+    reader.set_position(offset);
+    Ok(reader)
+}
+
+/// Reads the memory mapped snapshot file into the order books of the implementation, the records
+/// are decoded in place, without copying them into a buffer.
+pub fn read_snapshot_file<B: OrderBookReader>(
+    snapshot_file: PathBuf,
+    config: &Config,
+) -> anyhow::Result<HashMap<u64, B>> {
+    info!("Reading memory mapped snapshot file: {:?}", snapshot_file);
+    let mmap = map_file(snapshot_file)?;
+    let mut order_books = B::init_order_books(config);
+    apply_snapshot_records(&mmap, &mut order_books, config, &mut GapRecovery::new())?;
+    Ok(order_books)
+}
+
+/// Reads the memory mapped incremental file and applies the messages to the order books in
+/// place, so a message of any size is read, unlike with a fixed size buffer.
+/// See `apply_incremental_messages` for the exceptions.
+pub fn read_incremental_file<B: OrderBookReader>(
+    incremental_file: PathBuf,
+    order_books: &mut HashMap<u64, B>,
+    recovery: Option<&mut GapRecovery>,
+) -> anyhow::Result<()> {
+    info!(
+        "Reading memory mapped incremental file: {:?}",
+        incremental_file
+    );
+    let mmap = map_file(incremental_file)?;
+    apply_incremental_messages(&mmap, order_books, recovery)
+}
+
+/// Applies all the snapshot records of the buffer, e.g. of a memory mapped file, to the order
+/// books. Same as with the buffered readers, a partial record at the end of the buffer is ignored,
+/// apart from a depth record with incomplete levels, which is an error.
+pub fn apply_snapshot_records<B: OrderBookReader>(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, B>,
+    config: &Config,
+    recovery: &mut GapRecovery,
+) -> anyhow::Result<()> {
+    let mut offset = 0;
+    while offset < buf.len() {
+        let record = &buf[offset..];
+        let size = match snapshot_record_size(record, config.snapshot_format) {
+            Ok(size) => size,
+            Err(Error::BufferTooSmall) => break,
+            Err(e) => bail!(e),
+        };
+        if record.len() < size {
+            if config.snapshot_format == SnapshotFormat::Depth {
+                bail!("Truncated snapshot record at offset {}", offset);
+            }
+            break;
+        }
+        B::apply_snapshot_record(&record[..size], order_books, config, recovery)?;
+        offset += size;
+    }
+    Ok(())
+}
+
+/// Applies all the incremental messages of the buffer, e.g. of a memory mapped file, to the
+/// order books.
+/// Exceptions:
+/// * If the order book with the given ID does not exist, an error is returned.
+/// * If invalid data, an invalid field value (see `ser::validate`) or a truncated message is
+///   encountered, an error is returned.
+/// * If an order book is crossed or locked with `CrossPolicy::Fail`, an error is returned.
+///
+/// * If a gap is detected, the order book is marked stale and its updates are buffered in the
+///   recovery until the order book is re-synced from a snapshot. Without a recovery, the updates
+///   are skipped.
+pub fn apply_incremental_messages<B: OrderBookReader>(
+    buf: &[u8],
+    order_books: &mut HashMap<u64, B>,
+    mut recovery: Option<&mut GapRecovery>,
+) -> anyhow::Result<()> {
+    let mut offset = 0;
+    let mut stale_ids = HashSet::new();
+    while offset < buf.len() {
+        match B::apply_incremental_message(&buf[offset..], order_books) {
+            Ok(size) => offset += size,
+            Err(Error::GapDetected(id, size)) => {
+                if let Some(recovery) = recovery.as_deref_mut() {
+                    if recovery.buffered_size(id) == 0 {
+                        warn!(
+                            "Gap detected in incremental updates for order book ID {}",
+                            id
+                        );
+                    }
+                    recovery.buffer(id, &buf[offset..offset + size]);
+                } else if stale_ids.insert(id) {
+                    warn!(
+                        "Gap detected in incremental updates for order book ID {}, skipping its updates",
+                        id
+                    );
+                }
+                offset += size;
+            }
+            Err(Error::OrderBookNotFound(id)) => bail!("Order book with ID {} not found", id),
+            Err(Error::BufferTooSmall) => {
+                bail!("Truncated incremental message at offset {}", offset)
+            }
+            Err(Error::InvalidData(msg)) => bail!("Invalid incremental update data: {}", msg),
+            Err(e @ Error::CrossedBook(..)) => bail!(e),
+            Err(
+                e @ (Error::InvalidSide(..)
+                | Error::InvalidPrice(..)
+                | Error::OffTickPrice(..)
+                | Error::InvalidNumUpdates(..)
+                | Error::UnknownLevel(..)),
+            ) => bail!("Invalid incremental message at offset {}: {}", offset, e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::{BookHealth, CrossPolicy},
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+                Side, SnapshotRecord,
+            },
+            InputMode,
+        },
+    };
+
+    fn config(snapshot_format: SnapshotFormat) -> Config {
+        Config {
+            instruments: HashMap::from([(
+                1,
+                OrderBookConfig {
+                    id: 1,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                },
+            )]),
+            incremental_buffer_size: 64,
+            snapshot_format,
+            input: InputMode::Mmap,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    fn temp_file(name: &str, buf: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.bin", name, std::process::id()));
+        std::fs::write(&path, buf).unwrap();
+        path
+    }
+
+    fn snapshots(format: SnapshotFormat) -> Vec<u8> {
+        let mut buf = vec![];
+        for seq_no in [1, 3] {
+            let record = SnapshotRecord {
+                timestamp: seq_no * 1000,
+                seq_no,
+                id: 1,
+                bids: vec![(100.0, seq_no), (99.5, 2)],
+                asks: vec![(101.0, 5)],
+            };
+            write_snapshot_record(&mut buf, &record, format).unwrap();
+        }
+        buf
+    }
+
+    fn incrementals() -> Vec<u8> {
+        let mut buf = vec![];
+        for (seq_no, num_updates) in [(4, 1), (5, 0), (6, 40), (8, 1)] {
+            let message = IncrementalMessage {
+                timestamp: seq_no * 1000,
+                seq_no,
+                id: 1,
+                updates: (0..num_updates)
+                    .map(|i| LevelUpdate {
+                        side: Side::Ask,
+                        price: 101.0 + i as f64 / 10.0,
+                        qty: seq_no,
+                    })
+                    .collect(),
+            };
+            write_incremental_message(&mut buf, &message).unwrap();
+        }
+        buf
+    }
+
+    fn test_read_mmap<B: OrderBookReader>() {
+        for format in [SnapshotFormat::Legacy, SnapshotFormat::Depth] {
+            let config = config(format);
+            let snapshot_file =
+                temp_file(&format!("mmap_snapshot_{:?}", format), &snapshots(format));
+            // the message with 40 updates is larger than the incremental buffer size
+            let incremental_file = temp_file("mmap_incremental", &incrementals());
+
+            let mut order_books = B::read_snapshot_file(snapshot_file.clone(), &config).unwrap();
+            let order_book = &order_books[&1];
+            let tick_size = order_book.tick_size();
+            assert_eq!(order_book.seq_no(), 3);
+            assert_eq!(order_book.best_bid(), Some((tick_size.to_price(100.0), 3)));
+            B::read_incremental_file(incremental_file.clone(), &mut order_books, &config).unwrap();
+            let order_book = &order_books[&1];
+            // the last message is behind a gap
+            assert_eq!(order_book.seq_no(), 6);
+            assert_eq!(order_book.health(), BookHealth::Stale);
+            assert_eq!(order_book.iter_asks().count(), 40);
+            assert_eq!(
+                order_book.qty_up_to(Side::Ask, tick_size.to_price(110.0)),
+                240
+            );
+
+            // the same as the buffered readers with a buffer large enough
+            let buffered_config = Config {
+                input: InputMode::Buffered,
+                incremental_buffer_size: 1024,
+                ..config.clone()
+            };
+            let mut buffered_books =
+                B::read_snapshot_file(snapshot_file.clone(), &buffered_config).unwrap();
+            B::read_incremental_file(
+                incremental_file.clone(),
+                &mut buffered_books,
+                &buffered_config,
+            )
+            .unwrap();
+            assert_eq!(
+                crate::output::OrderBookOutput::from_order_book(&buffered_books[&1]),
+                crate::output::OrderBookOutput::from_order_book(order_book)
+            );
+            std::fs::remove_file(snapshot_file).unwrap();
+            std::fs::remove_file(incremental_file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_mmap_btree() {
+        test_read_mmap::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_read_mmap_array() {
+        test_read_mmap::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    #[test]
+    fn test_truncated_records() {
+        let config = config(SnapshotFormat::Depth);
+        let mut order_books = btree_orderbook::orderbook::OrderBook::init_order_books(&config);
+        let mut recovery = GapRecovery::new();
+        let buf = snapshots(SnapshotFormat::Depth);
+        assert!(apply_snapshot_records(
+            &buf[..buf.len() - 1],
+            &mut order_books,
+            &config,
+            &mut recovery
+        )
+        .is_err());
+        let buf = incrementals();
+        assert!(apply_incremental_messages(
+            &buf[..buf.len() - 1],
+            &mut order_books,
+            Some(&mut recovery)
+        )
+        .is_err());
+
+        // a partial legacy record at the end is ignored, the same as with the buffered reader
+        let config = Config {
+            snapshot_format: SnapshotFormat::Legacy,
+            ..config
+        };
+        let buf = snapshots(SnapshotFormat::Legacy);
+        apply_snapshot_records(
+            &buf[..buf.len() - 1],
+            &mut order_books,
+            &config,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_eq!(order_books[&1].seq_no, 1);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let config = config(SnapshotFormat::Legacy);
+        let file = temp_file("mmap_empty", &[]);
+        let mut order_books =
+            read_snapshot_file::<btree_orderbook::orderbook::OrderBook>(file.clone(), &config)
+                .unwrap();
+        assert!(order_books.is_empty());
+        read_incremental_file(file.clone(), &mut order_books, None).unwrap();
+        assert!(open_at(file.clone(), 1).is_err());
+        std::fs::remove_file(file).unwrap();
+    }
+}
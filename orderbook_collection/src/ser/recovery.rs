@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use tracing::{debug, info, warn};
+
+use crate::ser::Error;
+
+/// Recovery of order books from gaps in the incremental updates.
+/// When a gap is detected, the order book is marked stale (see `orderbook::BookHealth`) and its
+/// incremental messages are buffered here, as the order book can't be updated until it's re-synced
+/// from a snapshot. Once the snapshot is applied, the buffered messages are replayed: messages
+/// older than the snapshot are skipped, newer ones are applied and, if there's still a gap after
+/// the snapshot, the order book is marked stale again and the rest of the messages are buffered
+/// again.
+///
+/// Messages are buffered as raw bytes of the incremental format, so buffering doesn't decode them
+/// and doesn't allocate per message. The buffer of an order book grows until a snapshot for it
+/// is received or until it exceeds the limit, see `buffer`.
+#[derive(Debug)]
+pub struct GapRecovery {
+    buffers: HashMap<u64, Vec<u8>>,
+    limit: usize,
+}
+
+/// Default limit of the buffered messages per order book in bytes.
+pub const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
+impl Default for GapRecovery {
+    fn default() -> Self {
+        Self::with_limit(DEFAULT_BUFFER_LIMIT)
+    }
+}
+
+impl GapRecovery {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates the recovery, which buffers at most `limit` bytes of messages per order book.
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            limit,
+        }
+    }
+
+    /// Buffers the incremental message of the stale order book.
+    /// If the buffer of the order book would exceed the limit, the buffered messages are dropped
+    /// and only the later ones are buffered. The order book stays stale until a snapshot newer
+    /// than the dropped messages re-syncs it, the replay of the later messages detects the gap.
+    pub fn buffer(&mut self, id: u64, message: &[u8]) {
+        let buf = self.buffers.entry(id).or_default();
+        if buf.len() + message.len() > self.limit {
+            warn!(
+                "Dropping {} buffered bytes of order book ID {}, the limit of {} bytes is exceeded",
+                buf.len(),
+                id,
+                self.limit
+            );
+            // release the memory of the dropped messages
+            *buf = Vec::new();
+            if message.len() > self.limit {
+                self.buffers.remove(&id);
+                return;
+            }
+        }
+        buf.extend_from_slice(message);
+    }
+
+    /// Number of bytes buffered for the order book.
+    pub fn buffered_size(&self, id: u64) -> usize {
+        self.buffers.get(&id).map_or(0, |buf| buf.len())
+    }
+
+    /// Buffered messages of the order book.
+    pub fn buffered(&self, id: u64) -> &[u8] {
+        self.buffers.get(&id).map_or(&[], |buf| buf.as_slice())
+    }
+
+    /// IDs of the order books with buffered messages.
+    pub fn buffered_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.buffers.keys().copied()
+    }
+
+    /// Replays the buffered messages of the order book after it was re-synced from a snapshot,
+    /// using the incremental reader of the order book implementation, e.g.
+    /// `recovery.replay(id, |buf| incremental::read(buf, &mut order_books))`.
+    /// Messages, which are still behind a gap, are buffered again.
+    /// Returns the number of replayed bytes.
+    pub fn replay<F>(&mut self, id: u64, mut read: F) -> Result<usize, Error>
+    where
+        F: FnMut(&[u8]) -> Result<usize, Error>,
+    {
+        let Some(buf) = self.buffers.remove(&id) else {
+            return Ok(0);
+        };
+        debug!(
+            "Replaying {} buffered bytes of order book ID {}",
+            buf.len(),
+            id
+        );
+        let mut offset = 0;
+        while offset < buf.len() {
+            match read(&buf[offset..]) {
+                Ok(size) => offset += size,
+                Err(Error::GapDetected(_, size)) => {
+                    self.buffer(id, &buf[offset..offset + size]);
+                    offset += size;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if self.buffered_size(id) == 0 {
+            info!("Order book ID {} re-synced from snapshot", id);
+        }
+        Ok(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::{BookHealth, CrossPolicy, OrderBook},
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+                Side, SnapshotRecord,
+            },
+            SnapshotFormat,
+        },
+    };
+
+    fn config() -> OrderBookConfig {
+        OrderBookConfig {
+            id: 1,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        }
+    }
+
+    fn snapshot(seq_no: u64, bid: (f64, u64)) -> Vec<u8> {
+        let record = SnapshotRecord {
+            timestamp: seq_no,
+            seq_no,
+            id: 1,
+            bids: vec![bid],
+            asks: vec![(101.0, 5)],
+        };
+        let mut buf = vec![];
+        write_snapshot_record(&mut buf, &record, SnapshotFormat::Legacy).unwrap();
+        buf
+    }
+
+    /// Writes incremental messages with the given sequence numbers, each updating the bid at 100.0
+    /// to qty of 10 * seq_no.
+    fn write_incremental_file(name: &str, seq_nos: &[u64]) -> PathBuf {
+        let mut buf = vec![];
+        for seq_no in seq_nos {
+            let message = IncrementalMessage {
+                timestamp: *seq_no,
+                seq_no: *seq_no,
+                id: 1,
+                updates: vec![LevelUpdate {
+                    side: Side::Bid,
+                    price: 100.0,
+                    qty: 10 * seq_no,
+                }],
+            };
+            write_incremental_message(&mut buf, &message).unwrap();
+        }
+        let file = std::env::temp_dir().join(format!("{}_{}.bin", name, std::process::id()));
+        std::fs::write(&file, buf).unwrap();
+        file
+    }
+
+    fn assert_book<B: OrderBook>(order_book: &B, health: BookHealth, seq_no: u64, qty: u64) {
+        assert_eq!(order_book.health(), health);
+        assert_eq!(order_book.seq_no(), seq_no);
+        assert_eq!(order_book.best_bid().map(|(_, qty)| qty), Some(qty));
+    }
+
+    #[test]
+    fn test_btree_gap_recovery() {
+        let configs = HashMap::from([(1, config())]);
+        let mut order_books = HashMap::new();
+        let mut recovery = GapRecovery::new();
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(1, (100.0, 1)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+
+        // 3 is missing
+        let file = write_incremental_file("recovery_btree", &[2, 4, 5, 6]);
+        btree_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut order_books,
+            64,
+            Some(&mut recovery),
+        )
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+        assert_book(&order_books[&1], BookHealth::Stale, 2, 20);
+        assert!(recovery.buffered_size(1) > 0);
+
+        // snapshot older than the gap doesn't fill it, the book stays stale
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(2, (100.0, 20)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_book(&order_books[&1], BookHealth::Stale, 2, 20);
+
+        // snapshot at 4 re-syncs the book and 5, 6 are replayed, 4 is re-applied
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(4, (100.0, 40)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_book(&order_books[&1], BookHealth::Live, 6, 60);
+        assert_eq!(recovery.buffered_size(1), 0);
+    }
+
+    #[test]
+    fn test_array_gap_recovery() {
+        let mut order_books = HashMap::new();
+        let mut order_book = Box::new(array_orderbook::orderbook::OrderBook::new(config()));
+        order_book.init();
+        order_books.insert(1, order_book);
+        let mut recovery = GapRecovery::new();
+        array_orderbook::ser::apply_snapshot_record(
+            &snapshot(1, (100.0, 1)),
+            &mut order_books,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+
+        let file = write_incremental_file("recovery_array", &[2, 4, 5, 6]);
+        array_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut order_books,
+            64,
+            Some(&mut recovery),
+        )
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+        assert_book(&order_books[&1], BookHealth::Stale, 2, 20);
+
+        // snapshot at 3 fills the gap, the bid qty of the snapshot is overwritten by the updates
+        array_orderbook::ser::apply_snapshot_record(
+            &snapshot(3, (99.0, 30)),
+            &mut order_books,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_book(&order_books[&1], BookHealth::Live, 6, 60);
+        assert_eq!(order_books[&1].get_bids().len(), 2);
+        assert_eq!(recovery.buffered_size(1), 0);
+    }
+
+    #[test]
+    fn test_gap_recovery_buffer_limit() {
+        let configs = HashMap::from([(1, config())]);
+        let mut order_books = HashMap::new();
+        let file = write_incremental_file("recovery_limit", &[2, 4, 5, 6]);
+        let message_size = std::fs::metadata(&file).unwrap().len() as usize / 4;
+        // 4 and 5 are buffered, 6 exceeds the limit and drops them
+        let mut recovery = GapRecovery::with_limit(2 * message_size);
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(1, (100.0, 1)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        btree_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut order_books,
+            64,
+            Some(&mut recovery),
+        )
+        .unwrap();
+        std::fs::remove_file(file).unwrap();
+        assert_book(&order_books[&1], BookHealth::Stale, 2, 20);
+        assert_eq!(recovery.buffered_size(1), message_size);
+
+        // 5 was dropped, so the snapshot at 4 doesn't re-sync the book
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(4, (100.0, 40)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_eq!(order_books[&1].health(), BookHealth::Stale);
+        assert_eq!(recovery.buffered_size(1), message_size);
+
+        btree_orderbook::ser::apply_snapshot_record(
+            &snapshot(5, (100.0, 50)),
+            &mut order_books,
+            &configs,
+            SnapshotFormat::Legacy,
+            &mut recovery,
+        )
+        .unwrap();
+        assert_book(&order_books[&1], BookHealth::Live, 6, 60);
+        assert_eq!(recovery.buffered_size(1), 0);
+
+        // a message larger than the limit isn't buffered at all
+        let mut recovery = GapRecovery::with_limit(message_size - 1);
+        recovery.buffer(1, &vec![0; message_size]);
+        assert_eq!(recovery.buffered_size(1), 0);
+        assert_eq!(recovery.buffered_ids().count(), 0);
+    }
+
+    #[test]
+    fn test_replay_without_buffered_messages() {
+        let mut recovery = GapRecovery::new();
+        assert_eq!(recovery.replay(1, |_| unreachable!()).unwrap(), 0);
+    }
+}
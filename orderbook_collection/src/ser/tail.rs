@@ -0,0 +1,300 @@
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::ser::{read_u64_le, UPDATE_LEVEL_SIZE, UPDATE_METADATA_SIZE, UPDATE_NUM_UPDATES_OFFSET};
+
+/// Default interval of checking the incremental file for new data.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reader of a growing incremental file, like `tail -f`.
+/// At the end of the file it waits for new data instead of returning end of file, until no data
+/// is appended for the idle timeout (if set).
+///
+/// The reader is aware of the incremental message boundaries and returns only complete messages,
+/// a partial trailing message, which is still being written, is held back until it's complete.
+/// If the file is truncated or rotated (i.e. the path refers to a new file), the reader continues
+/// from the start of the new content and a held back partial message of the old content
+/// is dropped.
+pub struct TailReader {
+    path: PathBuf,
+    file: File,
+    file_id: Option<(u64, u64)>,
+    // number of bytes read from the current file
+    position: u64,
+    buf: Vec<u8>,
+    buffer_size: usize,
+    // start of the bytes not returned yet
+    start: usize,
+    // end of the complete messages in the buffer
+    complete: usize,
+    poll_interval: Duration,
+    idle_timeout: Option<Duration>,
+}
+
+impl TailReader {
+    pub fn open(path: PathBuf, buffer_size: usize) -> io::Result<Self> {
+        Self::open_at(path, buffer_size, 0)
+    }
+
+    /// Opens the file for reading from the given position, which is expected to be at
+    /// the start of a message, e.g. the offset saved in a checkpoint.
+    pub fn open_at(path: PathBuf, buffer_size: usize, position: u64) -> io::Result<Self> {
+        let mut file = File::open(&path)?;
+        let file_id = file_id(&file.metadata()?);
+        file.seek(SeekFrom::Start(position))?;
+        Ok(Self {
+            path,
+            file,
+            file_id,
+            position,
+            buf: Vec::with_capacity(buffer_size),
+            buffer_size: buffer_size.max(UPDATE_METADATA_SIZE),
+            start: 0,
+            complete: 0,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            idle_timeout: None,
+        })
+    }
+
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Sets the time without new data, after which the reader returns end of file.
+    /// Reading again after that continues waiting for new data.
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Reads the data appended to the file into the buffer.
+    /// Returns the number of bytes read, 0 if there's no new data.
+    fn fill(&mut self) -> io::Result<usize> {
+        // drop returned bytes to keep the buffer small
+        if self.start > 0 {
+            self.buf.drain(..self.start);
+            self.complete -= self.start;
+            self.start = 0;
+        }
+        let len = self.buf.len();
+        self.buf.resize(len + self.buffer_size, 0);
+        let bytes_read = match self.file.read(&mut self.buf[len..]) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                self.buf.truncate(len);
+                return Err(e);
+            }
+        };
+        self.buf.truncate(len + bytes_read);
+        self.position += bytes_read as u64;
+        self.scan()?;
+        Ok(bytes_read)
+    }
+
+    /// Moves the end of complete messages past all complete messages in the buffer.
+    fn scan(&mut self) -> io::Result<()> {
+        while self.buf.len() - self.complete >= UPDATE_METADATA_SIZE {
+            let num_updates = read_u64_le(&self.buf[self.complete..], UPDATE_NUM_UPDATES_OFFSET);
+            let size = num_updates
+                .checked_mul(UPDATE_LEVEL_SIZE as u64)
+                .and_then(|size| size.checked_add(UPDATE_METADATA_SIZE as u64))
+                .and_then(|size| usize::try_from(size).ok())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid number of updates: {}", num_updates),
+                    )
+                })?;
+            if self.buf.len() - self.complete < size {
+                break;
+            }
+            self.complete += size;
+        }
+        Ok(())
+    }
+
+    /// Checks if the file was truncated or rotated and if so, continues from the start of
+    /// the new content. Returns true if the reader was reset.
+    fn check_reset(&mut self) -> io::Result<bool> {
+        // the file may be missing for a moment during rotation
+        let Ok(metadata) = std::fs::metadata(&self.path) else {
+            return Ok(false);
+        };
+        if file_id(&metadata) != self.file_id {
+            info!("Incremental file {:?} was rotated", self.path);
+            self.file = File::open(&self.path)?;
+            self.file_id = file_id(&self.file.metadata()?);
+        } else if metadata.len() < self.position {
+            info!("Incremental file {:?} was truncated", self.path);
+            self.file.seek(SeekFrom::Start(0))?;
+        } else {
+            return Ok(false);
+        }
+        if self.buf.len() > self.complete {
+            warn!(
+                "Dropping partial incremental message of {} bytes",
+                self.buf.len() - self.complete
+            );
+            self.buf.truncate(self.complete);
+        }
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl Read for TailReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut idle_since = Instant::now();
+        loop {
+            if self.start < self.complete {
+                let size = out.len().min(self.complete - self.start);
+                out[..size].copy_from_slice(&self.buf[self.start..self.start + size]);
+                self.start += size;
+                return Ok(size);
+            }
+            if self.fill()? > 0 {
+                idle_since = Instant::now();
+                continue;
+            }
+            if self.check_reset()? {
+                continue;
+            }
+            if let Some(idle_timeout) = self.idle_timeout {
+                if idle_since.elapsed() >= idle_timeout {
+                    debug!("No new incremental data for {:?}", idle_timeout);
+                    return Ok(0);
+                }
+            }
+            thread::sleep(self.poll_interval);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    // rotation is not detected, only truncation
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, path::Path};
+
+    use super::*;
+    use crate::ser::{
+        read_incremental_message,
+        writer::{write_incremental_message, IncrementalMessage, LevelUpdate, Side},
+    };
+
+    fn message(seq_no: u64) -> Vec<u8> {
+        let message = IncrementalMessage {
+            timestamp: seq_no,
+            seq_no,
+            id: 1,
+            updates: vec![LevelUpdate {
+                side: Side::Bid,
+                price: 100.0,
+                qty: seq_no,
+            }],
+        };
+        let mut buf = vec![];
+        write_incremental_message(&mut buf, &message).unwrap();
+        buf
+    }
+
+    fn append(path: &Path, buf: &[u8]) {
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .unwrap();
+        file.write_all(buf).unwrap();
+    }
+
+    fn open(path: &Path) -> TailReader {
+        TailReader::open(path.to_path_buf(), 32)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(1))
+            .with_idle_timeout(Some(Duration::from_millis(20)))
+    }
+
+    /// Reads the sequence numbers of the available messages.
+    fn read_seq_nos(reader: &mut TailReader) -> Vec<u64> {
+        let mut buf = vec![];
+        let mut seq_nos = vec![];
+        while read_incremental_message(reader, &mut buf).unwrap() {
+            seq_nos.push(read_u64_le(&buf, crate::ser::UPDATE_SEQ_NO_OFFSET));
+        }
+        seq_nos
+    }
+
+    fn temp_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.bin", name, std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_tail_partial_message() {
+        let path = temp_file("tail_partial");
+        let mut reader = open(&path);
+        assert!(read_seq_nos(&mut reader).is_empty());
+
+        let second = message(2);
+        append(&path, &message(1));
+        append(&path, &second[..20]);
+        assert_eq!(read_seq_nos(&mut reader), vec![1]);
+
+        append(&path, &second[20..]);
+        append(&path, &message(3));
+        assert_eq!(read_seq_nos(&mut reader), vec![2, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_tail_truncated_file() {
+        let path = temp_file("tail_truncated");
+        append(&path, &message(1));
+        append(&path, &message(2));
+        let mut reader = open(&path);
+        assert_eq!(read_seq_nos(&mut reader), vec![1, 2]);
+
+        // partial message is dropped on truncation
+        append(&path, &message(3)[..10]);
+        assert!(read_seq_nos(&mut reader).is_empty());
+        std::fs::write(&path, message(4)).unwrap();
+        assert_eq!(read_seq_nos(&mut reader), vec![4]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tail_rotated_file() {
+        let path = temp_file("tail_rotated");
+        let rotated_path = path.with_extension("bin.1");
+        append(&path, &message(1));
+        let mut reader = open(&path);
+        assert_eq!(read_seq_nos(&mut reader), vec![1]);
+
+        std::fs::rename(&path, &rotated_path).unwrap();
+        append(&path, &message(2));
+        append(&path, &message(3));
+        assert_eq!(read_seq_nos(&mut reader), vec![2, 3]);
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(rotated_path).unwrap();
+    }
+}
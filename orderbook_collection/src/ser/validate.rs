@@ -0,0 +1,70 @@
+//! Validation of the field values of the incremental messages and snapshot records, shared by
+//! the readers of both implementations. Each invalid value is reported with its own
+//! `ser::Error` variant, which carries the byte offset of the field within the message or the
+//! record. The number of updates is validated by `ser::incremental_message_size` and removing
+//! a level, which doesn't exist, by the readers, as it depends on the order book.
+
+use crate::{
+    orderbook::Side,
+    price::{Price, TickSize},
+    ser::Error,
+};
+
+/// Decodes the side of a level update at the offset, 0 is a bid and 1 is an ask.
+#[inline(always)]
+pub fn side(side: u8, offset: usize) -> Result<Side, Error> {
+    match side {
+        0 => Ok(Side::Bid),
+        1 => Ok(Side::Ask),
+        _ => Err(Error::InvalidSide(side, offset)),
+    }
+}
+
+/// Converts the price of a level at the offset to ticks. The price must be finite, positive and
+/// on the tick grid, see `TickSize::to_price_on_grid`.
+#[inline(always)]
+pub fn price(price: f64, tick_size: &TickSize, offset: usize) -> Result<Price, Error> {
+    // NaN isn't positive either
+    if !(price.is_finite() && price > 0.0) {
+        return Err(Error::InvalidPrice(price, offset));
+    }
+    tick_size
+        .to_price_on_grid(price)
+        .ok_or(Error::OffTickPrice(price, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_side() {
+        assert_eq!(side(0, 32).unwrap(), Side::Bid);
+        assert_eq!(side(1, 32).unwrap(), Side::Ask);
+        assert!(matches!(side(2, 32), Err(Error::InvalidSide(2, 32))));
+        assert!(matches!(side(255, 49), Err(Error::InvalidSide(255, 49))));
+    }
+
+    #[test]
+    fn test_price() {
+        let tick_size = TickSize::new(0.01);
+        assert_eq!(price(100.05, &tick_size, 33).unwrap(), Price(10005));
+        for invalid in [
+            0.0,
+            -0.0,
+            -100.0,
+            f64::NAN,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+        ] {
+            assert!(matches!(
+                price(invalid, &tick_size, 33),
+                Err(Error::InvalidPrice(_, 33))
+            ));
+        }
+        assert!(matches!(
+            price(100.055, &tick_size, 33),
+            Err(Error::OffTickPrice(p, 33)) if p == 100.055
+        ));
+    }
+}
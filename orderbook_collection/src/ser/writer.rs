@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use anyhow::bail;
+use tracing::info;
+
+use crate::{
+    orderbook::OrderBook,
+    ser::{
+        incremental_message_size, read_u64_le, Error, SnapshotFormat, LEVEL_PRICE_SIZE,
+        LEVEL_SIDE_SIZE, SNAPSHOT_LEGACY_LEVELS, UPDATE_ID_OFFSET, UPDATE_LEVEL_SIZE,
+        UPDATE_METADATA_SIZE, UPDATE_NUM_UPDATES_OFFSET, UPDATE_SEQ_NO_OFFSET,
+        UPDATE_TIMESTAMP_OFFSET,
+    },
+};
+
+pub use crate::orderbook::Side;
+
+/// Single level update of an incremental message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelUpdate {
+    pub side: Side,
+    pub price: f64,
+    /// New qty of the level, 0 removes the level.
+    pub qty: u64,
+}
+
+/// Incremental message with updates of a single order book.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IncrementalMessage {
+    pub timestamp: u64,
+    pub seq_no: u64,
+    pub id: u64,
+    pub updates: Vec<LevelUpdate>,
+}
+
+impl IncrementalMessage {
+    /// Decodes the incremental message at the start of the buffer, see
+    /// `write_incremental_message` for the layout. The order book readers apply messages
+    /// without decoding them into this type, it's used e.g. to report a message.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
+        if buf.len() < UPDATE_METADATA_SIZE {
+            return Err(Error::BufferTooSmall);
+        }
+        let size = incremental_message_size(read_u64_le(buf, UPDATE_NUM_UPDATES_OFFSET))?;
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall);
+        }
+        let updates = buf[UPDATE_METADATA_SIZE..size]
+            .chunks_exact(UPDATE_LEVEL_SIZE)
+            .enumerate()
+            .map(|(i, update)| {
+                let side = match update[0] {
+                    0 => Side::Bid,
+                    1 => Side::Ask,
+                    side => {
+                        let offset = UPDATE_METADATA_SIZE + i * UPDATE_LEVEL_SIZE;
+                        return Err(Error::InvalidSide(side, offset));
+                    }
+                };
+                Ok(LevelUpdate {
+                    side,
+                    price: f64::from_bits(read_u64_le(update, LEVEL_SIDE_SIZE)),
+                    qty: read_u64_le(update, LEVEL_SIDE_SIZE + LEVEL_PRICE_SIZE),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            timestamp: read_u64_le(buf, UPDATE_TIMESTAMP_OFFSET),
+            seq_no: read_u64_le(buf, UPDATE_SEQ_NO_OFFSET),
+            id: read_u64_le(buf, UPDATE_ID_OFFSET),
+            updates,
+        })
+    }
+}
+
+/// Snapshot record of a single order book, levels are sorted by distance to mid.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SnapshotRecord {
+    pub timestamp: u64,
+    pub seq_no: u64,
+    pub id: u64,
+    pub bids: Vec<(f64, u64)>,
+    pub asks: Vec<(f64, u64)>,
+}
+
+impl SnapshotRecord {
+    /// Creates the snapshot record from all levels of the order book.
+    /// Prices are converted from ticks using the tick size of the order book.
+    pub fn from_order_book<B: OrderBook>(order_book: &B) -> Self {
+        let tick_size = order_book.tick_size();
+        let to_f64 = |levels: Vec<(crate::price::Price, u64)>| -> Vec<(f64, u64)> {
+            levels
+                .into_iter()
+                .map(|(price, qty)| (tick_size.to_f64(price), qty))
+                .collect()
+        };
+        Self {
+            timestamp: order_book.timestamp(),
+            seq_no: order_book.seq_no(),
+            id: order_book.id(),
+            bids: to_f64(order_book.get_bids()),
+            asks: to_f64(order_book.get_asks()),
+        }
+    }
+}
+
+/// Writes the incremental message in the format expected by the incremental readers:
+/// - 8 bytes for timestamp (u64)
+/// - 8 bytes for sequence number (u64)
+/// - 8 bytes for ID (u64)
+/// - 8 bytes for number of updates (u64)
+/// - For each update:
+///   - 1 byte for side (0 for bid, 1 for ask)
+///   - 8 bytes for price (f64)
+///   - 8 bytes for qty (u64)
+///
+/// Returns the number of bytes written.
+pub fn write_incremental_message<W: Write>(
+    writer: &mut W,
+    message: &IncrementalMessage,
+) -> anyhow::Result<usize> {
+    writer.write_all(&message.timestamp.to_le_bytes())?;
+    writer.write_all(&message.seq_no.to_le_bytes())?;
+    writer.write_all(&message.id.to_le_bytes())?;
+    writer.write_all(&(message.updates.len() as u64).to_le_bytes())?;
+    for update in message.updates.iter() {
+        writer.write_all(&[update.side as u8])?;
+        writer.write_all(&update.price.to_le_bytes())?;
+        writer.write_all(&update.qty.to_le_bytes())?;
+    }
+    Ok(crate::ser::UPDATE_METADATA_SIZE + message.updates.len() * crate::ser::UPDATE_LEVEL_SIZE)
+}
+
+/// Writes the snapshot record in the given format.
+/// In the legacy format, the bids and asks are interleaved as (bid, ask) pairs and missing levels
+/// are written as empty levels with zero price and qty. Records with more than
+/// `SNAPSHOT_LEGACY_LEVELS` levels per side can't be written in the legacy format and
+/// Error::InvalidData is returned before anything is written, the depth format has no such
+/// limit.
+///
+/// Returns the number of bytes written.
+pub fn write_snapshot_record<W: Write>(
+    writer: &mut W,
+    record: &SnapshotRecord,
+    format: SnapshotFormat,
+) -> anyhow::Result<usize> {
+    if format == SnapshotFormat::Legacy
+        && (record.bids.len() > SNAPSHOT_LEGACY_LEVELS
+            || record.asks.len() > SNAPSHOT_LEGACY_LEVELS)
+    {
+        bail!(Error::InvalidData(format!(
+            "Too many levels for legacy snapshot of order book ID {}, bids: {}, asks: {}",
+            record.id,
+            record.bids.len(),
+            record.asks.len()
+        )));
+    }
+    writer.write_all(&record.timestamp.to_le_bytes())?;
+    writer.write_all(&record.seq_no.to_le_bytes())?;
+    writer.write_all(&record.id.to_le_bytes())?;
+    match format {
+        SnapshotFormat::Legacy => {
+            for i in 0..SNAPSHOT_LEGACY_LEVELS {
+                write_level(writer, record.bids.get(i).copied().unwrap_or_default())?;
+                write_level(writer, record.asks.get(i).copied().unwrap_or_default())?;
+            }
+            Ok(crate::ser::SNAPSHOT_RECORD_SIZE)
+        }
+        SnapshotFormat::Depth => {
+            writer.write_all(&(record.bids.len() as u64).to_le_bytes())?;
+            writer.write_all(&(record.asks.len() as u64).to_le_bytes())?;
+            for level in record.bids.iter().chain(record.asks.iter()) {
+                write_level(writer, *level)?;
+            }
+            Ok(crate::ser::SNAPSHOT_DEPTH_METADATA_SIZE
+                + (record.bids.len() + record.asks.len()) * crate::ser::SNAPSHOT_LEVEL_SIZE)
+        }
+    }
+}
+
+/// Writes the snapshot file of the given format with all order books, ordered by ID.
+pub fn write_snapshot_file<B: OrderBook>(
+    snapshot_file: PathBuf,
+    order_books: &HashMap<u64, B>,
+    format: SnapshotFormat,
+) -> anyhow::Result<()> {
+    info!("Writing snapshot file: {:?}", snapshot_file);
+    let mut writer = BufWriter::new(File::create(snapshot_file)?);
+    let mut ids: Vec<&u64> = order_books.keys().collect();
+    ids.sort();
+    for id in ids {
+        let record = SnapshotRecord::from_order_book(&order_books[id]);
+        write_snapshot_record(&mut writer, &record, format)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_level<W: Write>(writer: &mut W, (price, qty): (f64, u64)) -> anyhow::Result<()> {
+    writer.write_all(&price.to_le_bytes())?;
+    writer.write_all(&qty.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook, config::OrderBookConfig, orderbook::CrossPolicy,
+        price::TickSize,
+    };
+
+    fn config() -> OrderBookConfig {
+        OrderBookConfig {
+            id: 3,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        }
+    }
+
+    fn record() -> SnapshotRecord {
+        SnapshotRecord {
+            timestamp: 1,
+            seq_no: 2,
+            id: 3,
+            bids: vec![(100.05, 10), (100.0, 20)],
+            asks: vec![(100.1, 5)],
+        }
+    }
+
+    #[test]
+    fn test_write_incremental_message() {
+        let message = IncrementalMessage {
+            timestamp: 2,
+            seq_no: 3,
+            id: 3,
+            updates: vec![
+                LevelUpdate {
+                    side: Side::Bid,
+                    price: 100.0,
+                    qty: 15,
+                },
+                LevelUpdate {
+                    side: Side::Ask,
+                    price: 100.1,
+                    qty: 0,
+                },
+            ],
+        };
+        let mut buf = vec![];
+        let size = write_incremental_message(&mut buf, &message).unwrap();
+        assert_eq!(size, buf.len());
+        assert_eq!(
+            buf.len(),
+            crate::ser::UPDATE_METADATA_SIZE + 2 * crate::ser::UPDATE_LEVEL_SIZE
+        );
+        assert_eq!(IncrementalMessage::from_bytes(&buf).unwrap(), message);
+        assert!(IncrementalMessage::from_bytes(&buf[..buf.len() - 1]).is_err());
+
+        // round trip with both readers
+        let mut btree_books = HashMap::new();
+        let mut btree_book =
+            btree_orderbook::orderbook::OrderBook::with_tick_size(3, TickSize::new(0.01));
+        btree_book.seq_no = 2;
+        btree_book.add_ask(btree_book.tick_size.to_price(100.1), 5);
+        btree_books.insert(3, btree_book);
+        let offset = btree_orderbook::ser::incremental::read(&buf, &mut btree_books).unwrap();
+        assert_eq!(offset, buf.len());
+
+        let mut array_books = HashMap::new();
+        let mut array_book = Box::new(array_orderbook::orderbook::OrderBook::new(config()));
+        array_book.init();
+        array_book.seq_no = 2;
+        array_book
+            .add_ask(array_book.tick_size().to_price(100.1), 5)
+            .unwrap();
+        array_books.insert(3, array_book);
+        let offset = array_orderbook::ser::incremental::read(&buf, &mut array_books).unwrap();
+        assert_eq!(offset, buf.len());
+
+        for record in [
+            SnapshotRecord::from_order_book(&btree_books[&3]),
+            SnapshotRecord::from_order_book(&array_books[&3]),
+        ] {
+            assert_eq!(record.seq_no, 3);
+            assert_eq!(record.timestamp, 2);
+            assert_eq!(record.bids, vec![(100.0, 15)]);
+            assert!(record.asks.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_write_snapshot_record_round_trip() {
+        let configs = HashMap::from([(3, config())]);
+        for format in [SnapshotFormat::Legacy, SnapshotFormat::Depth] {
+            let mut buf = vec![];
+            let size = write_snapshot_record(&mut buf, &record(), format).unwrap();
+            assert_eq!(size, buf.len());
+            assert_eq!(
+                crate::ser::snapshot_record_size(&buf, format).unwrap(),
+                size
+            );
+
+            let btree_book = match format {
+                SnapshotFormat::Legacy => btree_orderbook::ser::snapshot::read(&buf, &configs),
+                SnapshotFormat::Depth => btree_orderbook::ser::snapshot::read_depth(&buf, &configs),
+            }
+            .unwrap();
+            assert_eq!(SnapshotRecord::from_order_book(&btree_book), record());
+
+            let mut array_books = HashMap::new();
+            let mut array_book = Box::new(array_orderbook::orderbook::OrderBook::new(config()));
+            array_book.init();
+            array_books.insert(3, array_book);
+            match format {
+                SnapshotFormat::Legacy => {
+                    array_orderbook::ser::snapshot::read(&buf, &mut array_books).unwrap()
+                }
+                SnapshotFormat::Depth => {
+                    array_orderbook::ser::snapshot::read_depth(&buf, &mut array_books).unwrap();
+                }
+            }
+            assert_eq!(SnapshotRecord::from_order_book(&array_books[&3]), record());
+        }
+    }
+
+    #[test]
+    fn test_write_legacy_snapshot_record_with_too_many_levels() {
+        let mut record = record();
+        record.asks = (0..6).map(|i| (101.0 + i as f64, 1)).collect();
+        let mut buf = vec![];
+        assert!(write_snapshot_record(&mut buf, &record, SnapshotFormat::Legacy).is_err());
+        // nothing of the rejected record is written
+        assert!(buf.is_empty());
+        assert!(write_snapshot_record(&mut buf, &record, SnapshotFormat::Depth).is_ok());
+    }
+}
@@ -0,0 +1,491 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Cursor, Read},
+    mem,
+    path::PathBuf,
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context};
+use memmap2::Mmap;
+use tracing::{debug, info};
+
+use crate::{
+    config::Config,
+    orderbook::OrderBookReader,
+    replay::{apply_incremental_message, Event},
+    ser::{
+        self, incremental_message_size, read_incremental_message, read_snapshot_record,
+        recovery::GapRecovery, snapshot_record_size, UPDATE_METADATA_SIZE,
+        UPDATE_NUM_UPDATES_OFFSET,
+    },
+};
+
+/// Size of the batches of records sent to the shards, a batch is sent once it's full.
+pub const BATCH_SIZE: usize = 64 * 1024;
+/// Number of batches in the queue of each shard, the reader waits if the queue is full.
+pub const QUEUE_CAPACITY: usize = 16;
+
+// kind of the record in a batch, each record is prefixed with it and with its offset in its file
+const SNAPSHOT_RECORD: u8 = 0;
+const INCREMENTAL_MESSAGE: u8 = 1;
+const RECORD_HEADER_SIZE: usize = 1 + 8;
+
+/// Index of the shard, which owns the order book with the given ID.
+pub fn shard_of(id: u64, shards: usize) -> usize {
+    (id % shards as u64) as usize
+}
+
+/// Replay of the snapshot and incremental streams, where the instruments are partitioned across
+/// worker threads (shards, see `shard_of`), e.g. to process many instruments in parallel.
+///
+/// A single reader thread (the calling one) merges the streams in the same order as
+/// `replay::Replay` and routes each snapshot record and incremental message to the shard of its
+/// ID over a bounded queue with a single producer (the reader) and a single consumer (the shard).
+/// The records are sent in batches and the batches are returned to the reader over a second queue
+/// to be reused, so there is no allocation per record. As each instrument is owned by one shard,
+/// which applies its records in the order they were sent, the order per instrument is preserved
+/// and the order books are the same as with `Replay`.
+pub struct ShardedReplay<S: Read = BufReader<File>, I: Read = BufReader<File>> {
+    config: Config,
+    snapshots: S,
+    incrementals: I,
+    shards: usize,
+}
+
+impl ShardedReplay {
+    /// Opens the snapshot and incremental files for the replay with the given number of shards.
+    pub fn open(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        shards: usize,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Replaying snapshot file: {:?} and incremental file: {:?} in {} shards",
+            snapshot_file, incremental_file, shards
+        );
+        let snapshots = BufReader::new(File::open(snapshot_file)?);
+        let incrementals = BufReader::with_capacity(
+            config.incremental_buffer_size,
+            File::open(incremental_file)?,
+        );
+        Self::new(snapshots, incrementals, config, shards)
+    }
+}
+
+impl ShardedReplay<Cursor<Mmap>, Cursor<Mmap>> {
+    /// Same as `open`, but the files are memory mapped, see `replay::Replay::open_mmap`.
+    pub fn open_mmap(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+        shards: usize,
+    ) -> anyhow::Result<Self> {
+        info!(
+            "Replaying memory mapped snapshot file: {:?} and incremental file: {:?} in {} shards",
+            snapshot_file, incremental_file, shards
+        );
+        let snapshots = ser::mmap::open_at(snapshot_file, 0)?;
+        let incrementals = ser::mmap::open_at(incremental_file, 0)?;
+        Self::new(snapshots, incrementals, config, shards)
+    }
+}
+
+impl<S: Read, I: Read> ShardedReplay<S, I> {
+    pub fn new(
+        snapshots: S,
+        incrementals: I,
+        config: Config,
+        shards: usize,
+    ) -> anyhow::Result<Self> {
+        if shards == 0 {
+            bail!("Number of shards must be positive");
+        }
+        Ok(Self {
+            config,
+            snapshots,
+            incrementals,
+            shards,
+        })
+    }
+
+    /// Replays both streams to the end and returns the order books of all the shards.
+    /// Exceptions:
+    /// * If reading the streams or applying a record in a shard fails, the replay is stopped and
+    ///   the error is returned, the error of a shard takes precedence.
+    /// * If there are incremental messages for an order book, which has no snapshot,
+    ///   an error is returned.
+    pub fn run<B: OrderBookReader + Send>(mut self) -> anyhow::Result<HashMap<u64, B>> {
+        let shards = self.shards;
+        thread::scope(|scope| {
+            let mut queues = Vec::with_capacity(shards);
+            let mut workers = Vec::with_capacity(shards);
+            for index in 0..shards {
+                let (sender, receiver) = sync_channel(QUEUE_CAPACITY);
+                // the shard never blocks on returning a batch, as at most all the batches of
+                // the queue and the one being filled are in flight
+                let (recycle_sender, recycled) = sync_channel(QUEUE_CAPACITY + 1);
+                let mut config = self.config.clone();
+                config
+                    .instruments
+                    .retain(|id, _| shard_of(*id, shards) == index);
+                workers.push(scope.spawn(move || {
+                    Shard::<B>::new(config)
+                        .run(receiver, recycle_sender)
+                        .with_context(|| format!("Shard {} failed", index))
+                }));
+                queues.push(Queue {
+                    index,
+                    sender,
+                    recycled,
+                    batch: Vec::with_capacity(BATCH_SIZE),
+                });
+            }
+            let routed = self.route(&mut queues);
+            // closing the queues stops the shards
+            drop(queues);
+            let mut order_books = HashMap::new();
+            let mut shard_error = None;
+            for worker in workers {
+                match worker.join() {
+                    Ok(Ok(shard_books)) => order_books.extend(shard_books),
+                    Ok(Err(e)) => {
+                        shard_error.get_or_insert(e);
+                    }
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+            if let Some(e) = shard_error {
+                return Err(e);
+            }
+            let events = routed?;
+            debug!(
+                "Sharded replay finished, events: {}, total order books: {}",
+                events,
+                order_books.len()
+            );
+            Ok(order_books)
+        })
+    }
+
+    /// Merges the streams and sends each record to the queue of its shard.
+    /// Returns the number of routed records.
+    fn route(&mut self, queues: &mut [Queue]) -> anyhow::Result<u64> {
+        let format = self.config.snapshot_format;
+        let mut snapshot_buf = Vec::with_capacity(ser::SNAPSHOT_RECORD_SIZE);
+        let mut incremental_buf = Vec::with_capacity(UPDATE_METADATA_SIZE);
+        let mut has_snapshot =
+            read_snapshot_record(&mut self.snapshots, format, &mut snapshot_buf)?;
+        let mut has_incremental =
+            read_incremental_message(&mut self.incrementals, &mut incremental_buf)?;
+        let (mut snapshot_offset, mut incremental_offset) = (0, 0);
+        let mut events = 0;
+        while let Some(event) = Event::earlier(
+            has_snapshot.then(|| Event::snapshot(&snapshot_buf)),
+            has_incremental.then(|| Event::incremental(&incremental_buf)),
+        ) {
+            let queue = &mut queues[shard_of(event.id, self.shards)];
+            if event.snapshot {
+                queue.push(SNAPSHOT_RECORD, snapshot_offset, &snapshot_buf)?;
+                snapshot_offset += snapshot_buf.len() as u64;
+                has_snapshot =
+                    read_snapshot_record(&mut self.snapshots, format, &mut snapshot_buf)?;
+            } else {
+                queue.push(INCREMENTAL_MESSAGE, incremental_offset, &incremental_buf)?;
+                incremental_offset += incremental_buf.len() as u64;
+                has_incremental =
+                    read_incremental_message(&mut self.incrementals, &mut incremental_buf)?;
+            }
+            events += 1;
+        }
+        for queue in queues.iter_mut() {
+            queue.flush()?;
+        }
+        Ok(events)
+    }
+}
+
+/// Reader side of the queue of a shard.
+struct Queue {
+    index: usize,
+    sender: SyncSender<Vec<u8>>,
+    recycled: Receiver<Vec<u8>>,
+    batch: Vec<u8>,
+}
+
+impl Queue {
+    fn push(&mut self, kind: u8, offset: u64, record: &[u8]) -> anyhow::Result<()> {
+        if !self.batch.is_empty()
+            && self.batch.len() + RECORD_HEADER_SIZE + record.len() > BATCH_SIZE
+        {
+            self.flush()?;
+        }
+        self.batch.push(kind);
+        self.batch.extend_from_slice(&offset.to_le_bytes());
+        self.batch.extend_from_slice(record);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+        let next = match self.recycled.try_recv() {
+            Ok(mut batch) => {
+                batch.clear();
+                batch
+            }
+            Err(_) => Vec::with_capacity(BATCH_SIZE),
+        };
+        self.sender
+            .send(mem::replace(&mut self.batch, next))
+            .map_err(|_| anyhow!("Shard {} stopped", self.index))
+    }
+}
+
+/// Order books of the instruments of a shard, which are updated by its worker thread.
+struct Shard<B: OrderBookReader> {
+    config: Config,
+    order_books: HashMap<u64, B>,
+    recovery: GapRecovery,
+}
+
+impl<B: OrderBookReader> Shard<B> {
+    fn new(config: Config) -> Self {
+        Self {
+            order_books: B::init_order_books(&config),
+            recovery: GapRecovery::with_limit(config.recovery_buffer_limit),
+            config,
+        }
+    }
+
+    fn run(
+        mut self,
+        receiver: Receiver<Vec<u8>>,
+        recycle: SyncSender<Vec<u8>>,
+    ) -> anyhow::Result<HashMap<u64, B>> {
+        for batch in receiver {
+            self.apply_batch(&batch)?;
+            // the reader allocates a new batch if the returned ones are not picked up yet
+            let _ = recycle.try_send(batch);
+        }
+        if let Some(id) = self
+            .recovery
+            .buffered_ids()
+            .find(|id| !self.order_books.contains_key(id))
+        {
+            bail!("Order book with ID {} not found", id);
+        }
+        Ok(self.order_books)
+    }
+
+    /// Applies the records of the batch in order, the same as `replay::Replay::step`.
+    fn apply_batch(&mut self, batch: &[u8]) -> anyhow::Result<()> {
+        let mut offset = 0;
+        while offset < batch.len() {
+            let kind = batch[offset];
+            let file_offset = ser::read_u64_le(batch, offset + 1);
+            let record = &batch[offset + RECORD_HEADER_SIZE..];
+            let size = if kind == SNAPSHOT_RECORD {
+                let size = snapshot_record_size(record, self.config.snapshot_format)?;
+                B::apply_snapshot_record(
+                    &record[..size],
+                    &mut self.order_books,
+                    &self.config,
+                    &mut self.recovery,
+                )
+                .with_context(|| {
+                    format!("Failed to apply snapshot record at offset {}", file_offset)
+                })?;
+                size
+            } else {
+                let size =
+                    incremental_message_size(ser::read_u64_le(record, UPDATE_NUM_UPDATES_OFFSET))?;
+                apply_incremental_message(
+                    &record[..size],
+                    &mut self.order_books,
+                    &mut self.recovery,
+                    &mut (),
+                )
+                .with_context(|| {
+                    format!(
+                        "Failed to apply incremental message at offset {}",
+                        file_offset
+                    )
+                })?;
+                size
+            };
+            offset += RECORD_HEADER_SIZE + size;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::CrossPolicy,
+        replay::Replay,
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, IncrementalMessage, LevelUpdate,
+                Side, SnapshotRecord,
+            },
+            InputMode, SnapshotFormat,
+        },
+    };
+
+    const INSTRUMENTS: u64 = 5;
+
+    fn config() -> Config {
+        Config {
+            instruments: (1..=INSTRUMENTS)
+                .map(|id| {
+                    (
+                        id,
+                        OrderBookConfig {
+                            id,
+                            min_price: 90.0,
+                            max_price: 110.0,
+                            tick_size: 0.01,
+                            recenter: false,
+                            cross_policy: CrossPolicy::Warn,
+                        },
+                    )
+                })
+                .collect(),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    /// Snapshots and interleaved incremental messages of all the instruments, with a gap and
+    /// a later snapshot for instrument 2 and messages of instrument 3 before its first snapshot.
+    fn streams() -> (Vec<u8>, Vec<u8>) {
+        let mut snapshots = vec![];
+        let mut records = vec![];
+        for id in 1..=INSTRUMENTS {
+            if id == 3 {
+                records.push((49_500, 49, id));
+            } else {
+                records.push((0, 0, id));
+            }
+        }
+        records.sort();
+        records.push((120_000, 119, 2));
+        for (timestamp, seq_no, id) in records {
+            let record = SnapshotRecord {
+                timestamp,
+                seq_no,
+                id,
+                bids: vec![(100.0, id)],
+                asks: vec![(101.0, id)],
+            };
+            write_snapshot_record(&mut snapshots, &record, SnapshotFormat::Legacy).unwrap();
+        }
+        let mut incrementals = vec![];
+        for seq_no in 1..200u64 {
+            for id in 1..=INSTRUMENTS {
+                // the gap of instrument 2
+                if id == 2 && seq_no == 100 {
+                    continue;
+                }
+                let message = IncrementalMessage {
+                    timestamp: seq_no * 1000 + id,
+                    seq_no,
+                    id,
+                    updates: vec![LevelUpdate {
+                        side: if seq_no % 2 == 0 {
+                            Side::Bid
+                        } else {
+                            Side::Ask
+                        },
+                        price: 95.0 + (seq_no % 20) as f64 / 2.0,
+                        qty: seq_no * id,
+                    }],
+                };
+                write_incremental_message(&mut incrementals, &message).unwrap();
+            }
+        }
+        (snapshots, incrementals)
+    }
+
+    fn test_sharded<B: OrderBookReader + Send>() {
+        let (snapshots, incrementals) = streams();
+        let expected = Replay::<B, _, _>::new(&snapshots[..], &incrementals[..], config())
+            .unwrap()
+            .run()
+            .unwrap();
+        for shards in [1, 2, 3, 8] {
+            let order_books: HashMap<u64, B> =
+                ShardedReplay::new(&snapshots[..], &incrementals[..], config(), shards)
+                    .unwrap()
+                    .run()
+                    .unwrap();
+            assert_eq!(order_books.len(), expected.len());
+            for (id, order_book) in expected.iter() {
+                let sharded_book = &order_books[id];
+                assert_eq!(sharded_book.seq_no(), order_book.seq_no(), "ID {}", id);
+                assert_eq!(sharded_book.health(), order_book.health());
+                assert_eq!(sharded_book.get_bids(), order_book.get_bids());
+                assert_eq!(sharded_book.get_asks(), order_book.get_asks());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sharded_btree() {
+        test_sharded::<btree_orderbook::orderbook::OrderBook>();
+    }
+
+    #[test]
+    fn test_sharded_array() {
+        test_sharded::<Box<array_orderbook::orderbook::OrderBook>>();
+    }
+
+    #[test]
+    fn test_sharded_errors() {
+        let (snapshots, mut incrementals) = streams();
+        assert!(ShardedReplay::new(&snapshots[..], &incrementals[..], config(), 0).is_err());
+
+        // a price out of bounds fails the shard of the instrument
+        let offset = incrementals.len();
+        let message = IncrementalMessage {
+            timestamp: 1_000_000,
+            seq_no: 200,
+            id: 4,
+            updates: vec![LevelUpdate {
+                side: Side::Bid,
+                price: 200.0,
+                qty: 1,
+            }],
+        };
+        write_incremental_message(&mut incrementals, &message).unwrap();
+        let error = ShardedReplay::new(&snapshots[..], &incrementals[..], config(), 2)
+            .unwrap()
+            .run::<Box<array_orderbook::orderbook::OrderBook>>()
+            .unwrap_err();
+        assert!(format!("{:#}", error).starts_with(&format!(
+            "Shard 0 failed: Failed to apply incremental message at offset {}",
+            offset
+        )));
+
+        // an order book without snapshot
+        let (_, mut incrementals) = streams();
+        let message = IncrementalMessage { id: 9, ..message };
+        write_incremental_message(&mut incrementals, &message).unwrap();
+        let error = ShardedReplay::new(&snapshots[..], &incrementals[..], config(), 2)
+            .unwrap()
+            .run::<btree_orderbook::orderbook::OrderBook>()
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("Order book with ID 9 not found"));
+    }
+}
@@ -0,0 +1,383 @@
+use std::{collections::HashMap, sync::Arc};
+
+#[cfg(loom)]
+use loom::sync::atomic::{fence, AtomicU64, Ordering};
+#[cfg(not(loom))]
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::{
+    orderbook::{BookHealth, OrderBook},
+    price::Price,
+};
+
+/// Default number of levels per side of the published top of book.
+pub const TOP_LEVELS: usize = 5;
+
+/// Top N levels per side of an order book, as published after each applied message.
+/// Missing levels have zero qty, the same as in the legacy snapshot format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopOfBook<const N: usize = TOP_LEVELS> {
+    pub seq_no: u64,
+    pub timestamp: u64,
+    pub health: BookHealth,
+    /// Bids from the best one.
+    pub bids: [(Price, u64); N],
+    /// Asks from the best one.
+    pub asks: [(Price, u64); N],
+}
+
+impl<const N: usize> Default for TopOfBook<N> {
+    fn default() -> Self {
+        Self {
+            seq_no: 0,
+            timestamp: 0,
+            health: BookHealth::Live,
+            bids: [(Price(0), 0); N],
+            asks: [(Price(0), 0); N],
+        }
+    }
+}
+
+impl<const N: usize> TopOfBook<N> {
+    pub fn from_order_book<B: OrderBook>(order_book: &B) -> Self {
+        let mut top = Self {
+            seq_no: order_book.seq_no(),
+            timestamp: order_book.timestamp(),
+            health: order_book.health(),
+            ..Self::default()
+        };
+        for (level, bid) in top.bids.iter_mut().zip(order_book.iter_bids()) {
+            *level = bid;
+        }
+        for (level, ask) in top.asks.iter_mut().zip(order_book.iter_asks()) {
+            *level = ask;
+        }
+        top
+    }
+
+    pub fn best_bid(&self) -> Option<(Price, u64)> {
+        self.bids.first().copied().filter(|(_, qty)| *qty > 0)
+    }
+
+    pub fn best_ask(&self) -> Option<(Price, u64)> {
+        self.asks.first().copied().filter(|(_, qty)| *qty > 0)
+    }
+}
+
+/// Seqlock holding the published top of book, i.e. the fields of `TopOfBook` stored as atomic
+/// words, which are read and written with relaxed ordering between the loads and stores of
+/// the sequence number: the sequence number is odd while a write is in progress, so a reader
+/// retries if it's odd or if it changed during the read.
+struct TopOfBookCell<const N: usize> {
+    seq: AtomicU64,
+    header: [AtomicU64; 3],
+    bids: [[AtomicU64; 2]; N],
+    asks: [[AtomicU64; 2]; N],
+}
+
+impl<const N: usize> TopOfBookCell<N> {
+    fn new() -> Self {
+        let level = |_| [AtomicU64::new(0), AtomicU64::new(0)];
+        Self {
+            seq: AtomicU64::new(0),
+            header: std::array::from_fn(|_| AtomicU64::new(0)),
+            bids: std::array::from_fn(level),
+            asks: std::array::from_fn(level),
+        }
+    }
+
+    /// Must be called by a single writer at a time, see `TopOfBookPublisher`.
+    fn write(&self, top: &TopOfBook<N>) {
+        let seq = self.seq.load(Ordering::Relaxed);
+        self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        // the odd sequence number is visible before any of the words
+        fence(Ordering::Release);
+        self.header[0].store(top.seq_no, Ordering::Relaxed);
+        self.header[1].store(top.timestamp, Ordering::Relaxed);
+        self.header[2].store(top.health as u64, Ordering::Relaxed);
+        for (levels, cells) in [(&top.bids, &self.bids), (&top.asks, &self.asks)] {
+            for ((price, qty), cell) in levels.iter().zip(cells.iter()) {
+                cell[0].store(price.ticks() as u64, Ordering::Relaxed);
+                cell[1].store(*qty, Ordering::Relaxed);
+            }
+        }
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Returns None if a write is in progress or completed during the read.
+    fn try_read(&self) -> Option<TopOfBook<N>> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq % 2 == 1 {
+            return None;
+        }
+        let mut top = TopOfBook {
+            seq_no: self.header[0].load(Ordering::Relaxed),
+            timestamp: self.header[1].load(Ordering::Relaxed),
+            health: match self.header[2].load(Ordering::Relaxed) {
+                health if health == BookHealth::Stale as u64 => BookHealth::Stale,
+                health if health == BookHealth::Invalid as u64 => BookHealth::Invalid,
+                _ => BookHealth::Live,
+            },
+            ..TopOfBook::default()
+        };
+        for (levels, cells) in [(&mut top.bids, &self.bids), (&mut top.asks, &self.asks)] {
+            for (level, cell) in levels.iter_mut().zip(cells.iter()) {
+                *level = (
+                    Price(cell[0].load(Ordering::Relaxed) as i64),
+                    cell[1].load(Ordering::Relaxed),
+                );
+            }
+        }
+        // the loads of the words complete before the sequence number is checked again
+        fence(Ordering::Acquire);
+        (self.seq.load(Ordering::Relaxed) == seq).then_some(top)
+    }
+}
+
+/// Writer of the published top of book of an order book, created with `top_of_book`.
+/// Publishing never blocks and never waits for the readers.
+pub struct TopOfBookPublisher<const N: usize = TOP_LEVELS> {
+    cell: Arc<TopOfBookCell<N>>,
+}
+
+impl<const N: usize> TopOfBookPublisher<N> {
+    /// Publishes the top of book, which replaces the previous one.
+    pub fn publish(&mut self, top: &TopOfBook<N>) {
+        self.cell.write(top);
+    }
+
+    /// Publishes the top N levels of the order book.
+    pub fn publish_order_book<B: OrderBook>(&mut self, order_book: &B) {
+        self.publish(&TopOfBook::from_order_book(order_book));
+    }
+
+    /// Returns a new reader of the published top of book.
+    pub fn subscribe(&self) -> TopOfBookSubscriber<N> {
+        TopOfBookSubscriber {
+            cell: self.cell.clone(),
+        }
+    }
+}
+
+/// Reader of the published top of book, which can be sent to and cloned for any number of
+/// threads. Reading doesn't block the publisher, a read, which overlaps with publishing, is
+/// retried, so the returned top of book is always consistent, i.e. of a single publish.
+#[derive(Clone)]
+pub struct TopOfBookSubscriber<const N: usize = TOP_LEVELS> {
+    cell: Arc<TopOfBookCell<N>>,
+}
+
+impl<const N: usize> TopOfBookSubscriber<N> {
+    /// Reads the latest published top of book, retrying while it's being published.
+    /// Before the first publish, it's the default (empty) top of book.
+    pub fn read(&self) -> TopOfBook<N> {
+        loop {
+            if let Some(top) = self.cell.try_read() {
+                return top;
+            }
+            #[cfg(loom)]
+            loom::thread::yield_now();
+            #[cfg(not(loom))]
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Single attempt of `read`, returns None if the top of book is being published.
+    pub fn try_read(&self) -> Option<TopOfBook<N>> {
+        self.cell.try_read()
+    }
+}
+
+/// Creates the published top of book of an order book with its publisher (the thread applying
+/// the messages) and a subscriber (e.g. a strategy thread).
+pub fn top_of_book<const N: usize>() -> (TopOfBookPublisher<N>, TopOfBookSubscriber<N>) {
+    let publisher = TopOfBookPublisher {
+        cell: Arc::new(TopOfBookCell::new()),
+    };
+    let subscriber = publisher.subscribe();
+    (publisher, subscriber)
+}
+
+/// Publishers of the order books with subscribers, attached to a replay with
+/// `replay::Replay::with_publishers`, which publishes the top of book of an order book after
+/// each applied snapshot record or incremental message of it.
+#[derive(Default)]
+pub struct Publishers<const N: usize = TOP_LEVELS> {
+    publishers: HashMap<u64, TopOfBookPublisher<N>>,
+}
+
+impl<const N: usize> Publishers<N> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a reader of the published top of book of the order book with the given ID.
+    pub fn subscribe(&mut self, id: u64) -> TopOfBookSubscriber<N> {
+        self.publishers
+            .entry(id)
+            .or_insert_with(|| top_of_book().0)
+            .subscribe()
+    }
+
+    /// Publishes the top of book of the order book with the given ID, if it has subscribers.
+    pub fn publish<B: OrderBook>(&mut self, order_books: &HashMap<u64, B>, id: u64) {
+        if let (Some(publisher), Some(order_book)) =
+            (self.publishers.get_mut(&id), order_books.get(&id))
+        {
+            publisher.publish_order_book(order_book);
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{sync::atomic::AtomicBool, thread};
+
+    use super::*;
+    use crate::{btree_orderbook, price::TickSize};
+
+    /// Top of book, whose fields are all derived from `i`, so a torn read is detected.
+    fn top(i: u64) -> TopOfBook<3> {
+        TopOfBook {
+            seq_no: i,
+            timestamp: i * 10,
+            health: if i.is_multiple_of(2) {
+                BookHealth::Live
+            } else {
+                BookHealth::Stale
+            },
+            bids: std::array::from_fn(|level| (Price(i as i64 - level as i64), i + level as u64)),
+            asks: std::array::from_fn(|level| (Price(i as i64 + level as i64 + 1), i * 2)),
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let (mut publisher, subscriber) = top_of_book::<3>();
+        assert_eq!(subscriber.read(), TopOfBook::default());
+        assert_eq!(subscriber.read().best_bid(), None);
+        publisher.publish(&top(7));
+        assert_eq!(subscriber.read(), top(7));
+        assert_eq!(publisher.subscribe().try_read(), Some(top(7)));
+        assert_eq!(subscriber.read().best_ask(), Some((Price(8), 14)));
+    }
+
+    #[test]
+    fn test_from_order_book() {
+        let tick_size = TickSize::new(0.01);
+        let mut order_book = btree_orderbook::orderbook::OrderBook::with_tick_size(1, tick_size);
+        order_book.seq_no = 3;
+        order_book.timestamp = 30;
+        order_book.add_bid(Price(100), 1);
+        order_book.add_bid(Price(99), 2);
+        order_book.add_ask(Price(101), 3);
+        let top = TopOfBook::<2>::from_order_book(&order_book);
+        assert_eq!(top.seq_no, 3);
+        assert_eq!(top.timestamp, 30);
+        assert_eq!(top.bids, [(Price(100), 1), (Price(99), 2)]);
+        assert_eq!(top.asks, [(Price(101), 3), (Price(0), 0)]);
+
+        let mut publishers = Publishers::<2>::new();
+        let subscriber = publishers.subscribe(1);
+        // order books without subscribers are not published
+        let other = btree_orderbook::orderbook::OrderBook::with_tick_size(2, tick_size);
+        publishers.publish(&HashMap::from([(2, other)]), 2);
+        assert_eq!(subscriber.read(), TopOfBook::default());
+        publishers.publish(&HashMap::from([(1, order_book)]), 1);
+        assert_eq!(subscriber.read(), top);
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let (mut publisher, subscriber) = top_of_book::<3>();
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..3 {
+                let subscriber = subscriber.clone();
+                let done = &done;
+                scope.spawn(move || {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let read = subscriber.read();
+                        assert_eq!(
+                            read,
+                            if read.seq_no == 0 {
+                                TopOfBook::default()
+                            } else {
+                                top(read.seq_no)
+                            }
+                        );
+                        // the published tops are read in order
+                        assert!(read.seq_no >= last);
+                        last = read.seq_no;
+                    }
+                });
+            }
+            for i in 1..=100_000 {
+                publisher.publish(&top(i));
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(subscriber.read(), top(100_000));
+    }
+}
+
+/// Concurrency tests of the seqlock with loom, which runs the threads with all the possible
+/// interleavings and memory orderings, run with:
+/// `RUSTFLAGS="--cfg loom" cargo test --release --lib top::loom_tests`
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use loom::{model::Builder, thread};
+
+    use super::*;
+
+    /// Runs the model with at most 3 preemptions per execution, which covers a write
+    /// interleaved with the loads of a read, without exploring all the interleavings of every
+    /// word.
+    fn model(f: impl Fn() + Sync + Send + 'static) {
+        let mut builder = Builder::new();
+        builder.preemption_bound = Some(3);
+        builder.check(f);
+    }
+
+    fn top(i: u64) -> TopOfBook<1> {
+        TopOfBook {
+            seq_no: i,
+            timestamp: i * 10,
+            health: BookHealth::Live,
+            bids: [(Price(i as i64), i)],
+            asks: [(Price(i as i64 + 1), i)],
+        }
+    }
+
+    #[test]
+    fn test_read_is_not_torn() {
+        model(|| {
+            let (mut publisher, subscriber) = top_of_book::<1>();
+            let reader = thread::spawn(move || {
+                if let Some(read) = subscriber.try_read() {
+                    assert!(read == TopOfBook::default() || read == top(1) || read == top(2));
+                }
+            });
+            publisher.publish(&top(1));
+            publisher.publish(&top(2));
+            reader.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_read_latest() {
+        model(|| {
+            let (mut publisher, subscriber) = top_of_book::<1>();
+            let writer = thread::spawn(move || {
+                publisher.publish(&top(1));
+                publisher
+            });
+            let read = subscriber.read();
+            assert!(read == TopOfBook::default() || read == top(1));
+            let publisher = writer.join().unwrap();
+            // once the publish happened before, the read sees it
+            assert_eq!(publisher.subscribe().read(), top(1));
+        });
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::PathBuf,
+};
+
+use anyhow::{bail, Context};
+use tracing::info;
+
+use crate::{
+    config::Config,
+    orderbook::{OrderBook, OrderBookReader},
+    output::OrderBookOutput,
+    replay::{Event, Replay},
+    ser::writer::IncrementalMessage,
+};
+
+/// First difference between the order books of both implementations, see `Verifier`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mismatch {
+    /// Event after which the order books differ.
+    pub event: Event,
+    /// Byte offset of the snapshot record or incremental message of the event in its file.
+    pub offset: u64,
+    /// Incremental message of the event, None for a snapshot record.
+    pub message: Option<IncrementalMessage>,
+    /// Order book of the first implementation, None if it has no snapshot yet.
+    pub left: Option<OrderBookOutput>,
+    /// Order book of the second implementation, None if it has no snapshot yet.
+    pub right: Option<OrderBookOutput>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (record, file) = if self.event.snapshot {
+            ("snapshot record", "snapshot")
+        } else {
+            ("incremental message", "incremental")
+        };
+        write!(
+            f,
+            "Order books with ID {} differ after {} at byte offset {} of the {} file: {:?}, \
+             message: {:?}, left: {:?}, right: {:?}",
+            self.event.id,
+            record,
+            self.offset,
+            file,
+            self.event,
+            self.message,
+            self.left,
+            self.right
+        )
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Differential replay of two order book implementations, e.g. the BTreeMap based and the array
+/// based ones, which feeds each snapshot record and incremental message to both of them and
+/// checks, that the order book of the event is identical in both after each event: the same
+/// sequence number, timestamp, health and levels with decimal prices.
+/// Order books without a snapshot (e.g. the preallocated array based order books) are considered
+/// the same as missing order books.
+///
+/// Both replays should read the same files, the first mismatch is returned as a `Mismatch` error
+/// with the byte offset and the decoded message, and an error of only one of the implementations
+/// is returned with the byte offset of the event.
+pub struct Verifier<
+    A: OrderBookReader,
+    B: OrderBookReader,
+    S: Read = BufReader<File>,
+    I: Read = BufReader<File>,
+> {
+    left: Replay<A, S, I>,
+    right: Replay<B, S, I>,
+    // raw bytes of the event being verified, copied as the replay reuses its buffers
+    record: Vec<u8>,
+    events: u64,
+}
+
+impl<A: OrderBookReader, B: OrderBookReader> Verifier<A, B> {
+    /// Opens the snapshot and incremental files for both replays.
+    pub fn open(
+        snapshot_file: PathBuf,
+        incremental_file: PathBuf,
+        config: Config,
+    ) -> anyhow::Result<Self> {
+        info!("Verifying order book implementations against each other");
+        let left = Replay::open(
+            snapshot_file.clone(),
+            incremental_file.clone(),
+            config.clone(),
+        )?;
+        let right = Replay::open(snapshot_file, incremental_file, config)?;
+        Ok(Self::new(left, right))
+    }
+}
+
+impl<A: OrderBookReader, B: OrderBookReader, S: Read, I: Read> Verifier<A, B, S, I> {
+    pub fn new(left: Replay<A, S, I>, right: Replay<B, S, I>) -> Self {
+        Self {
+            left,
+            right,
+            record: vec![],
+            events: 0,
+        }
+    }
+
+    /// Replays both streams to the end and returns the number of verified events.
+    pub fn run(mut self) -> anyhow::Result<u64> {
+        while let Some(event) = self.left.peek() {
+            let (offset, record) = self.left.peek_record(&event);
+            self.record.clear();
+            self.record.extend_from_slice(record);
+            let right_event = self.right.peek();
+            if right_event != Some(event) {
+                bail!(
+                    "Replays read different events at byte offset {}: {:?} and {:?}",
+                    offset,
+                    event,
+                    right_event
+                );
+            }
+            self.left.step().with_context(|| {
+                format!("Left replay failed at byte offset {}: {:?}", offset, event)
+            })?;
+            self.right.step().with_context(|| {
+                format!("Right replay failed at byte offset {}: {:?}", offset, event)
+            })?;
+            self.events += 1;
+
+            let left = output(self.left.order_books(), event.id);
+            let right = output(self.right.order_books(), event.id);
+            if left != right {
+                let message = (!event.snapshot)
+                    .then(|| IncrementalMessage::from_bytes(&self.record))
+                    .transpose()?;
+                return Err(Mismatch {
+                    event,
+                    offset,
+                    message,
+                    left,
+                    right,
+                }
+                .into());
+            }
+        }
+        if let Some(event) = self.right.peek() {
+            bail!("Right replay has more events: {:?}", event);
+        }
+        info!(
+            "Verified {} events, the order books are identical",
+            self.events
+        );
+        Ok(self.events)
+    }
+}
+
+fn output<B: OrderBook>(order_books: &HashMap<u64, B>, id: u64) -> Option<OrderBookOutput> {
+    order_books
+        .get(&id)
+        .filter(|order_book| order_book.timestamp() > 0)
+        .map(OrderBookOutput::from_order_book)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        array_orderbook, btree_orderbook,
+        config::OrderBookConfig,
+        orderbook::CrossPolicy,
+        ser::{
+            writer::{
+                write_incremental_message, write_snapshot_record, LevelUpdate, Side, SnapshotRecord,
+            },
+            InputMode, SnapshotFormat,
+        },
+    };
+
+    fn config() -> Config {
+        Config {
+            instruments: HashMap::from([(
+                1,
+                OrderBookConfig {
+                    id: 1,
+                    min_price: 90.0,
+                    max_price: 110.0,
+                    tick_size: 0.01,
+                    recenter: false,
+                    cross_policy: CrossPolicy::Warn,
+                },
+            )]),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: crate::ser::recovery::DEFAULT_BUFFER_LIMIT,
+        }
+    }
+
+    fn message(seq_no: u64, side: Side, price: f64, qty: u64) -> IncrementalMessage {
+        IncrementalMessage {
+            timestamp: seq_no * 1000,
+            seq_no,
+            id: 1,
+            updates: vec![LevelUpdate { side, price, qty }],
+        }
+    }
+
+    fn streams(messages: &[IncrementalMessage]) -> (Vec<u8>, Vec<u8>) {
+        let mut snapshots = vec![];
+        let record = SnapshotRecord {
+            timestamp: 10_000,
+            seq_no: 10,
+            id: 1,
+            bids: vec![(100.0, 1)],
+            asks: vec![(101.0, 5)],
+        };
+        write_snapshot_record(&mut snapshots, &record, SnapshotFormat::Legacy).unwrap();
+        let mut incrementals = vec![];
+        for message in messages {
+            write_incremental_message(&mut incrementals, message).unwrap();
+        }
+        (snapshots, incrementals)
+    }
+
+    fn messages() -> Vec<IncrementalMessage> {
+        vec![
+            message(9, Side::Bid, 95.0, 9),
+            message(11, Side::Bid, 100.0, 11),
+            message(12, Side::Ask, 101.5, 12),
+            message(13, Side::Bid, 100.0, 0),
+            // gap, the order book is stale
+            message(15, Side::Bid, 99.0, 15),
+        ]
+    }
+
+    #[test]
+    fn test_verify() {
+        let (snapshots, incrementals) = streams(&messages());
+        let verifier = Verifier::new(
+            Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+                &snapshots[..],
+                &incrementals[..],
+                config(),
+            )
+            .unwrap(),
+            Replay::<Box<array_orderbook::orderbook::OrderBook>, _, _>::new(
+                &snapshots[..],
+                &incrementals[..],
+                config(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(verifier.run().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_verify_mismatch() {
+        let (snapshots, incrementals) = streams(&messages());
+        let mut changed = messages();
+        changed[2].updates[0].qty = 13;
+        let (_, changed_incrementals) = streams(&changed);
+        let replay = |incrementals| {
+            Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+                &snapshots[..],
+                incrementals,
+                config(),
+            )
+            .unwrap()
+        };
+        let error = Verifier::new(replay(&incrementals[..]), replay(&changed_incrementals[..]))
+            .run()
+            .unwrap_err();
+        let mismatch = error.downcast_ref::<Mismatch>().unwrap();
+        let message_size = incrementals.len() as u64 / 5;
+        assert_eq!(mismatch.offset, 2 * message_size);
+        assert_eq!(mismatch.message, Some(messages()[2].clone()));
+        assert_eq!(
+            mismatch.event,
+            Event {
+                snapshot: false,
+                timestamp: 12_000,
+                seq_no: 12,
+                id: 1,
+            }
+        );
+        assert_eq!(mismatch.left.as_ref().unwrap().asks[1].qty, 12);
+        assert_eq!(mismatch.right.as_ref().unwrap().asks[1].qty, 13);
+    }
+}
@@ -0,0 +1,400 @@
+//! Property based tests of the binary decoders, which parse untrusted input: no input panics or
+//! is read out of bounds, and the array based readers (raw pointer reads) decode the same order
+//! books as the BTreeMap based readers.
+//! Fuzz targets of the same decoders are in the `fuzz` directory.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use orderbook_collection_lib::{
+    array_orderbook, btree_orderbook,
+    config::{Config, OrderBookConfig},
+    orderbook::{BookHealth, CrossPolicy, OrderBook},
+    output::OrderBookOutput,
+    price::TickSize,
+    replay::Replay,
+    ser::{
+        read_snapshot_record,
+        recovery::{GapRecovery, DEFAULT_BUFFER_LIMIT},
+        snapshot_record_size,
+        writer::{write_snapshot_record, SnapshotRecord},
+        Error, InputMode, SnapshotFormat, MAX_SNAPSHOT_LEVELS, SNAPSHOT_DEPTH_METADATA_SIZE,
+        UPDATE_METADATA_SIZE, UPDATE_NUM_UPDATES_OFFSET,
+    },
+};
+use proptest::prelude::*;
+
+type ArrayBooks = HashMap<u64, Box<array_orderbook::orderbook::OrderBook>>;
+type BtreeBooks = HashMap<u64, btree_orderbook::orderbook::OrderBook>;
+
+// prices of the generated levels in ticks of 0.01, within the bounds of the array order book
+const MIN_TICKS: i64 = 9_000;
+const MAX_TICKS: i64 = 11_000;
+
+fn configs() -> HashMap<u64, OrderBookConfig> {
+    HashMap::from([(
+        1,
+        OrderBookConfig {
+            id: 1,
+            min_price: 90.0,
+            max_price: 110.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        },
+    )])
+}
+
+fn order_books(seq_no: u64, health: BookHealth) -> (ArrayBooks, BtreeBooks) {
+    let mut array_books = array_orderbook::ser::init_order_books(&configs());
+    let array_book = array_books.get_mut(&1).unwrap();
+    array_book.seq_no = seq_no;
+    array_book.health = health;
+    let mut btree_book =
+        btree_orderbook::orderbook::OrderBook::with_tick_size(1, TickSize::new(0.01));
+    btree_book.seq_no = seq_no;
+    btree_book.health = health;
+    (array_books, HashMap::from([(1, btree_book)]))
+}
+
+fn assert_same_order_books(array_books: &ArrayBooks, btree_books: &BtreeBooks) {
+    assert_eq!(
+        OrderBookOutput::from_order_book(&array_books[&1]),
+        OrderBookOutput::from_order_book(&btree_books[&1])
+    );
+}
+
+/// Level update as (side, price in ticks, qty), with any side byte, i.e. also an invalid one,
+/// and zero qty, i.e. also removing a level which doesn't exist.
+fn level_update() -> impl Strategy<Value = (u8, i64, u64)> {
+    (
+        prop_oneof![Just(0u8), Just(1u8), any::<u8>()],
+        MIN_TICKS..=MAX_TICKS,
+        0..100u64,
+    )
+}
+
+/// Level update, which is valid whatever the state of the order book, i.e. a bid or an ask
+/// with non-zero qty.
+fn valid_level_update() -> impl Strategy<Value = (u8, i64, u64)> {
+    (0..=1u8, MIN_TICKS..=MAX_TICKS, 1..100u64)
+}
+
+/// Incremental message of order book 1, with the same layout as `write_incremental_message`,
+/// but with any side byte.
+fn message_bytes(seq_no: u64, updates: &[(u8, i64, u64)]) -> Vec<u8> {
+    let mut buf = vec![];
+    buf.extend_from_slice(&(seq_no * 1000).to_le_bytes());
+    buf.extend_from_slice(&seq_no.to_le_bytes());
+    buf.extend_from_slice(&1u64.to_le_bytes());
+    buf.extend_from_slice(&(updates.len() as u64).to_le_bytes());
+    for (side, ticks, qty) in updates {
+        buf.push(*side);
+        buf.extend_from_slice(&(*ticks as f64 / 100.0).to_le_bytes());
+        buf.extend_from_slice(&qty.to_le_bytes());
+    }
+    buf
+}
+
+/// Number of updates, which include the ones whose size overflows, e.g. `u64::MAX / 17 + 1`
+/// updates of 17 bytes wrap around to 16 bytes.
+fn num_updates() -> impl Strategy<Value = u64> {
+    prop_oneof![
+        0..8u64,
+        Just(u64::MAX / 17 + 1),
+        Just(u64::MAX),
+        any::<u64>()
+    ]
+}
+
+fn temp_file(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::env::temp_dir().join(format!(
+        "{}_{}_{}.bin",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+#[test]
+fn test_incremental_num_updates_overflow() {
+    let mut buf = message_bytes(1, &[(0, 10_000, 1), (1, 10_100, 2)]);
+    buf[UPDATE_NUM_UPDATES_OFFSET..UPDATE_METADATA_SIZE]
+        .copy_from_slice(&(u64::MAX / 17 + 1).to_le_bytes());
+    let (mut array_books, mut btree_books) = order_books(0, BookHealth::Live);
+    assert!(matches!(
+        array_orderbook::ser::incremental::read(&buf, &mut array_books),
+        Err(Error::InvalidNumUpdates(_, UPDATE_NUM_UPDATES_OFFSET))
+    ));
+    assert!(matches!(
+        btree_orderbook::ser::incremental::read(&buf, &mut btree_books),
+        Err(Error::InvalidNumUpdates(_, UPDATE_NUM_UPDATES_OFFSET))
+    ));
+    assert_eq!(array_books[&1].seq_no, 0);
+    assert_eq!(btree_books[&1].seq_no, 0);
+}
+
+#[test]
+fn test_snapshot_depth_num_levels_cap() {
+    for (num_bids, num_asks) in [(MAX_SNAPSHOT_LEVELS + 1, 0), (1, u64::MAX / 16)] {
+        let mut buf = vec![0u8; 24];
+        buf.extend_from_slice(&num_bids.to_le_bytes());
+        buf.extend_from_slice(&num_asks.to_le_bytes());
+        assert!(matches!(
+            snapshot_record_size(&buf, SnapshotFormat::Depth),
+            Err(Error::InvalidData(_))
+        ));
+        // the record is rejected before the buffer is resized for its levels
+        let mut record = vec![];
+        assert!(read_snapshot_record(&mut &buf[..], SnapshotFormat::Depth, &mut record).is_err());
+        assert_eq!(record.len(), SNAPSHOT_DEPTH_METADATA_SIZE);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_incremental_arbitrary_bytes(
+        mut buf in proptest::collection::vec(any::<u8>(), 0..256),
+        num_updates in num_updates(),
+        known_id in any::<bool>(),
+        seq_no in any::<u64>(),
+    ) {
+        if buf.len() >= UPDATE_METADATA_SIZE {
+            buf[UPDATE_NUM_UPDATES_OFFSET..UPDATE_METADATA_SIZE]
+                .copy_from_slice(&num_updates.to_le_bytes());
+            if known_id {
+                buf[16..24].copy_from_slice(&1u64.to_le_bytes());
+            }
+        }
+        let (mut array_books, mut btree_books) = order_books(seq_no, BookHealth::Live);
+        for result in [
+            array_orderbook::ser::incremental::read(&buf, &mut array_books),
+            btree_orderbook::ser::incremental::read(&buf, &mut btree_books),
+        ] {
+            match result {
+                Ok(size) | Err(Error::GapDetected(_, size)) => prop_assert!(size <= buf.len()),
+                Err(_) => {}
+            }
+        }
+    }
+
+    #[test]
+    fn prop_incremental_equivalence(
+        seq_no in 0..20u64,
+        stale in any::<bool>(),
+        messages in proptest::collection::vec(
+            (0..25u64, proptest::collection::vec(level_update(), 0..6), any::<usize>()),
+            1..8,
+        ),
+    ) {
+        let health = if stale { BookHealth::Stale } else { BookHealth::Live };
+        let (mut array_books, mut btree_books) = order_books(seq_no, health);
+        for (seq_no, updates, truncate) in messages {
+            let mut buf = message_bytes(seq_no, &updates);
+            // some of the messages are truncated
+            if truncate % 4 == 0 {
+                buf.truncate(truncate % buf.len());
+            }
+            let array_result = array_orderbook::ser::incremental::read(&buf, &mut array_books);
+            let btree_result = btree_orderbook::ser::incremental::read(&buf, &mut btree_books);
+            prop_assert_eq!(format!("{:?}", array_result), format!("{:?}", btree_result));
+            assert_same_order_books(&array_books, &btree_books);
+        }
+    }
+
+    #[test]
+    fn prop_snapshot_arbitrary_bytes(
+        mut buf in proptest::collection::vec(any::<u8>(), 0..400),
+        num_levels in (0..12u64, 0..12u64),
+        known_id in any::<bool>(),
+    ) {
+        if buf.len() >= 40 {
+            buf[24..32].copy_from_slice(&num_levels.0.to_le_bytes());
+            buf[32..40].copy_from_slice(&num_levels.1.to_le_bytes());
+        }
+        if known_id && buf.len() >= 24 {
+            buf[16..24].copy_from_slice(&1u64.to_le_bytes());
+        }
+        let (mut array_books, _) = order_books(0, BookHealth::Live);
+        let _ = array_orderbook::ser::snapshot::read(&buf, &mut array_books);
+        if let Ok(size) = array_orderbook::ser::snapshot::read_depth(&buf, &mut array_books) {
+            prop_assert!(size <= buf.len());
+        }
+        let _ = btree_orderbook::ser::snapshot::read(&buf, &configs());
+        let _ = btree_orderbook::ser::snapshot::read_depth(&buf, &configs());
+    }
+
+    #[test]
+    fn prop_snapshot_equivalence(
+        bids in proptest::collection::vec((MIN_TICKS..=MAX_TICKS, 0..100u64), 0..12),
+        asks in proptest::collection::vec((MIN_TICKS..=MAX_TICKS, 0..100u64), 0..12),
+        depth in any::<bool>(),
+    ) {
+        let to_levels = |levels: &[(i64, u64)]| -> Vec<(f64, u64)> {
+            levels.iter().map(|(ticks, qty)| (*ticks as f64 / 100.0, *qty)).collect()
+        };
+        let record = SnapshotRecord {
+            timestamp: 1000,
+            seq_no: 1,
+            id: 1,
+            bids: to_levels(&bids),
+            asks: to_levels(&asks),
+        };
+        let format = if depth { SnapshotFormat::Depth } else { SnapshotFormat::Legacy };
+        let mut buf = vec![];
+        // the legacy format holds at most 5 levels per side
+        prop_assume!(write_snapshot_record(&mut buf, &record, format).is_ok());
+
+        let (mut array_books, _) = order_books(0, BookHealth::Live);
+        let btree_book = match format {
+            SnapshotFormat::Legacy => {
+                array_orderbook::ser::snapshot::read(&buf, &mut array_books).unwrap();
+                btree_orderbook::ser::snapshot::read(&buf, &configs()).unwrap()
+            }
+            SnapshotFormat::Depth => {
+                let size = array_orderbook::ser::snapshot::read_depth(&buf, &mut array_books)
+                    .unwrap();
+                prop_assert_eq!(size, buf.len());
+                btree_orderbook::ser::snapshot::read_depth(&buf, &configs()).unwrap()
+            }
+        };
+        assert_same_order_books(&array_books, &HashMap::from([(1, btree_book)]));
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn prop_read_incremental_file_buffer_size(
+        messages in proptest::collection::vec(
+            (1..3u64, proptest::collection::vec(valid_level_update(), 0..6)),
+            1..20,
+        ),
+        buffer_size in 0..400usize,
+        trailing in 0..UPDATE_METADATA_SIZE,
+    ) {
+        // sequence numbers increase by 1 or 2, i.e. with gaps
+        let mut seq_no = 0;
+        let mut incremental = vec![];
+        for (increment, updates) in messages {
+            seq_no += increment;
+            incremental.extend_from_slice(&message_bytes(seq_no, &updates));
+        }
+        let file = temp_file("incremental_buffer_size");
+        std::fs::write(&file, &incremental).unwrap();
+
+        let (mut expected_books, _) = order_books(0, BookHealth::Live);
+        array_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut expected_books,
+            1 << 16,
+            None,
+        )
+        .unwrap();
+        let (mut array_books, mut btree_books) = order_books(0, BookHealth::Live);
+        let mut array_recovery = GapRecovery::new();
+        let mut btree_recovery = GapRecovery::new();
+        array_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut array_books,
+            buffer_size,
+            Some(&mut array_recovery),
+        )
+        .unwrap();
+        btree_orderbook::ser::read_incremental_file(
+            file.clone(),
+            &mut btree_books,
+            buffer_size,
+            Some(&mut btree_recovery),
+        )
+        .unwrap();
+        assert_same_order_books(&array_books, &btree_books);
+        assert_same_order_books(&expected_books, &btree_books);
+        prop_assert_eq!(array_recovery.buffered(1), btree_recovery.buffered(1));
+
+        // a truncated message at the end of the file is an error
+        if trailing > 0 {
+            incremental.extend_from_slice(&message_bytes(seq_no + 1, &[(0, MIN_TICKS, 1)])[..trailing]);
+            std::fs::write(&file, &incremental).unwrap();
+            let (mut array_books, mut btree_books) = order_books(0, BookHealth::Live);
+            prop_assert!(array_orderbook::ser::read_incremental_file(
+                file.clone(),
+                &mut array_books,
+                buffer_size,
+                None,
+            )
+            .is_err());
+            prop_assert!(btree_orderbook::ser::read_incremental_file(
+                file.clone(),
+                &mut btree_books,
+                buffer_size,
+                None,
+            )
+            .is_err());
+        }
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn prop_replay_empty_messages(
+        messages in proptest::collection::vec(
+            proptest::collection::vec(valid_level_update(), 0..3),
+            1..20,
+        ),
+    ) {
+        let record = SnapshotRecord {
+            timestamp: 1000,
+            seq_no: 1,
+            id: 1,
+            bids: vec![(100.0, 1)],
+            asks: vec![(101.0, 5)],
+        };
+        let mut snapshots = vec![];
+        write_snapshot_record(&mut snapshots, &record, SnapshotFormat::Legacy).unwrap();
+        let mut expected_books =
+            HashMap::from([(1, btree_orderbook::ser::snapshot::read(&snapshots, &configs()).unwrap())]);
+        // the messages without updates only advance the sequence number
+        let mut incrementals = vec![];
+        let mut seq_no = 1;
+        for updates in messages {
+            seq_no += 1;
+            let buf = message_bytes(seq_no, &updates);
+            btree_orderbook::ser::incremental::read(&buf, &mut expected_books).unwrap();
+            incrementals.extend_from_slice(&buf);
+        }
+
+        let config = Config {
+            instruments: configs(),
+            incremental_buffer_size: 1024,
+            snapshot_format: SnapshotFormat::Legacy,
+            input: InputMode::Buffered,
+            recovery_buffer_limit: DEFAULT_BUFFER_LIMIT,
+        };
+        let array_books = Replay::<Box<array_orderbook::orderbook::OrderBook>, _, _>::new(
+            &snapshots[..],
+            &incrementals[..],
+            config.clone(),
+        )
+        .unwrap()
+        .run()
+        .unwrap();
+        let btree_books =
+            Replay::<btree_orderbook::orderbook::OrderBook, _, _>::new(
+                &snapshots[..],
+                &incrementals[..],
+                config,
+            )
+            .unwrap()
+            .run()
+            .unwrap();
+        prop_assert_eq!(btree_books[&1].health(), BookHealth::Live);
+        prop_assert_eq!(btree_books[&1].seq_no(), seq_no);
+        assert_same_order_books(&array_books, &btree_books);
+        assert_same_order_books(&array_books, &expected_books);
+    }
+}
@@ -1,6 +1,20 @@
 use std::path::PathBuf;
 
-use orderbook_collection_lib::{config, run_array, run_btree};
+use orderbook_collection_lib::{
+    array_orderbook, btree_orderbook,
+    checkpoint::Checkpoint,
+    config,
+    orderbook::{CrossPolicy, OrderBook},
+    output::{write_output_file, OrderBookOutput, OutputFormat},
+    price::Price,
+    replay::{AsOf, Replay},
+    run, run_array, run_btree, run_follow, run_sharded, run_until, run_verify,
+    sampler::{SampleTrigger, Sampler},
+    ser::{
+        read_incremental_message, writer::write_snapshot_file, InputMode, SnapshotFormat,
+        SNAPSHOT_RECORD_SIZE,
+    },
+};
 
 #[test]
 fn test_run_btree() {
@@ -9,6 +23,9 @@ fn test_run_btree() {
     let config = config::Config {
         instruments: std::collections::HashMap::new(),
         incremental_buffer_size: 256, //smaller buffer size to test reader reset
+        snapshot_format: SnapshotFormat::Legacy,
+        input: InputMode::Buffered,
+        recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
     };
     let order_books = run_btree(snapshot_file, incremental_file, config).unwrap();
 
@@ -29,6 +46,8 @@ fn test_run_array() {
             min_price: 4000.0,
             max_price: 7000.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         },
     );
     instruments.insert(
@@ -39,11 +58,16 @@ fn test_run_array() {
             // min_price: 600000.0,
             max_price: 602000.0,
             tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
         },
     );
     let config = config::Config {
         instruments,
         incremental_buffer_size: 256, //smaller buffer size to test reader reset
+        snapshot_format: SnapshotFormat::Legacy,
+        input: InputMode::Buffered,
+        recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
     };
     let order_books = run_array(snapshot_file, incremental_file, config).unwrap();
 
@@ -51,3 +75,472 @@ fn test_run_array() {
     assert_eq!(format!("{:?}", order_books.get(&1).unwrap()), "OrderBook(id: 1, seq_no: 51, timestamp: 1705717811000, bids: [(5000.75, 1300), (5000.7, 1300), (5000.65, 1200), (5000.6, 1100), (5000.55, 1000)], asks: [(5001.0, 2000), (5001.1, 2100), (5001.2, 2200), (5001.3, 2300), (5001.4, 2400)])");
     assert_eq!(format!("{:?}", order_books.get(&2).unwrap()), "OrderBook(id: 2, seq_no: 50, timestamp: 1705717810000, bids: [(600000.0, 250), (599900.0, 200), (599800.0, 150), (599700.0, 180), (599600.0, 220)], asks: [(600500.0, 300), (600600.0, 400), (600700.0, 350), (600800.0, 420), (600900.0, 500)])");
 }
+
+fn array_config() -> config::Config {
+    let mut instruments = std::collections::HashMap::new();
+    instruments.insert(
+        1,
+        config::OrderBookConfig {
+            id: 1,
+            min_price: 4000.0,
+            max_price: 7000.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        },
+    );
+    instruments.insert(
+        2,
+        config::OrderBookConfig {
+            id: 2,
+            min_price: 599000.0,
+            max_price: 602000.0,
+            tick_size: 0.01,
+            recenter: false,
+            cross_policy: CrossPolicy::Warn,
+        },
+    );
+    config::Config {
+        instruments,
+        incremental_buffer_size: 256,
+        snapshot_format: SnapshotFormat::Legacy,
+        input: InputMode::Buffered,
+        recovery_buffer_limit: orderbook_collection_lib::ser::recovery::DEFAULT_BUFFER_LIMIT,
+    }
+}
+
+type Levels = Vec<(Price, u64)>;
+
+fn levels<B: OrderBook>(order_book: &B) -> (u64, u64, Levels, Levels) {
+    (
+        order_book.seq_no(),
+        order_book.timestamp(),
+        order_book.get_bids(),
+        order_book.get_asks(),
+    )
+}
+
+#[test]
+fn test_run_generic() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let btree_books = run::<btree_orderbook::orderbook::OrderBook>(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+    let array_books = run::<Box<array_orderbook::orderbook::OrderBook>>(
+        snapshot_file,
+        incremental_file,
+        array_config(),
+    )
+    .unwrap();
+
+    assert_eq!(btree_books.len(), array_books.len());
+    for (id, btree_book) in btree_books.iter() {
+        assert_eq!(levels(btree_book), levels(array_books.get(id).unwrap()));
+    }
+}
+
+#[test]
+fn test_run_sharded() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let expected_books = run_array(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+    for shards in [1, 2, 4] {
+        let array_books = run_sharded::<Box<array_orderbook::orderbook::OrderBook>>(
+            snapshot_file.clone(),
+            incremental_file.clone(),
+            array_config(),
+            shards,
+        )
+        .unwrap();
+        let btree_books = run_sharded::<btree_orderbook::orderbook::OrderBook>(
+            snapshot_file.clone(),
+            incremental_file.clone(),
+            array_config(),
+            shards,
+        )
+        .unwrap();
+        assert_eq!(array_books.len(), 2);
+        for (id, expected_book) in expected_books.iter() {
+            assert_eq!(levels(expected_book), levels(array_books.get(id).unwrap()));
+            assert_eq!(levels(expected_book), levels(btree_books.get(id).unwrap()));
+        }
+    }
+}
+
+#[test]
+fn test_run_mmap() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let buffered_books = run_btree(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+    let mut config = array_config();
+    config.input = InputMode::Mmap;
+    let btree_books = run_btree(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        config.clone(),
+    )
+    .unwrap();
+    let array_books = run_array(snapshot_file, incremental_file, config).unwrap();
+
+    assert_eq!(buffered_books.len(), 2);
+    for (id, buffered_book) in buffered_books.iter() {
+        assert_eq!(levels(buffered_book), levels(btree_books.get(id).unwrap()));
+        assert_eq!(levels(buffered_book), levels(array_books.get(id).unwrap()));
+    }
+}
+
+/// Converts the legacy snapshot file to the depth format, dropping empty levels.
+fn write_depth_snapshot_file(legacy_file: &str, depth_file: &std::path::Path) {
+    let order_books = btree_orderbook::ser::read_snapshot_file(
+        PathBuf::from(legacy_file),
+        &array_config().instruments,
+        SnapshotFormat::Legacy,
+    )
+    .unwrap();
+    write_snapshot_file(
+        depth_file.to_path_buf(),
+        &order_books,
+        SnapshotFormat::Depth,
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_run_depth_snapshot_format() {
+    let depth_snapshot_file =
+        std::env::temp_dir().join(format!("snapshot_depth_{}.bin", std::process::id()));
+    write_depth_snapshot_file("resources/snapshot.bin", &depth_snapshot_file);
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+
+    let mut config = array_config();
+    let legacy_books = run_btree(
+        PathBuf::from("resources/snapshot.bin"),
+        incremental_file.clone(),
+        config.clone(),
+    )
+    .unwrap();
+    config.snapshot_format = SnapshotFormat::Depth;
+    let btree_books = run_btree(
+        depth_snapshot_file.clone(),
+        incremental_file.clone(),
+        config.clone(),
+    )
+    .unwrap();
+    let array_books = run_array(depth_snapshot_file.clone(), incremental_file, config).unwrap();
+    std::fs::remove_file(depth_snapshot_file).unwrap();
+
+    assert_eq!(legacy_books.len(), 2);
+    for (id, legacy_book) in legacy_books.iter() {
+        assert_eq!(levels(legacy_book), levels(btree_books.get(id).unwrap()));
+        assert_eq!(levels(legacy_book), levels(array_books.get(id).unwrap()));
+    }
+}
+
+#[test]
+fn test_write_snapshot_file_after_run() {
+    let snapshot_file =
+        std::env::temp_dir().join(format!("snapshot_eod_{}.bin", std::process::id()));
+    let incremental_file =
+        std::env::temp_dir().join(format!("incremental_empty_{}.bin", std::process::id()));
+    std::fs::write(&incremental_file, []).unwrap();
+
+    let mut config = array_config();
+    config.snapshot_format = SnapshotFormat::Depth;
+    let array_books = run_array(
+        PathBuf::from("resources/snapshot.bin"),
+        PathBuf::from("resources/incremental.bin"),
+        array_config(),
+    )
+    .unwrap();
+    write_snapshot_file(snapshot_file.clone(), &array_books, SnapshotFormat::Depth).unwrap();
+    let restored_books =
+        run_btree(snapshot_file.clone(), incremental_file.clone(), config).unwrap();
+    std::fs::remove_file(snapshot_file).unwrap();
+    std::fs::remove_file(incremental_file).unwrap();
+
+    assert_eq!(array_books.len(), restored_books.len());
+    for (id, array_book) in array_books.iter() {
+        let restored_book = restored_books.get(id).unwrap();
+        assert_eq!(levels(array_book), levels(restored_book));
+        assert_eq!(array_book.seq_no, restored_book.seq_no);
+        assert_eq!(array_book.timestamp, restored_book.timestamp);
+    }
+}
+
+#[test]
+fn test_run_follow() {
+    let incremental = std::fs::read("resources/incremental.bin").unwrap();
+    let incremental_file =
+        std::env::temp_dir().join(format!("incremental_follow_{}.bin", std::process::id()));
+    // the capture process writes the rest of the file in chunks, which split messages
+    let (head, tail) = incremental.split_at(incremental.len() / 2);
+    std::fs::write(&incremental_file, head).unwrap();
+    let writer = {
+        let incremental_file = incremental_file.clone();
+        let tail = tail.to_vec();
+        std::thread::spawn(move || {
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(incremental_file)
+                .unwrap();
+            for chunk in tail.chunks(100) {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                std::io::Write::write_all(&mut file, chunk).unwrap();
+            }
+        })
+    };
+
+    let order_books = run_follow::<btree_orderbook::orderbook::OrderBook>(
+        PathBuf::from("resources/snapshot.bin"),
+        incremental_file.clone(),
+        array_config(),
+        Some(std::time::Duration::from_millis(500)),
+    )
+    .unwrap();
+    writer.join().unwrap();
+    std::fs::remove_file(incremental_file).unwrap();
+
+    let expected = run_btree(
+        PathBuf::from("resources/snapshot.bin"),
+        PathBuf::from("resources/incremental.bin"),
+        array_config(),
+    )
+    .unwrap();
+    assert_eq!(order_books.len(), expected.len());
+    for (id, order_book) in order_books.iter() {
+        assert_eq!(levels(order_book), levels(expected.get(id).unwrap()));
+        assert_eq!(order_book.seq_no, expected.get(id).unwrap().seq_no);
+    }
+}
+
+#[test]
+fn test_write_output_after_run() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let mut btree_config = array_config();
+    btree_config.instruments.clear();
+    let btree_books = run_btree(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        btree_config,
+    )
+    .unwrap();
+    let array_books = run_array(snapshot_file, incremental_file, array_config()).unwrap();
+
+    for format in [OutputFormat::Json, OutputFormat::Csv] {
+        let btree_file =
+            std::env::temp_dir().join(format!("output_btree_{}.txt", std::process::id()));
+        let array_file =
+            std::env::temp_dir().join(format!("output_array_{}.txt", std::process::id()));
+        write_output_file(btree_file.clone(), &btree_books, format).unwrap();
+        write_output_file(array_file.clone(), &array_books, format).unwrap();
+        let btree_output = std::fs::read_to_string(&btree_file).unwrap();
+        let array_output = std::fs::read_to_string(&array_file).unwrap();
+        std::fs::remove_file(btree_file).unwrap();
+        std::fs::remove_file(array_file).unwrap();
+        assert_eq!(btree_output, array_output);
+
+        if format == OutputFormat::Json {
+            let outputs: Vec<OrderBookOutput> = serde_json::from_str(&btree_output).unwrap();
+            assert_eq!(outputs.len(), 2);
+            assert_eq!((outputs[0].id, outputs[0].seq_no), (1, 51));
+            assert_eq!(outputs[0].bids[0].price, 5000.75);
+            assert_eq!((outputs[1].id, outputs[1].seq_no), (2, 50));
+            assert_eq!(outputs[1].asks[4].qty, 500);
+        } else {
+            assert_eq!(btree_output.lines().count(), 1 + 2 * 10);
+        }
+    }
+}
+
+fn sample<B: orderbook_collection_lib::orderbook::OrderBookReader>(
+    sample_file: &std::path::Path,
+    trigger: SampleTrigger,
+) -> std::collections::HashMap<u64, B> {
+    let sampler = Sampler::create(sample_file.to_path_buf(), trigger, 3).unwrap();
+    Replay::<B>::open(
+        PathBuf::from("resources/snapshot.bin"),
+        PathBuf::from("resources/incremental.bin"),
+        array_config(),
+    )
+    .unwrap()
+    .with_sampler(Some(sampler))
+    .run()
+    .unwrap()
+}
+
+#[test]
+fn test_run_with_sampler() {
+    for trigger in [SampleTrigger::Interval(1000), SampleTrigger::Updates(10)] {
+        let btree_file =
+            std::env::temp_dir().join(format!("samples_btree_{}.csv", std::process::id()));
+        let array_file =
+            std::env::temp_dir().join(format!("samples_array_{}.csv", std::process::id()));
+        let btree_books = sample::<btree_orderbook::orderbook::OrderBook>(&btree_file, trigger);
+        sample::<Box<array_orderbook::orderbook::OrderBook>>(&array_file, trigger);
+        let btree_samples = std::fs::read_to_string(&btree_file).unwrap();
+        let array_samples = std::fs::read_to_string(&array_file).unwrap();
+        std::fs::remove_file(btree_file).unwrap();
+        std::fs::remove_file(array_file).unwrap();
+
+        assert_eq!(btree_samples, array_samples);
+        let lines: Vec<&str> = btree_samples.lines().collect();
+        assert!(lines.len() > 2);
+        assert!(lines[0].starts_with("sample_timestamp,id,timestamp,seq_no,health,bid_price_0"));
+        // samples are taken during the run, so none of them is later than the final books
+        for line in lines[1..].iter() {
+            let columns: Vec<&str> = line.split(',').collect();
+            let order_book = &btree_books[&columns[1].parse::<u64>().unwrap()];
+            assert!(columns[3].parse::<u64>().unwrap() <= order_book.seq_no);
+        }
+    }
+}
+
+#[test]
+fn test_run_until() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let order_books = run_btree(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+
+    for (id, order_book) in order_books.iter() {
+        let as_of = AsOf::Timestamp(order_book.timestamp);
+        let btree_book = run_until::<btree_orderbook::orderbook::OrderBook>(
+            snapshot_file.clone(),
+            incremental_file.clone(),
+            array_config(),
+            *id,
+            as_of,
+        )
+        .unwrap();
+        let array_book = run_until::<Box<array_orderbook::orderbook::OrderBook>>(
+            snapshot_file.clone(),
+            incremental_file.clone(),
+            array_config(),
+            *id,
+            as_of,
+        )
+        .unwrap();
+        assert_eq!(levels(&btree_book), levels(order_book));
+        assert_eq!(levels(&array_book), levels(order_book));
+    }
+
+    // as of just before the last update of book 1, the book is older
+    let order_book = run_until::<btree_orderbook::orderbook::OrderBook>(
+        snapshot_file,
+        incremental_file,
+        array_config(),
+        1,
+        AsOf::Timestamp(order_books[&1].timestamp - 1),
+    )
+    .unwrap();
+    assert!(order_book.timestamp < order_books[&1].timestamp);
+}
+
+fn test_checkpoint_restore<B: orderbook_collection_lib::orderbook::OrderBookReader>() {
+    let incremental = std::fs::read("resources/incremental.bin").unwrap();
+    // split at a message boundary in the middle of the file
+    let mut reader = &incremental[..];
+    let mut buf = vec![];
+    let mut split = 0;
+    while split < incremental.len() / 2 {
+        assert!(read_incremental_message(&mut reader, &mut buf).unwrap());
+        split += buf.len();
+    }
+    let incremental_file =
+        std::env::temp_dir().join(format!("incremental_restore_{}.bin", std::process::id()));
+    let checkpoint_file =
+        std::env::temp_dir().join(format!("checkpoint_{}.json", std::process::id()));
+    std::fs::write(&incremental_file, &incremental[..split]).unwrap();
+
+    let mut replay = Replay::<B>::open(
+        PathBuf::from("resources/snapshot.bin"),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+    while replay.step().unwrap() {}
+    replay.checkpoint().write(checkpoint_file.clone()).unwrap();
+    assert_eq!(replay.checkpoint().incremental_offset, split as u64);
+
+    // the capture continues and the next run resumes from the checkpoint
+    std::fs::write(&incremental_file, &incremental).unwrap();
+    let checkpoint = Checkpoint::read(checkpoint_file.clone()).unwrap();
+    let order_books = Replay::<B>::restore(
+        PathBuf::from("resources/snapshot.bin"),
+        incremental_file.clone(),
+        array_config(),
+        checkpoint,
+    )
+    .unwrap()
+    .run()
+    .unwrap();
+    std::fs::remove_file(incremental_file).unwrap();
+    std::fs::remove_file(checkpoint_file).unwrap();
+
+    let expected = run::<B>(
+        PathBuf::from("resources/snapshot.bin"),
+        PathBuf::from("resources/incremental.bin"),
+        array_config(),
+    )
+    .unwrap();
+    assert_eq!(order_books.len(), expected.len());
+    for (id, order_book) in order_books.iter() {
+        assert_eq!(levels(order_book), levels(expected.get(id).unwrap()));
+        assert_eq!(order_book.health(), expected.get(id).unwrap().health());
+    }
+}
+
+#[test]
+fn test_checkpoint_restore_btree() {
+    test_checkpoint_restore::<btree_orderbook::orderbook::OrderBook>();
+}
+
+#[test]
+fn test_checkpoint_restore_array() {
+    test_checkpoint_restore::<Box<array_orderbook::orderbook::OrderBook>>();
+}
+
+#[test]
+fn test_run_verify() {
+    let snapshot_file = PathBuf::from("resources/snapshot.bin");
+    let incremental_file = PathBuf::from("resources/incremental.bin");
+    let events = run_verify(
+        snapshot_file.clone(),
+        incremental_file.clone(),
+        array_config(),
+    )
+    .unwrap();
+
+    // every snapshot record and incremental message is verified
+    let snapshots = std::fs::read(snapshot_file).unwrap();
+    let incremental = std::fs::read(incremental_file).unwrap();
+    let mut reader = &incremental[..];
+    let mut buf = vec![];
+    let mut messages = 0;
+    while read_incremental_message(&mut reader, &mut buf).unwrap() {
+        messages += 1;
+    }
+    assert_eq!(
+        events,
+        (snapshots.len() / SNAPSHOT_RECORD_SIZE) as u64 + messages
+    );
+}